@@ -0,0 +1,143 @@
+use serde::Deserialize;
+use crate::packet::VBAN_CHANNELS_MAX_NB;
+
+/// Turns the channels of a stream into the channels of the output.
+///
+/// Every output channel is a weighted sum of stream channels. The textual form lists the output
+/// channels separated by commas, each one a sum of stream channels counted from 1, optionally
+/// with a gain: `2,1` swaps left and right, `1,1` plays mono on both sides, `1,2,0,0` adds two
+/// silent channels and `0.5*1+0.5*2` mixes stereo down to mono. Stream channels that are not
+/// mentioned are dropped. The names of the presets are accepted as well, see `PRESETS`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ChannelMap {
+    /// Stream channel and gain of the terms of every output channel
+    outputs : Vec<Vec<(usize, f32)>>,
+}
+
+/// Downmixes of common layouts. Surround layouts are taken in the usual WAV order (FL FR FC LFE
+/// then the rear and side pairs); the LFE channel is dropped. The gains follow ITU-R BS.775,
+/// scaled so full scale on all channels does not clip.
+pub const PRESETS : &[(&str, &str)] = &[
+    ("mono-stereo", "1,1"),
+    ("stereo-mono", "0.5*1+0.5*2"),
+    ("quad-stereo", "0.5*1+0.5*3,0.5*2+0.5*4"),
+    ("5.1-stereo", "0.414*1+0.293*3+0.293*5,0.414*2+0.293*3+0.293*6"),
+    ("7.1-stereo", "0.319*1+0.226*3+0.226*5+0.226*7,0.319*2+0.226*3+0.226*6+0.226*8"),
+];
+
+impl std::str::FromStr for ChannelMap {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let s = PRESETS.iter().find(|(name, _map)| *name == s).map_or(s, |(_name, map)| *map);
+        let outputs = s.split(',')
+            .map(|output| output.split('+').filter_map(|term| parse_term(term.trim()).transpose()).collect())
+            .collect::<Result<Vec<Vec<(usize, f32)>>, String>>()?;
+        if outputs.len() > VBAN_CHANNELS_MAX_NB {
+            return Err(format!("channel map has {} channels, at most {VBAN_CHANNELS_MAX_NB} are supported", outputs.len()));
+        }
+        Ok(Self {
+            outputs,
+        })
+    }
+}
+
+impl TryFrom<String> for ChannelMap {
+    type Error = String;
+
+    fn try_from(s : String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parses `[gain*]channel`. Channel 0 is silence and gives None.
+fn parse_term(term : &str) -> Result<Option<(usize, f32)>, String> {
+    let (gain, channel) = match term.split_once('*') {
+        None => (1.0, term),
+        Some((gain, channel)) => (gain.trim().parse::<f32>().map_err(|_| format!("invalid gain {gain} in channel map"))?, channel.trim()),
+    };
+    match channel.parse::<usize>() {
+        Ok(0) => Ok(None),
+        Ok(channel) if channel <= VBAN_CHANNELS_MAX_NB => Ok(Some((channel - 1, gain))),
+        _ => Err(format!("invalid channel {channel} in channel map (expected 0 to {VBAN_CHANNELS_MAX_NB})")),
+    }
+}
+
+impl ChannelMap {
+
+    /// Number of channels the map produces
+    pub fn num_outputs(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Maps interleaved frames of `num_channels`. Channels the stream does not have are silent.
+    pub fn apply(&self, samples : &[f32], num_channels : usize) -> Vec<f32> {
+        let mut out = Vec::with_capacity(samples.len() / num_channels * self.outputs.len());
+        for frame in samples.chunks_exact(num_channels) {
+            for terms in &self.outputs {
+                out.push(terms.iter().map(|(ch, gain)| frame.get(*ch).map_or(0.0, |smp| smp * gain)).sum());
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(s : &str) -> ChannelMap {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_lists_and_gains() {
+        assert_eq!(map("2,1").outputs, [vec![(1, 1.0)], vec![(0, 1.0)]]);
+        assert_eq!(map("1, 0 ,2").outputs, [vec![(0, 1.0)], vec![], vec![(1, 1.0)]]);
+        assert_eq!(map("0.5*1 + 0.25 * 3").outputs, [vec![(0, 0.5), (2, 0.25)]]);
+        assert_eq!(map("256").num_outputs(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_maps() {
+        for invalid in ["", "1,,2", "a", "x*1", "0.5*", "257", "-1", "1+"] {
+            assert!(invalid.parse::<ChannelMap>().is_err(), "{invalid:?}");
+        }
+        assert!(vec!["1"; VBAN_CHANNELS_MAX_NB + 1].join(",").parse::<ChannelMap>().is_err());
+    }
+
+    #[test]
+    fn preset_coefficients() {
+        assert_eq!(map("mono-stereo").outputs, [vec![(0, 1.0)], vec![(0, 1.0)]]);
+        assert_eq!(map("stereo-mono").outputs, [vec![(0, 0.5), (1, 0.5)]]);
+        assert_eq!(map("quad-stereo").outputs, [vec![(0, 0.5), (2, 0.5)], vec![(1, 0.5), (3, 0.5)]]);
+        assert_eq!(map("5.1-stereo").outputs, [vec![(0, 0.414), (2, 0.293), (4, 0.293)], vec![(1, 0.414), (2, 0.293), (5, 0.293)]]);
+        assert_eq!(map("7.1-stereo").outputs, [
+            vec![(0, 0.319), (2, 0.226), (4, 0.226), (6, 0.226)],
+            vec![(1, 0.319), (2, 0.226), (5, 0.226), (7, 0.226)],
+        ]);
+        /* The LFE channel of the surround layouts is left out */
+        for name in ["5.1-stereo", "7.1-stereo"] {
+            assert!(map(name).outputs.iter().flatten().all(|(ch, _)| *ch != 3), "{name}");
+        }
+        /* Full scale on all channels does not clip */
+        for (name, _) in PRESETS {
+            let preset = map(name);
+            assert!(preset.outputs.iter().all(|terms| terms.iter().map(|(_, gain)| gain).sum::<f32>() <= 1.0), "{name}");
+        }
+    }
+
+    #[test]
+    fn applies_to_frames() {
+        assert_eq!(map("2,1").apply(&[0.1, 0.2, 0.3, 0.4], 2), [0.2, 0.1, 0.4, 0.3]);
+        assert_eq!(map("stereo-mono").apply(&[0.5, -0.5, 0.2, 0.4], 2), [0.0, 0.3]);
+        /* Channels the stream does not have are silent */
+        assert_eq!(map("1,3").apply(&[0.5, 0.25], 2), [0.5, 0.0]);
+
+        let surround = [0.1, 0.2, 0.3, 1.0, 0.4, 0.5];
+        let stereo = map("5.1-stereo").apply(&surround, 6);
+        assert!((stereo[0] - (0.414 * 0.1 + 0.293 * 0.3 + 0.293 * 0.4)).abs() < 1e-6);
+        assert!((stereo[1] - (0.414 * 0.2 + 0.293 * 0.3 + 0.293 * 0.5)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,272 @@
+use serde::Deserialize;
+
+/// Concealed audio plays at full level this long before it starts to fade
+const CONCEAL_HOLD_MS : u32 = 10;
+
+/// Time over which concealed audio fades to silence, so a long gap does not turn into a buzz
+const CONCEAL_FADE_MS : u32 = 50;
+
+/// Length of the crossfade from concealed audio into the packet that follows the gap
+const CONCEAL_CROSSFADE_MS : u32 = 5;
+
+/// Longest and shortest period the waveform similarity search tries, 50 Hz and 400 Hz
+const CONCEAL_MAX_PERIOD_HZ : u32 = 50;
+const CONCEAL_MIN_PERIOD_HZ : u32 = 400;
+
+/// How the gap left by packets that never arrived is filled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Concealment {
+    /// Play silence for the missing audio
+    #[default]
+    Silence,
+
+    /// Play the last packet again, fading out
+    Repeat,
+
+    /// Continue the last pitch period found by waveform similarity, fading out
+    Similarity,
+}
+
+impl std::str::FromStr for Concealment {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "silence" => Ok(Concealment::Silence),
+            "repeat" => Ok(Concealment::Repeat),
+            "similarity" => Ok(Concealment::Similarity),
+            _ => Err(format!("unknown concealment {s} (expected silence, repeat or similarity)")),
+        }
+    }
+}
+
+impl std::fmt::Display for Concealment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Concealment::Silence => write!(f, "silence"),
+            Concealment::Repeat => write!(f, "repeat"),
+            Concealment::Similarity => write!(f, "similarity"),
+        }
+    }
+}
+
+/// Fills the gaps of missing packets, so the output timeline stays continuous.
+///
+/// Received audio passes through and the most recent part of it is kept. For a gap, the kept
+/// audio is continued periodically: with `Repeat` the period is the last packet, with
+/// `Similarity` it is the lag at which the end of the kept audio matches an earlier part best,
+/// which usually is a pitch period, so the continuation joins without a click. Concealed audio
+/// fades out after a short while and crossfades into the packet that ends the gap.
+pub struct Concealer {
+    mode : Concealment,
+
+    sample_rate : u32,
+
+    num_channels : usize,
+
+    /// The most recent interleaved frames that were passed on
+    history : Vec<f32>,
+
+    /// Frames of the last packet received
+    last_packet : usize,
+
+    /// Continuation of the concealed audio, crossfaded into the next packet
+    tail : Vec<f32>,
+}
+
+impl Concealer {
+
+    pub fn new(mode : Concealment, sample_rate : u32, num_channels : usize) -> Self {
+        Self {
+            mode,
+            sample_rate,
+            num_channels : num_channels.max(1),
+            history : Vec::new(),
+            last_packet : 0,
+            tail : Vec::new(),
+        }
+    }
+
+    /// Returns `gap` frames of concealed audio followed by the packet `samples`
+    pub fn process(&mut self, gap : usize, mut samples : Vec<f32>) -> Vec<f32> {
+        let mut out = match gap {
+            0 => Vec::new(),
+            _ => self.conceal(gap),
+        };
+
+        /* Blend the continuation of the concealed audio into the start of the packet */
+        let tail = std::mem::take(&mut self.tail);
+        let frames = (tail.len() / self.num_channels).min(samples.len() / self.num_channels);
+        for n in 0..frames {
+            let weight = (n + 1) as f32 / (frames + 1) as f32;
+            for ch in 0..self.num_channels {
+                let at = n * self.num_channels + ch;
+                samples[at] = tail[at] * (1.0 - weight) + samples[at] * weight;
+            }
+        }
+
+        self.last_packet = samples.len() / self.num_channels;
+        self.remember(&samples);
+        out.extend_from_slice(&samples);
+        out
+    }
+
+    /// Produces `frames` of audio for a gap and prepares the tail for the crossfade
+    fn conceal(&mut self, frames : usize) -> Vec<f32> {
+        let crossfade = self.ms_to_frames(CONCEAL_CROSSFADE_MS);
+        let total = (frames + crossfade) * self.num_channels;
+        let period = match self.mode {
+            Concealment::Silence => 0,
+            Concealment::Repeat => self.last_packet,
+            Concealment::Similarity => self.best_period().unwrap_or(self.last_packet),
+        };
+        let kept = self.history.len() / self.num_channels;
+        let period = period.min(kept);
+
+        let mut audio = vec![0f32; total];
+        if period > 0 {
+            let hold = self.ms_to_frames(CONCEAL_HOLD_MS);
+            let fade = self.ms_to_frames(CONCEAL_FADE_MS).max(1);
+            let start = (kept - period) * self.num_channels;
+            let pattern = &self.history[start..];
+            for (n, frame) in audio.chunks_exact_mut(self.num_channels).enumerate() {
+                let level = 1.0 - (n.saturating_sub(hold) as f32 / fade as f32).min(1.0);
+                let source = (n % period) * self.num_channels;
+                for (ch, smp) in frame.iter_mut().enumerate() {
+                    *smp = pattern[source + ch] * level;
+                }
+            }
+        }
+
+        self.tail = audio.split_off(frames * self.num_channels);
+        self.remember(&audio);
+        audio
+    }
+
+    /// Lag at which the last few milliseconds are most similar to the audio before them
+    fn best_period(&self) -> Option<usize> {
+        let kept = self.history.len() / self.num_channels;
+        let window = self.ms_to_frames(CONCEAL_CROSSFADE_MS).max(1);
+        let min_lag = (self.sample_rate / CONCEAL_MIN_PERIOD_HZ) as usize;
+        let max_lag = ((self.sample_rate / CONCEAL_MAX_PERIOD_HZ) as usize).min(kept.saturating_sub(window));
+        if min_lag == 0 || max_lag < min_lag {
+            return None;
+        }
+
+        /* Channels are summed, the period has to fit all of them */
+        let mono : Vec<f32> = self.history.chunks_exact(self.num_channels).map(|frame| frame.iter().sum()).collect();
+        let template = &mono[kept - window..];
+        let template_energy : f32 = template.iter().map(|smp| smp * smp).sum();
+        if template_energy == 0.0 {
+            return None;
+        }
+
+        let mut best = None;
+        let mut best_score = 0.0;
+        for lag in min_lag..=max_lag {
+            let candidate = &mono[kept - window - lag..kept - lag];
+            let (dot, energy) = template.iter().zip(candidate).fold((0.0, 0.0), |(dot, energy), (a, b)| (dot + a * b, energy + b * b));
+            if energy == 0.0 {
+                continue;
+            }
+            let score = dot / (template_energy * energy).sqrt();
+            if score > best_score {
+                best_score = score;
+                best = Some(lag);
+            }
+        }
+        best
+    }
+
+    /// Appends audio to the history and trims it to what the next gap may need
+    fn remember(&mut self, samples : &[f32]) {
+        self.history.extend_from_slice(samples);
+        let needed = self.ms_to_frames(1000 / CONCEAL_MAX_PERIOD_HZ + CONCEAL_CROSSFADE_MS).max(self.last_packet) * self.num_channels;
+        if self.history.len() > needed {
+            self.history.drain(..self.history.len() - needed);
+        }
+    }
+
+    fn ms_to_frames(&self, ms : u32) -> usize {
+        (self.sample_rate as u64 * ms as u64 / 1000) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE : u32 = 48000;
+
+    /// Stereo 400 Hz sine, a period is exactly 120 frames
+    fn sine(start : usize, frames : usize) -> Vec<f32> {
+        (start..start + frames)
+            .flat_map(|n| {
+                let smp = (2.0 * std::f32::consts::PI * 400.0 * n as f32 / RATE as f32).sin() * 0.5;
+                [smp, smp * 0.5]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gap_gets_exactly_its_frames() {
+        for mode in [Concealment::Silence, Concealment::Repeat, Concealment::Similarity] {
+            let mut concealer = Concealer::new(mode, RATE, 2);
+            concealer.process(0, sine(0, 480));
+            let mut at = 480;
+            for gap in [1, 37, 480, 5000, 0] {
+                let out = concealer.process(gap, sine(at + gap, 480));
+                assert_eq!(out.len(), (gap + 480) * 2, "{mode} with a gap of {gap}");
+                at += gap + 480;
+            }
+        }
+    }
+
+    #[test]
+    fn gap_before_any_audio_is_silent() {
+        for mode in [Concealment::Silence, Concealment::Repeat, Concealment::Similarity] {
+            let out = Concealer::new(mode, RATE, 2).process(100, sine(0, 480));
+            assert_eq!(out.len(), 580 * 2);
+            assert!(out[..200].iter().all(|smp| *smp == 0.0), "{mode}");
+        }
+    }
+
+    #[test]
+    fn silence_plays_zeros() {
+        let mut concealer = Concealer::new(Concealment::Silence, RATE, 2);
+        concealer.process(0, sine(0, 480));
+        let out = concealer.process(240, sine(720, 480));
+        assert!(out[..480].iter().all(|smp| *smp == 0.0));
+    }
+
+    #[test]
+    fn repeat_plays_last_packet_again() {
+        let mut concealer = Concealer::new(Concealment::Repeat, RATE, 2);
+        concealer.process(0, sine(0, 100));
+        let last = sine(100, 100);
+        concealer.process(0, last.clone());
+        let out = concealer.process(1000, sine(1200, 100));
+        /* Full level during the hold time, then fading */
+        let hold = (RATE * CONCEAL_HOLD_MS / 1000) as usize;
+        for (n, (smp, repeated)) in out[..2000].iter().zip(last.iter().cycle()).enumerate() {
+            match n < hold * 2 {
+                true => assert_eq!(smp, repeated),
+                false => assert!(smp.abs() <= repeated.abs()),
+            }
+        }
+    }
+
+    #[test]
+    fn similarity_continues_the_waveform() {
+        /* 4.5 periods per packet, repeating the packet would jump */
+        let mut concealer = Concealer::new(Concealment::Similarity, RATE, 2);
+        concealer.process(0, sine(0, 540));
+        concealer.process(0, sine(540, 540));
+        let out = concealer.process(100, sine(1180, 540));
+        let expected = sine(1080, 100);
+        for (a, b) in out[..200].iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+    }
+}
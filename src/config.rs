@@ -0,0 +1,542 @@
+use std::{net::IpAddr, path::{Path, PathBuf}, time::Duration};
+use serde::Deserialize;
+use crate::{channels::ChannelMap, conceal::Concealment, pipe::PipeFormat, serial::MidiOutput, text::TextMode, vban::{Output, StreamRoute}};
+
+/// Settings read from a TOML config file. Every key is optional, options given on the
+/// command line take precedence over the file. The command line options are kept in a
+/// `Config` as well, so both are layered the same way.
+///
+/// ```toml
+/// address = "0.0.0.0"
+/// port = 6980
+/// stream-name = "Stream1"
+/// device-name = "default"
+/// silence = 100
+/// timeout = 2000
+/// command = "/usr/local/bin/on_playback.sh"
+/// latency = 40
+/// drift-compensation = true
+/// concealment = "similarity"
+/// mix = false
+/// mix-rate = 48000
+/// mix-channels = 2
+/// output = "alsa"
+/// record-dir = "/var/lib/vban"
+/// rotate-time = 3600
+/// rotate-size = 2000
+/// pipe = "/tmp/vban.fifo"
+/// pipe-format = "s16le"
+/// jack-ports = ["system:playback_1", "system:playback_2"]
+/// pulse-latency = 40
+/// output-rate = 48000
+/// text = "command"
+/// text-stream = "Command1"
+/// midi = "seq"
+/// serial-link = "/tmp/vban-serial"
+///
+/// [ping]
+/// user-name = "Living room"
+/// comment = "Raspberry Pi behind the TV"
+/// color = "#2080ff"
+///
+/// [[stream]]
+/// stream-name = "Stream1"
+/// device-name = "hw:1"
+///
+/// [[stream]]
+/// stream-name = "Stream2"
+/// source = "192.168.1.20"
+/// device-name = "surround71:CARD=Device"
+/// channel-offset = 2
+/// channel-map = "5.1-stereo"
+/// channel-gains = [0.0, -3.0]
+/// limiter = true
+/// device-channels = 8
+/// timeout = 5000
+/// gain = -6.0
+///
+/// [[stream]]
+/// stream-name = "Rehearsal"
+/// output = "wav"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub address : Option<IpAddr>,
+
+    pub port : Option<u16>,
+
+    pub stream_name : Option<String>,
+
+    pub device_name : Option<String>,
+
+    /// Silence prepended to playback in milliseconds
+    pub silence : Option<u32>,
+
+    /// Selects, reorders or mixes the channels of the streams
+    pub channel_map : Option<ChannelMap>,
+
+    /// Rate in Hz the outputs are opened with, streams of other rates are resampled
+    pub output_rate : Option<u32>,
+
+    /// Gain of the streams in dB
+    pub gain : Option<f32>,
+
+    /// Gain per channel in dB on top of `gain`
+    pub channel_gains : Option<Vec<f32>>,
+
+    /// Start the streams muted
+    pub mute : Option<bool>,
+
+    /// Soft limit the streams after the gain
+    pub limiter : Option<bool>,
+
+    /// Script that is run when the playback state changes
+    pub command : Option<String>,
+
+    /// Playback stops when no packet arrived for this many milliseconds
+    pub timeout : Option<u64>,
+
+    /// Target latency of the jitter buffer in milliseconds
+    pub latency : Option<u32>,
+
+    pub drift_compensation : Option<bool>,
+
+    /// Fill the gaps of lost packets with `silence`, `repeat` or `similarity`
+    pub concealment : Option<Concealment>,
+
+    /// Sum all streams into one output on `device-name`
+    pub mix : Option<bool>,
+
+    /// Sample rate of the mixer output
+    pub mix_rate : Option<u32>,
+
+    /// Number of channels of the mixer output
+    pub mix_channels : Option<u16>,
+
+    /// Play streams (`alsa`), record them (`wav`, `flac`), write raw PCM (`pipe`) or discard them (`null`)
+    pub output : Option<Output>,
+
+    /// Directory recordings are written to
+    pub record_dir : Option<PathBuf>,
+
+    /// Start a new recording after this many seconds
+    pub rotate_time : Option<u64>,
+
+    /// Start a new recording before a file exceeds this many megabytes
+    pub rotate_size : Option<u64>,
+
+    /// Named pipe the `pipe` output writes to instead of stdout
+    pub pipe : Option<PathBuf>,
+
+    /// Sample format of the `pipe` output
+    pub pipe_format : Option<PipeFormat>,
+
+    /// Ports the `jack` output connects to
+    pub jack_ports : Option<Vec<String>>,
+
+    /// Buffer latency of the `pulseaudio` output in milliseconds
+    pub pulse_latency : Option<u32>,
+
+    /// Handle VBAN-TEXT packets: print them (`log`), carry out receiver commands (`command`)
+    /// or pass them to `text-script` (`script`)
+    pub text : Option<TextMode>,
+
+    /// Only handle text packets of this stream
+    pub text_stream : Option<String>,
+
+    /// Script that is run for every text packet
+    pub text_script : Option<String>,
+
+    /// Plays MIDI of serial packets on a rawmidi device (`hw:1,0,0`) or sequencer port (`seq`, `seq:128:0`)
+    pub midi : Option<MidiOutput>,
+
+    /// Makes generic serial data available on a pseudo terminal
+    pub serial_pty : Option<bool>,
+
+    /// Symlink to the pseudo terminal, implies `serial-pty`
+    pub serial_link : Option<PathBuf>,
+
+    /// Only handle serial packets of this stream
+    pub serial_stream : Option<String>,
+
+    /// How the receiver identifies itself to VBAN discovery
+    #[serde(default)]
+    pub ping : PingConfig,
+
+    /// Routes for receiving several streams at once. Keys that are not set fall back to
+    /// the top level settings.
+    #[serde(default)]
+    pub stream : Vec<StreamConfig>,
+}
+
+/// The `[ping]` table of the config file: the fields of the reply to PING0 requests that are
+/// not filled in from the other settings
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PingConfig {
+    /// Answer PING0 requests, on by default
+    pub enable : Option<bool>,
+
+    /// Physical device, the audio device of the receiver by default
+    pub device_name : Option<String>,
+
+    pub manufacturer : Option<String>,
+
+    /// Name the receiver is listed with
+    pub user_name : Option<String>,
+
+    pub comment : Option<String>,
+
+    /// Color the receiver is shown with, "#RRGGBB"
+    pub color : Option<String>,
+
+    pub gps_position : Option<String>,
+
+    pub user_position : Option<String>,
+
+    /// Main language of the user, e.g. "EN"
+    pub lang : Option<String>,
+}
+
+/// One `[[stream]]` table of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StreamConfig {
+    pub stream_name : Option<String>,
+
+    /// Only accept the stream from this sender
+    pub source : Option<IpAddr>,
+
+    pub device_name : Option<String>,
+
+    /// Device channel that receives the first channel of the stream
+    pub channel_offset : Option<u16>,
+
+    /// Number of channels the device is opened with
+    pub device_channels : Option<u16>,
+
+    /// Selects, reorders or mixes the channels of the stream, e.g. "2,1" or "5.1-stereo"
+    pub channel_map : Option<ChannelMap>,
+
+    pub silence : Option<u32>,
+
+    /// Playback stops when no packet arrived for this many milliseconds
+    pub timeout : Option<u64>,
+
+    pub latency : Option<u32>,
+
+    pub drift_compensation : Option<bool>,
+
+    pub concealment : Option<Concealment>,
+
+    /// Rate in Hz the output is opened with, streams of other rates are resampled
+    pub output_rate : Option<u32>,
+
+    /// Gain of the stream in dB
+    pub gain : Option<f32>,
+
+    /// Gain per channel in dB on top of `gain`
+    pub channel_gains : Option<Vec<f32>>,
+
+    pub mute : Option<bool>,
+
+    /// Soft limit the stream after the gain
+    pub limiter : Option<bool>,
+
+    pub output : Option<Output>,
+
+    pub jack_ports : Option<Vec<String>>,
+
+    pub pulse_latency : Option<u32>,
+}
+
+impl StreamConfig {
+
+    /// Builds the route of this table, taking unset values from `defaults`
+    pub fn route(&self, defaults : &StreamRoute) -> StreamRoute {
+        StreamRoute {
+            stream_name : self.stream_name.clone().or(defaults.stream_name.clone()),
+            source : self.source.or(defaults.source),
+            device_name : self.device_name.clone().unwrap_or(defaults.device_name.clone()),
+            channel_offset : self.channel_offset.unwrap_or(defaults.channel_offset),
+            device_channels : self.device_channels.or(defaults.device_channels),
+            channel_map : self.channel_map.clone().or(defaults.channel_map.clone()),
+            silence : self.silence.unwrap_or(defaults.silence),
+            timeout : self.timeout.map(Duration::from_millis).unwrap_or(defaults.timeout),
+            latency : self.latency.unwrap_or(defaults.latency),
+            drift_compensation : self.drift_compensation.unwrap_or(defaults.drift_compensation),
+            concealment : self.concealment.unwrap_or(defaults.concealment),
+            output_rate : self.output_rate.or(defaults.output_rate),
+            gain : self.gain.unwrap_or(defaults.gain),
+            channel_gains : self.channel_gains.clone().unwrap_or(defaults.channel_gains.clone()),
+            mute : self.mute.unwrap_or(defaults.mute),
+            limiter : self.limiter.unwrap_or(defaults.limiter),
+            output : self.output.unwrap_or(defaults.output),
+            record : defaults.record.clone(),
+            pipe : defaults.pipe.clone(),
+            jack_ports : self.jack_ports.clone().or(defaults.jack_ports.clone()),
+            pulse_latency : self.pulse_latency.or(defaults.pulse_latency),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Could not read config file {}: {err}", path.display()),
+            ConfigError::Parse(path, err) => write!(f, "Invalid config file {}: {err}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+
+    pub fn load(path : &Path) -> Result<Self, ConfigError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => return Err(ConfigError::Io(path.to_path_buf(), err)),
+        };
+        Self::parse(&text).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))
+    }
+
+    pub fn parse(text : &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Sets the route settings that are given here and leaves the others alone
+    pub fn apply(&self, route : &mut StreamRoute) {
+        if let Some(name) = &self.stream_name {
+            route.stream_name = Some(name.clone());
+        }
+        if let Some(name) = &self.device_name {
+            route.device_name = name.clone();
+        }
+        if let Some(ms) = self.silence {
+            route.silence = ms;
+        }
+        if let Some(map) = &self.channel_map {
+            route.channel_map = Some(map.clone());
+        }
+        if let Some(rate) = self.output_rate {
+            route.output_rate = Some(rate);
+        }
+        if let Some(db) = self.gain {
+            route.gain = db;
+        }
+        if let Some(gains) = &self.channel_gains {
+            route.channel_gains = gains.clone();
+        }
+        if let Some(mute) = self.mute {
+            route.mute = mute;
+        }
+        if let Some(limiter) = self.limiter {
+            route.limiter = limiter;
+        }
+        if let Some(ms) = self.timeout {
+            route.timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.latency {
+            route.latency = ms;
+        }
+        if let Some(enable) = self.drift_compensation {
+            route.drift_compensation = enable;
+        }
+        if let Some(concealment) = self.concealment {
+            route.concealment = concealment;
+        }
+        if let Some(output) = self.output {
+            route.output = output;
+        }
+        if let Some(dir) = &self.record_dir {
+            route.record.directory = dir.clone();
+        }
+        if let Some(seconds) = self.rotate_time {
+            route.record.max_duration = Some(Duration::from_secs(seconds));
+        }
+        if let Some(mb) = self.rotate_size {
+            route.record.max_size = Some(mb * 1_000_000);
+        }
+        if let Some(path) = &self.pipe {
+            route.pipe.path = Some(path.clone());
+        }
+        if let Some(format) = self.pipe_format {
+            route.pipe.format = format;
+        }
+        if let Some(ports) = &self.jack_ports {
+            route.jack_ports = Some(ports.clone());
+        }
+        if let Some(ms) = self.pulse_latency {
+            route.pulse_latency = Some(ms);
+        }
+    }
+
+    /// Builds the routes of the file. Keys of a `[[stream]]` table take precedence over the top
+    /// level ones, the settings of `overrides`, usually the command line, over both.
+    pub fn routes(&self, overrides : &Config) -> Vec<StreamRoute> {
+        let mut defaults = StreamRoute::new(String::from("default"));
+        self.apply(&mut defaults);
+        let mut routes = match self.stream.is_empty() {
+            true => vec![defaults],
+            false => self.stream.iter().map(|stream| stream.route(&defaults)).collect(),
+        };
+        for route in &mut routes {
+            overrides.apply(route);
+        }
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_sets_nothing() {
+        let config = Config::parse("").unwrap();
+        assert!(config.port.is_none() && config.drift_compensation.is_none() && config.stream.is_empty());
+        assert!(config.ping.enable.is_none());
+    }
+
+    #[test]
+    fn parses_top_level_keys() {
+        let config = Config::parse(r##"
+            address = "127.0.0.1"
+            port = 6981
+            stream-name = "Stream1"
+            drift-compensation = false
+            concealment = "repeat"
+            output = "wav"
+            pipe-format = "f32le"
+            channel-map = "5.1-stereo"
+            channel-gains = [0.0, -3.0]
+            mute = true
+            midi = "seq:128:0"
+
+            [ping]
+            user-name = "Living room"
+            color = "#2080ff"
+        "##).unwrap();
+        assert_eq!(config.address, Some(IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(config.port, Some(6981));
+        assert_eq!(config.stream_name.as_deref(), Some("Stream1"));
+        assert_eq!(config.drift_compensation, Some(false));
+        assert_eq!(config.concealment, Some(Concealment::Repeat));
+        assert_eq!(config.output, Some(Output::Wav));
+        assert_eq!(config.pipe_format, Some(PipeFormat::F32le));
+        assert_eq!(config.channel_map, Some("5.1-stereo".parse().unwrap()));
+        assert_eq!(config.channel_gains, Some(vec![0.0, -3.0]));
+        assert_eq!(config.mute, Some(true));
+        assert_eq!(config.midi, Some("seq:128:0".parse().unwrap()));
+        assert_eq!(config.ping.user_name.as_deref(), Some("Living room"));
+        assert_eq!(config.ping.color.as_deref(), Some("#2080ff"));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Config::parse("prot = 6980").is_err());
+        assert!(Config::parse("[ping]\nname = \"x\"").is_err());
+        assert!(Config::parse("[[stream]]\ndevice = \"hw:1\"").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(Config::parse("port = 70000").is_err());
+        assert!(Config::parse("output = \"speakers\"").is_err());
+        assert!(Config::parse("concealment = \"guess\"").is_err());
+        assert!(Config::parse("channel-map = \"1,x\"").is_err());
+    }
+
+    #[test]
+    fn stream_tables_fall_back_to_defaults() {
+        let config = Config::parse(r#"
+            [[stream]]
+            stream-name = "Stream1"
+            device-name = "hw:1"
+
+            [[stream]]
+            stream-name = "Stream2"
+            source = "192.168.1.20"
+            channel-offset = 2
+            timeout = 5000
+            gain = -6.0
+            mute = true
+            drift-compensation = false
+            output = "null"
+        "#).unwrap();
+        assert_eq!(config.stream.len(), 2);
+
+        let mut defaults = StreamRoute::new(String::from("default"));
+        defaults.latency = 80;
+        defaults.gain = -1.0;
+
+        let first = config.stream[0].route(&defaults);
+        assert_eq!(first.stream_name.as_deref(), Some("Stream1"));
+        assert_eq!(first.device_name, "hw:1");
+        assert_eq!(first.source, None);
+        assert_eq!(first.latency, 80);
+        assert_eq!(first.gain, -1.0);
+        assert!(first.drift_compensation && !first.mute);
+        assert_eq!(first.output, Output::Alsa);
+
+        let second = config.stream[1].route(&defaults);
+        assert_eq!(second.device_name, "default");
+        assert_eq!(second.source, Some(IpAddr::from([192, 168, 1, 20])));
+        assert_eq!(second.channel_offset, 2);
+        assert_eq!(second.timeout, Duration::from_millis(5000));
+        assert_eq!(second.gain, -6.0);
+        assert!(!second.drift_compensation && second.mute);
+        assert_eq!(second.output, Output::Null);
+    }
+
+    #[test]
+    fn command_line_beats_the_file() {
+        let config = Config::parse(r#"
+            latency = 60
+            gain = -3.0
+            limiter = true
+            rotate-size = 10
+
+            [[stream]]
+            stream-name = "Stream1"
+            device-name = "hw:1"
+            gain = -6.0
+            output = "wav"
+
+            [[stream]]
+            stream-name = "Stream2"
+            latency = 100
+        "#).unwrap();
+        let cli = Config {
+            device_name : Some(String::from("hw:2")),
+            gain : Some(-1.0),
+            limiter : Some(false),
+            ..Default::default()
+        };
+
+        let routes = config.routes(&cli);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].stream_name.as_deref(), Some("Stream1"));
+        assert_eq!(routes[0].device_name, "hw:2");
+        assert_eq!(routes[0].gain, -1.0);
+        assert!(!routes[0].limiter);
+        assert_eq!(routes[0].output, Output::Wav);
+        assert_eq!(routes[0].latency, 60);
+        assert_eq!(routes[0].record.max_size, Some(10_000_000));
+        /* A table beats the top level of the file */
+        assert_eq!(routes[1].latency, 100);
+
+        let routes = Config::default().routes(&cli);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].device_name, "hw:2");
+        assert_eq!(routes[0].gain, -1.0);
+    }
+}
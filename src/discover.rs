@@ -0,0 +1,256 @@
+//! Finding VBAN devices and streams on the network. Devices are asked with a broadcast PING0
+//! request, streams are listed from the audio packets that arrive on the port. Like `packet`
+//! this does not depend on ALSA.
+
+use std::{io::ErrorKind, net::{IpAddr, SocketAddr, UdpSocket}, time::{Duration, Instant}};
+use crate::{error::VbanError, packet::{AudioFormat, VBanProtocol, VbanPacket, VBAN_PROTOCOL_MAX_SIZE}, service::{self, Ping0, VBAN_SERVICE_FNCT_REPLY, VBAN_SERVICE_IDENTIFICATION}};
+
+/// Longest time a single receive waits, so `listen` ends close to its deadline
+const DISCOVERY_POLL : Duration = Duration::from_millis(100);
+
+/// A device that answered a PING0 request
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredDevice {
+    pub address : SocketAddr,
+
+    pub info : Ping0,
+}
+
+/// An audio stream that was seen on the port
+#[derive(Clone, Debug)]
+pub struct SeenStream {
+    pub source : IpAddr,
+
+    pub stream_name : String,
+
+    /// Format of the last packet
+    pub format : AudioFormat,
+
+    pub packets : u64,
+
+    first_seen : Instant,
+
+    last_seen : Instant,
+}
+
+impl SeenStream {
+
+    /// Packets per second between the first and the last packet, 0 until two arrived
+    pub fn packet_rate(&self) -> f64 {
+        let span = (self.last_seen - self.first_seen).as_secs_f64();
+        match span > 0.0 {
+            true => (self.packets - 1) as f64 / span,
+            false => 0.0,
+        }
+    }
+}
+
+/// Collects PING0 replies and audio streams arriving on a socket
+pub struct Discovery {
+    socket : UdpSocket,
+
+    nu_frame : u32,
+
+    devices : Vec<DiscoveredDevice>,
+
+    streams : Vec<SeenStream>,
+}
+
+impl Discovery {
+
+    /// Binds to `addr`, usually the VBAN port on all interfaces. Fails if a receiver is
+    /// already listening there.
+    pub fn bind(addr : SocketAddr) -> Result<Self, VbanError> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_broadcast(true)?;
+        socket.set_read_timeout(Some(DISCOVERY_POLL))?;
+        Ok(Self {
+            socket,
+            nu_frame : 0,
+            devices : Vec::new(),
+            streams : Vec::new(),
+        })
+    }
+
+    /// Sends a PING0 request to `destination`, e.g. the broadcast address and the VBAN port.
+    /// Devices reply to the port the socket is bound to.
+    pub fn ping(&mut self, destination : SocketAddr) -> Result<(), VbanError> {
+        self.socket.send_to(&service::ping_request(self.nu_frame), destination)?;
+        self.nu_frame = self.nu_frame.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Receives packets for `duration` and records replies and streams. Packets that are not
+    /// VBAN or can not be parsed are skipped.
+    pub fn listen(&mut self, duration : Duration) -> Result<(), VbanError> {
+        let deadline = Instant::now() + duration;
+        let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+        while Instant::now() < deadline {
+            let (size, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if let Ok(packet) = VbanPacket::parse(&buf[..size]) {
+                self.record(addr, &packet);
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, addr : SocketAddr, packet : &VbanPacket) {
+        match packet.protocol() {
+            VBanProtocol::VbanProtocolAudio => {
+                let Ok(format) = packet.audio_format() else {
+                    return;
+                };
+                let now = Instant::now();
+                let stream_name = packet.stream_name_str();
+                match self.streams.iter_mut().find(|stream| stream.source == addr.ip() && stream.stream_name == stream_name) {
+                    Some(stream) => {
+                        stream.format = format;
+                        stream.packets += 1;
+                        stream.last_seen = now;
+                    },
+                    None => self.streams.push(SeenStream {
+                        source : addr.ip(),
+                        stream_name : stream_name.into_owned(),
+                        format,
+                        packets : 1,
+                        first_seen : now,
+                        last_seen : now,
+                    }),
+                }
+            },
+            VBanProtocol::VbanProtocolService => {
+                let Ok((service_type, function)) = packet.service() else {
+                    return;
+                };
+                /* Requests, including our own broadcast, are skipped. Some devices flag the
+                 * reply in the service type instead of the function. */
+                let reply = (function | service_type) & VBAN_SERVICE_FNCT_REPLY != 0;
+                if !reply || service_type & !VBAN_SERVICE_FNCT_REPLY != VBAN_SERVICE_IDENTIFICATION {
+                    return;
+                }
+                let Ok(info) = Ping0::parse(packet.payload()) else {
+                    return;
+                };
+                match self.devices.iter_mut().find(|device| device.address == addr) {
+                    Some(device) => device.info = info,
+                    None => self.devices.push(DiscoveredDevice { address : addr, info }),
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Devices that answered, in the order of their first reply
+    pub fn devices(&self) -> &[DiscoveredDevice] {
+        &self.devices
+    }
+
+    /// Audio streams that were seen, in the order of their first packet
+    pub fn streams(&self) -> &[SeenStream] {
+        &self.streams
+    }
+}
+
+/// Broadcasts a PING0 request on `port` and listens there for `duration`
+pub fn discover(port : u16, duration : Duration) -> Result<Discovery, VbanError> {
+    let mut discovery = Discovery::bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+    discovery.ping(SocketAddr::from(([255, 255, 255, 255], port)))?;
+    discovery.listen(duration)?;
+    Ok(discovery)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{stream_name_bytes, VBanBitResolution, VBanHeader, VBanSampleRates};
+
+    fn local_discovery() -> (Discovery, SocketAddr) {
+        let discovery = Discovery::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = discovery.socket.local_addr().unwrap();
+        (discovery, addr)
+    }
+
+    fn audio_packet(name : &str, format : &AudioFormat, nu_frame : u32) -> Vec<u8> {
+        let samples = vec![0.0; format.num_samples as usize * format.num_channels as usize];
+        VBanHeader::audio(stream_name_bytes(name).unwrap(), format, nu_frame).to_packet(&format.bit_resolution.encode(&samples))
+    }
+
+    #[test]
+    fn records_devices_that_reply() {
+        let (mut discovery, addr) = local_discovery();
+        let device = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        device.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        discovery.ping(device.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+        let (size, source) = device.recv_from(&mut buf).unwrap();
+        assert_eq!(source, addr);
+        let request = VbanPacket::parse(&buf[..size]).unwrap();
+        assert!(service::is_ping_request(&request));
+
+        let mut info = Ping0::receptor();
+        info.device_name = String::from("Living room");
+        device.send_to(&service::ping_reply(&request, &info), source).unwrap();
+        /* Answering twice updates the device instead of listing it again */
+        info.user_name = String::from("Kitchen");
+        device.send_to(&service::ping_reply(&request, &info), source).unwrap();
+        discovery.listen(Duration::from_millis(200)).unwrap();
+
+        assert_eq!(discovery.devices(), [DiscoveredDevice { address : device.local_addr().unwrap(), info }]);
+        assert!(discovery.streams().is_empty());
+    }
+
+    #[test]
+    fn skips_its_own_request() {
+        let (mut discovery, addr) = local_discovery();
+        discovery.ping(addr).unwrap();
+        discovery.socket.send_to(b"not VBAN", addr).unwrap();
+        discovery.listen(Duration::from_millis(200)).unwrap();
+        assert!(discovery.devices().is_empty());
+        assert!(discovery.streams().is_empty());
+    }
+
+    #[test]
+    fn lists_streams_with_format_and_rate() {
+        let (mut discovery, addr) = local_discovery();
+        let sender = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let stereo = AudioFormat {
+            sample_rate : VBanSampleRates::SampleRate48000Hz,
+            num_samples : 256,
+            num_channels : 2,
+            bit_resolution : VBanBitResolution::VbanBitfmt16Int,
+        };
+        let mono = AudioFormat {
+            sample_rate : VBanSampleRates::SampleRate44100Hz,
+            num_samples : 128,
+            num_channels : 1,
+            bit_resolution : VBanBitResolution::VbanBitfmt24Int,
+        };
+
+        sender.send_to(&audio_packet("Mic", &mono, 0), addr).unwrap();
+        /* Every listen lasts at least 20 ms, which bounds the packet rate */
+        for nu_frame in 0..5 {
+            sender.send_to(&audio_packet("Stream1", &stereo, nu_frame), addr).unwrap();
+            discovery.listen(Duration::from_millis(20)).unwrap();
+        }
+
+        let streams = discovery.streams();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].stream_name, "Mic");
+        assert_eq!(streams[0].source, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(streams[0].format, mono);
+        assert_eq!(streams[0].packets, 1);
+        assert_eq!(streams[0].packet_rate(), 0.0);
+
+        assert_eq!(streams[1].stream_name, "Stream1");
+        assert_eq!(streams[1].format, stereo);
+        assert_eq!(streams[1].packets, 5);
+        let rate = streams[1].packet_rate();
+        assert!(rate > 1.0 && rate <= 50.0, "{rate} packets per second");
+        assert!(discovery.devices().is_empty());
+    }
+}
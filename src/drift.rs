@@ -0,0 +1,142 @@
+use std::time::Instant;
+
+/// Largest deviation of the playback ratio from 1.0 the estimator will ask for
+const DRIFT_MAX_CORRECTION : f64 = 0.005;
+
+/// Time constant in seconds of the low pass that removes network jitter from the fill level
+const DRIFT_SMOOTHING_S : f64 = 2.0;
+
+/// Ratio correction per second of fill level error
+const DRIFT_PROPORTIONAL_GAIN : f64 = 0.02;
+
+/// Ratio correction per second of fill level error accumulated over one second
+const DRIFT_INTEGRAL_GAIN : f64 = 0.0005;
+
+/// Estimates the clock drift between sender and sound card from the buffer fill level.
+///
+/// A PI controller works on the low pass filtered difference between fill level and target.
+/// The integral part settles on the actual clock ratio, the proportional part pulls the fill
+/// level back to the target. The result is the ratio a resampler has to play the stream at.
+pub struct DriftEstimator {
+    sample_rate : f64,
+
+    smoothed : Option<f64>,
+
+    integral : f64,
+
+    ratio : f64,
+
+    last_update : Instant,
+}
+
+impl DriftEstimator {
+
+    pub fn new(sample_rate : u32) -> Self {
+        Self {
+            sample_rate : sample_rate as f64,
+            smoothed : None,
+            integral : 0.0,
+            ratio : 1.0,
+            last_update : Instant::now(),
+        }
+    }
+
+    /// Feeds the current fill level and target in frames and returns the new playback ratio
+    /// (output frames per input frame).
+    pub fn update(&mut self, fill : usize, target : usize) -> f64 {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+        self.advance(fill, target, dt)
+    }
+
+    /// One controller step `dt` seconds after the previous one
+    fn advance(&mut self, fill : usize, target : usize, dt : f64) -> f64 {
+        let error = (fill as f64 - target as f64) / self.sample_rate;
+        let smoothed = match self.smoothed {
+            None => error,
+            Some(prev) => prev + (error - prev) * (dt / DRIFT_SMOOTHING_S).min(1.0),
+        };
+        self.smoothed = Some(smoothed);
+
+        self.integral = (self.integral + smoothed * dt * DRIFT_INTEGRAL_GAIN).clamp(-DRIFT_MAX_CORRECTION, DRIFT_MAX_CORRECTION);
+
+        /* A buffer above target means the sender is faster, so input has to be consumed faster */
+        let correction = (smoothed * DRIFT_PROPORTIONAL_GAIN + self.integral).clamp(-DRIFT_MAX_CORRECTION, DRIFT_MAX_CORRECTION);
+        self.ratio = 1.0 - correction;
+        self.ratio
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Deviation of the sender clock from the sound card clock in parts per million
+    pub fn ppm(&self) -> f64 {
+        (1.0 / self.ratio - 1.0) * 1e6
+    }
+
+    /// Restarts the filter, e.g. after an underrun. The learned clock ratio is kept.
+    pub fn reset(&mut self) {
+        self.smoothed = None;
+        self.last_update = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE : u32 = 48000;
+
+    const TARGET : usize = 1920;
+
+    /// Plays a sender that is `ppm` faster than the sound card for `seconds` and returns the
+    /// final fill level
+    fn simulate(drift : &mut DriftEstimator, ppm : f64, seconds : f64) -> f64 {
+        let dt = 0.01;
+        let mut fill = TARGET as f64;
+        for _ in 0..(seconds / dt) as usize {
+            let ratio = drift.advance(fill.round() as usize, TARGET, dt);
+            fill += RATE as f64 * dt * ((1.0 + ppm * 1e-6) - 1.0 / ratio);
+        }
+        fill
+    }
+
+    #[test]
+    fn converges_on_clock_ratio() {
+        let mut drift = DriftEstimator::new(RATE);
+        let fill = simulate(&mut drift, 100.0, 2000.0);
+        assert!((drift.ppm() - 100.0).abs() < 2.0, "{} ppm", drift.ppm());
+        assert!((fill - TARGET as f64).abs() < 10.0, "fill {fill}");
+
+        let fill = simulate(&mut drift, -250.0, 2000.0);
+        assert!((drift.ppm() + 250.0).abs() < 2.0, "{} ppm", drift.ppm());
+        assert!((fill - TARGET as f64).abs() < 10.0, "fill {fill}");
+    }
+
+    #[test]
+    fn clamps_correction() {
+        let mut drift = DriftEstimator::new(RATE);
+        for _ in 0..10000 {
+            drift.advance(TARGET * 100, TARGET, 0.1);
+        }
+        assert_eq!(drift.ratio(), 1.0 - DRIFT_MAX_CORRECTION);
+        for _ in 0..100000 {
+            drift.advance(0, TARGET, 0.1);
+        }
+        assert_eq!(drift.ratio(), 1.0 + DRIFT_MAX_CORRECTION);
+    }
+
+    #[test]
+    fn reset_keeps_learned_ratio() {
+        let mut drift = DriftEstimator::new(RATE);
+        simulate(&mut drift, 100.0, 2000.0);
+        let ratio = drift.ratio();
+        drift.reset();
+        assert_eq!(drift.ratio(), ratio);
+        /* At the target only the integral part is left */
+        let after = drift.advance(TARGET, TARGET, 0.01);
+        assert!((after - ratio).abs() < 1e-5, "{ratio} became {after}");
+    }
+}
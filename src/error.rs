@@ -0,0 +1,136 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum VbanError {
+    /// Socket or file operation failed
+    Io(std::io::Error),
+
+    /// The audio device reported an error
+    #[cfg(feature = "alsa")]
+    Alsa(alsa::Error),
+
+    /// The PipeWire daemon reported an error
+    #[cfg(feature = "pipewire")]
+    PipeWire(::pipewire::Error),
+
+    /// The JACK server reported an error
+    #[cfg(feature = "jack")]
+    Jack(::jack::Error),
+
+    /// The PulseAudio server reported an error
+    #[cfg(feature = "pulseaudio")]
+    PulseAudio(crate::pulse::PulseError),
+
+    /// The audio device does not accept any sample format the stream can be converted to
+    UnsupportedDeviceFormat(u8),
+
+    StreamNameTooLong(usize),
+
+    NotVban,
+
+    PacketTooShort(usize),
+
+    PacketTooLong(usize),
+
+    /// Sample rate index of the header is not in the VBAN sample rate list
+    InvalidSampleRate(u8),
+
+    InvalidBitResolution(u8),
+
+    /// Sample rate in Hz that is not in the VBAN sample rate list
+    UnsupportedSampleRate(u32),
+
+    /// VBAN carries at most 256 channels
+    TooManyChannels(u16),
+
+    /// The output or the packet format can not carry this many channels
+    UnsupportedChannelCount(u16),
+
+    /// The reserved bit of the format byte is set
+    ReservedBitSet,
+
+    UnsupportedProtocol(u8),
+
+    UnsupportedCodec(u8),
+
+    /// Text packets in an encoding other than ASCII, UTF-8 or UTF-16
+    UnsupportedTextFormat(u8),
+
+    /// Serial packets that are neither generic serial data nor MIDI
+    UnsupportedSerialType(u8),
+}
+
+impl fmt::Display for VbanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VbanError::Io(err) => write!(f, "I/O error: {err}"),
+            #[cfg(feature = "alsa")]
+            VbanError::Alsa(err) => write!(f, "Audio device error: {err}"),
+            #[cfg(feature = "pipewire")]
+            VbanError::PipeWire(err) => write!(f, "PipeWire error: {err}"),
+            #[cfg(feature = "jack")]
+            VbanError::Jack(err) => write!(f, "JACK error: {err}"),
+            #[cfg(feature = "pulseaudio")]
+            VbanError::PulseAudio(err) => write!(f, "PulseAudio error: {err}"),
+            VbanError::UnsupportedDeviceFormat(bits) => write!(f, "Audio device does not support any sample format suitable for {bits} bit."),
+            VbanError::StreamNameTooLong(len) => write!(f, "Stream name exceeds the limit of 16 characters (found {len})."),
+            VbanError::NotVban => write!(f, "Packet is not VBAN"),
+            VbanError::PacketTooShort(size) => write!(f, "Discarding packet because it is too short ({size} bytes)."),
+            VbanError::PacketTooLong(size) => write!(f, "Discarding packet because it exceeds the maximum size ({size} bytes)."),
+            VbanError::InvalidSampleRate(value) => write!(f, "Discarding packet with invalid sample rate index {value}."),
+            VbanError::InvalidBitResolution(value) => write!(f, "Discarding packet with invalid bit resolution {value}."),
+            VbanError::UnsupportedSampleRate(rate) => write!(f, "Sample rate {rate} Hz is not supported by VBAN."),
+            VbanError::TooManyChannels(count) => write!(f, "VBAN supports 1 to 256 channels (found {count})."),
+            VbanError::UnsupportedChannelCount(count) => write!(f, "{count} channels are not supported by this output or format."),
+            VbanError::ReservedBitSet => write!(f, "Discarding packet because the reserved bit of the format is set."),
+            VbanError::UnsupportedProtocol(value) => write!(f, "Discarding packet with protocol {value:#04x} because it is not supported."),
+            VbanError::UnsupportedCodec(value) => write!(f, "Any codecs other than PCM are not supported (found {value:#04x})."),
+            VbanError::UnsupportedTextFormat(value) => write!(f, "Discarding text packet in unsupported format {value:#04x}."),
+            VbanError::UnsupportedSerialType(value) => write!(f, "Discarding serial packet of unsupported type {value:#04x}."),
+        }
+    }
+}
+
+impl std::error::Error for VbanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VbanError::Io(err) => Some(err),
+            #[cfg(feature = "alsa")]
+            VbanError::Alsa(err) => Some(err),
+            #[cfg(feature = "pipewire")]
+            VbanError::PipeWire(err) => Some(err),
+            #[cfg(feature = "jack")]
+            VbanError::Jack(err) => Some(err),
+            #[cfg(feature = "pulseaudio")]
+            VbanError::PulseAudio(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for VbanError {
+    fn from(err : std::io::Error) -> Self {
+        VbanError::Io(err)
+    }
+}
+
+#[cfg(feature = "pipewire")]
+impl From<::pipewire::Error> for VbanError {
+    fn from(err : ::pipewire::Error) -> Self {
+        VbanError::PipeWire(err)
+    }
+}
+
+#[cfg(feature = "jack")]
+impl From<::jack::Error> for VbanError {
+    fn from(err : ::jack::Error) -> Self {
+        VbanError::Jack(err)
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl From<alsa::Error> for VbanError {
+    fn from(err : alsa::Error) -> Self {
+        VbanError::Alsa(err)
+    }
+}
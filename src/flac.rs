@@ -0,0 +1,652 @@
+use std::{cell::RefCell, fs::File, io::{BufWriter, Seek, SeekFrom, Write}, net::IpAddr, path::PathBuf};
+use chrono::Local;
+use crate::{error::VbanError, packet::VBanBitResolution, record::RecordOptions, sink::VbanSink};
+
+/// Frames per FLAC block, the last block of a file may be shorter
+const FLAC_BLOCK_SIZE : usize = 4096;
+
+/// FLAC frames can hold up to eight channels
+const FLAC_MAX_CHANNELS : u16 = 8;
+
+const FLAC_MAX_FIXED_ORDER : usize = 4;
+
+const FLAC_MAX_PARTITION_ORDER : u32 = 8;
+
+/// Largest Rice parameter of the 4 bit parameter coding, 15 is the escape code
+const FLAC_MAX_RICE_PARAMETER : u32 = 14;
+
+const FLAC_STREAMINFO_SIZE : u32 = 34;
+
+/// Sync code followed by the flags of a fixed block size stream
+const FLAC_FRAME_SYNC : u64 = 0xFFF8;
+
+/// Subframe headers: padding bit, six type bits and the wasted bits flag
+const FLAC_SUBFRAME_CONSTANT : u64 = 0x00;
+const FLAC_SUBFRAME_VERBATIM : u64 = 0x02;
+const FLAC_SUBFRAME_FIXED : u64 = 0x10;
+
+const FLAC_BLOCK_STREAMINFO : u8 = 0;
+const FLAC_BLOCK_VORBIS_COMMENT : u8 = 4;
+
+const FLAC_VENDOR : &str = concat!("vban_sink ", env!("CARGO_PKG_VERSION"));
+
+/// Collects bits MSB first
+#[derive(Default)]
+struct BitWriter {
+    bytes : Vec<u8>,
+    acc : u64,
+    bits : u32,
+}
+
+impl BitWriter {
+
+    /// Writes the lowest `bits` bits of `value`, at most 56 at once
+    fn write(&mut self, value : u64, bits : u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = self.acc << bits | value & (u64::MAX >> (64 - bits));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value : i64, bits : u32) {
+        self.write(value as u64 & ((1u64 << bits) - 1), bits);
+    }
+
+    fn write_unary(&mut self, mut zeros : u64) {
+        while zeros > 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn write_rice(&mut self, value : u64, parameter : u32) {
+        self.write_unary(value >> parameter);
+        self.write(value, parameter);
+    }
+
+    /// Pads to the next byte boundary with zeros
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+fn crc8(data : &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 })
+    })
+}
+
+fn crc16(data : &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x8005 } else { crc << 1 })
+    })
+}
+
+/// Frame numbers are coded like UTF-8 characters, extended to 36 bits
+fn write_utf8_number(bits : &mut BitWriter, value : u64) {
+    if value < 0x80 {
+        bits.write(value, 8);
+        return;
+    }
+    let continuation = match value {
+        0..0x800 => 1,
+        0x800..0x10000 => 2,
+        0x10000..0x200000 => 3,
+        0x200000..0x4000000 => 4,
+        0x4000000..0x80000000 => 5,
+        _ => 6,
+    };
+    let lead_mask = !(0xFFu64 >> (continuation + 1)) & 0xFF;
+    bits.write(lead_mask | value >> (6 * continuation), 8);
+    for index in (0..continuation).rev() {
+        bits.write(0x80 | (value >> (6 * index)) & 0x3F, 8);
+    }
+}
+
+fn zigzag(residual : i64) -> u64 {
+    (residual << 1 ^ residual >> 63) as u64
+}
+
+/// Zigzag coded residuals of the fixed polynomial predictor of `order`, the warm up samples
+/// are left out
+fn fixed_residuals(samples : &[i64], order : usize) -> Vec<u64> {
+    let mut residuals = samples.to_vec();
+    for _ in 0..order {
+        for index in (1..residuals.len()).rev() {
+            residuals[index] -= residuals[index - 1];
+        }
+    }
+    residuals[order..].iter().map(|r| zigzag(*r)).collect()
+}
+
+/// Best Rice parameter and the bits it needs for one partition of zigzag coded residuals
+fn rice_partition(residuals : &[u64]) -> (u32, u64) {
+    let sum : u64 = residuals.iter().sum();
+    let mean = sum / residuals.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(FLAC_MAX_RICE_PARAMETER);
+    (guess.saturating_sub(1)..=(guess + 1).min(FLAC_MAX_RICE_PARAMETER))
+        .map(|k| (k, residuals.iter().map(|u| (u >> k) + 1 + k as u64).sum::<u64>()))
+        .min_by_key(|(_k, bits)| *bits)
+        .unwrap()
+}
+
+/// Chooses the partition order with the fewest bits. Returns it, the parameter of each
+/// partition and the total size of the residual section.
+fn rice_partitions(residuals : &[u64], block_size : usize, order : usize) -> (u32, Vec<u32>, u64) {
+    let mut best : Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=FLAC_MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        if !block_size.is_multiple_of(partitions) || block_size / partitions <= order {
+            break;
+        }
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut bits = 2 + 4;
+        let mut start = 0;
+        for partition in 0..partitions {
+            let len = block_size / partitions - if partition == 0 { order } else { 0 };
+            let (parameter, size) = rice_partition(&residuals[start..start + len]);
+            parameters.push(parameter);
+            bits += 4 + size;
+            start += len;
+        }
+        if best.as_ref().is_none_or(|(_order, _params, best_bits)| bits < *best_bits) {
+            best = Some((partition_order, parameters, bits));
+        }
+    }
+    best.unwrap()
+}
+
+/// Encodes one channel of a block with whichever of constant, fixed prediction or verbatim
+/// is the smallest
+fn write_subframe(bits : &mut BitWriter, samples : &[i64], bits_per_sample : u32) {
+    if samples.iter().all(|smp| *smp == samples[0]) {
+        bits.write(FLAC_SUBFRAME_CONSTANT, 8);
+        bits.write_signed(samples[0], bits_per_sample);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+    let best = (0..=FLAC_MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residuals = fixed_residuals(samples, order);
+            let (partition_order, parameters, size) = rice_partitions(&residuals, samples.len(), order);
+            (order, residuals, partition_order, parameters, size + (order as u64 * bits_per_sample as u64))
+        })
+        .min_by_key(|(_order, _residuals, _partition_order, _parameters, size)| *size);
+
+    match best {
+        Some((order, residuals, partition_order, parameters, size)) if size < verbatim_bits => {
+            bits.write(FLAC_SUBFRAME_FIXED | (order as u64) << 1, 8);
+            for smp in &samples[..order] {
+                bits.write_signed(*smp, bits_per_sample);
+            }
+            bits.write(0, 2);
+            bits.write(partition_order as u64, 4);
+            let mut start = 0;
+            for (partition, parameter) in parameters.iter().enumerate() {
+                let len = samples.len() / parameters.len() - if partition == 0 { order } else { 0 };
+                bits.write(*parameter as u64, 4);
+                for residual in &residuals[start..start + len] {
+                    bits.write_rice(*residual, *parameter);
+                }
+                start += len;
+            }
+        },
+        _ => {
+            bits.write(FLAC_SUBFRAME_VERBATIM, 8);
+            for smp in samples {
+                bits.write_signed(*smp, bits_per_sample);
+            }
+        },
+    }
+}
+
+/// Encodes a block of interleaved samples into a FLAC frame
+fn encode_frame(samples : &[i64], num_channels : usize, bits_per_sample : u32, frame_number : u64) -> Vec<u8> {
+    let block_size = samples.len() / num_channels;
+    let mut bits = BitWriter::default();
+
+    bits.write(FLAC_FRAME_SYNC, 16);
+    let size_code = if block_size == FLAC_BLOCK_SIZE { 12 } else { 7 };
+    bits.write(size_code, 4);
+    /* Sample rate is taken from STREAMINFO */
+    bits.write(0, 4);
+    bits.write(num_channels as u64 - 1, 4);
+    let depth_code = match bits_per_sample {
+        8 => 0b001,
+        16 => 0b100,
+        _ => 0b110,
+    };
+    bits.write(depth_code, 3);
+    bits.write(0, 1);
+    write_utf8_number(&mut bits, frame_number);
+    if size_code == 7 {
+        bits.write(block_size as u64 - 1, 16);
+    }
+    let crc = crc8(&bits.bytes);
+    bits.write(crc as u64, 8);
+
+    let mut channel = Vec::with_capacity(block_size);
+    for ch in 0..num_channels {
+        channel.clear();
+        channel.extend(samples.iter().skip(ch).step_by(num_channels));
+        write_subframe(&mut bits, &channel, bits_per_sample);
+    }
+
+    let mut frame = bits.into_bytes();
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+fn metadata_block_header(block_type : u8, last : bool, length : u32) -> [u8; 4] {
+    let len = length.to_be_bytes();
+    [block_type | if last { 0x80 } else { 0 }, len[1], len[2], len[3]]
+}
+
+struct FlacFile {
+    writer : BufWriter<File>,
+
+    path : PathBuf,
+
+    frames : u64,
+
+    bytes : u64,
+
+    frame_number : u64,
+
+    min_frame_size : u32,
+
+    max_frame_size : u32,
+}
+
+struct FlacState {
+    current : Option<FlacFile>,
+
+    /// Interleaved samples that do not fill a block yet
+    pending : Vec<i64>,
+
+    /// Blocks written since STREAMINFO was last brought up to date
+    unsynced : usize,
+}
+
+/// Records a stream to FLAC files. Stream name, sender, sample rate and start time are stored
+/// as Vorbis comments. 32 bit and float streams are stored with 24 bit, the most FLAC
+/// decoders support. Files are rotated by duration or size as set in the `RecordOptions`.
+pub struct FlacFileSink {
+    options : RecordOptions,
+
+    stream_name : String,
+
+    source : IpAddr,
+
+    sample_rate : u32,
+
+    num_channels : u16,
+
+    bits_per_sample : u32,
+
+    state : RefCell<FlacState>,
+}
+
+impl FlacFileSink {
+
+    /// Creates the directory if necessary and opens the first file
+    pub fn create(options : &RecordOptions, stream_name : &str, source : IpAddr, sample_rate : u32, num_channels : u16, sample_format : VBanBitResolution) -> Result<Self, VbanError> {
+        if num_channels == 0 || num_channels > FLAC_MAX_CHANNELS {
+            return Err(VbanError::UnsupportedChannelCount(num_channels));
+        }
+        std::fs::create_dir_all(&options.directory)?;
+        let bits_per_sample = match sample_format {
+            VBanBitResolution::VbanBitfmt8Int => 8,
+            VBanBitResolution::VbanBitfmt16Int
+            | VBanBitResolution::VbanBitfmt12Int
+            | VBanBitResolution::VbanBitfmt10Int => 16,
+            _ => 24,
+        };
+        let sink = Self {
+            options : options.clone(),
+            stream_name : String::from(stream_name),
+            source,
+            sample_rate,
+            num_channels,
+            bits_per_sample,
+            state : RefCell::new(FlacState {
+                current : None,
+                pending : Vec::new(),
+                unsynced : 0,
+            }),
+        };
+        sink.state.borrow_mut().current = Some(sink.open()?);
+        Ok(sink)
+    }
+
+    fn open(&self) -> Result<FlacFile, VbanError> {
+        let path = self.options.file_path(&self.stream_name, "flac");
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let comments = [
+            format!("TITLE={}", self.stream_name),
+            format!("DATE={}", Local::now().format("%Y-%m-%d")),
+            format!("VBAN_STREAM_NAME={}", self.stream_name),
+            format!("VBAN_SOURCE={}", self.source),
+            format!("VBAN_SAMPLE_RATE={}", self.sample_rate),
+            format!("VBAN_START_TIME={}", Local::now().to_rfc3339()),
+        ];
+        let mut vorbis = Vec::new();
+        vorbis.extend_from_slice(&(FLAC_VENDOR.len() as u32).to_le_bytes());
+        vorbis.extend_from_slice(FLAC_VENDOR.as_bytes());
+        vorbis.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments.iter() {
+            vorbis.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            vorbis.extend_from_slice(comment.as_bytes());
+        }
+
+        writer.write_all(b"fLaC")?;
+        writer.write_all(&metadata_block_header(FLAC_BLOCK_STREAMINFO, false, FLAC_STREAMINFO_SIZE))?;
+        writer.write_all(&self.streaminfo(0, 0, 0))?;
+        writer.write_all(&metadata_block_header(FLAC_BLOCK_VORBIS_COMMENT, true, vorbis.len() as u32))?;
+        writer.write_all(&vorbis)?;
+        eprintln!("Recording to {}.", path.display());
+        Ok(FlacFile {
+            writer,
+            path,
+            frames : 0,
+            bytes : 4 + 4 + FLAC_STREAMINFO_SIZE as u64 + 4 + vorbis.len() as u64,
+            frame_number : 0,
+            min_frame_size : 0,
+            max_frame_size : 0,
+        })
+    }
+
+    fn streaminfo(&self, total_frames : u64, min_frame_size : u32, max_frame_size : u32) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.write(FLAC_BLOCK_SIZE as u64, 16);
+        bits.write(FLAC_BLOCK_SIZE as u64, 16);
+        bits.write(min_frame_size as u64, 24);
+        bits.write(max_frame_size as u64, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.num_channels as u64 - 1, 3);
+        bits.write(self.bits_per_sample as u64 - 1, 5);
+        bits.write(total_frames, 36);
+        /* An MD5 sum of zero means it was not computed */
+        for _ in 0..4 {
+            bits.write(0, 32);
+        }
+        bits.into_bytes()
+    }
+
+    /// Writes the length and frame sizes into STREAMINFO
+    fn sync(&self, file : &mut FlacFile) -> std::io::Result<()> {
+        file.writer.seek(SeekFrom::Start(8))?;
+        file.writer.write_all(&self.streaminfo(file.frames, file.min_frame_size, file.max_frame_size))?;
+        file.writer.seek(SeekFrom::End(0))?;
+        file.writer.flush()
+    }
+
+    fn close(&self, mut file : FlacFile) {
+        match self.sync(&mut file) {
+            Ok(()) => eprintln!("Closed {} ({:.1} s).", file.path.display(), file.frames as f64 / self.sample_rate as f64),
+            Err(err) => eprintln!("Could not finish {}: {err}", file.path.display()),
+        }
+    }
+
+    /// Encodes the pending samples block by block. With `flush` the rest that does not fill a
+    /// whole block is written as well.
+    fn write_blocks(&self, state : &mut FlacState, flush : bool) -> Result<(), VbanError> {
+        let num_channels = self.num_channels as usize;
+        /* Upper bound of a frame, used to stay below the size limit */
+        let block_bytes = (FLAC_BLOCK_SIZE * num_channels * self.bits_per_sample as usize / 8 + 32) as u64;
+        let mut start = 0;
+        loop {
+            let available = (state.pending.len() - start) / num_channels;
+            if available == 0 || (available < FLAC_BLOCK_SIZE && !flush) {
+                break;
+            }
+            let mut file = match state.current.take() {
+                None => self.open()?,
+                Some(file) => file,
+            };
+
+            let duration_left = self.options.max_duration.map(|max| ((max.as_secs_f64() * self.sample_rate as f64) as u64).saturating_sub(file.frames));
+            let size_reached = self.options.max_size.is_some_and(|max| file.bytes + block_bytes > max);
+            if file.frames > 0 && (duration_left == Some(0) || size_reached) {
+                self.close(file);
+                continue;
+            }
+
+            /* A shorter block ends the file, fixed block size streams only allow that at the end */
+            let count = available.min(FLAC_BLOCK_SIZE).min(duration_left.unwrap_or(u64::MAX).max(1) as usize);
+            let end = start + count * num_channels;
+            let frame = encode_frame(&state.pending[start..end], num_channels, self.bits_per_sample, file.frame_number);
+            start = end;
+
+            let result = file.writer.write_all(&frame);
+            file.frames += count as u64;
+            file.bytes += frame.len() as u64;
+            file.frame_number += 1;
+            file.min_frame_size = if file.min_frame_size == 0 { frame.len() as u32 } else { file.min_frame_size.min(frame.len() as u32) };
+            file.max_frame_size = file.max_frame_size.max(frame.len() as u32);
+            state.unsynced += 1;
+            if count < FLAC_BLOCK_SIZE {
+                self.close(file);
+            } else {
+                if state.unsynced * FLAC_BLOCK_SIZE >= self.sample_rate as usize {
+                    state.unsynced = 0;
+                    self.sync(&mut file)?;
+                }
+                state.current = Some(file);
+            }
+            result?;
+        }
+        state.pending.drain(..start);
+        Ok(())
+    }
+}
+
+impl VbanSink for FlacFileSink {
+
+    fn write(&self, buf : &[f32]) {
+        let max = ((1i64 << (self.bits_per_sample - 1)) - 1) as f64;
+        let mut state = self.state.borrow_mut();
+        state.pending.extend(buf.iter().map(|smp| (smp.clamp(-1.0, 1.0) as f64 * max).round() as i64));
+        if let Err(err) = self.write_blocks(&mut state, false) {
+            /* Start over with a new file on the next write */
+            eprintln!("Recording failed: {err}");
+            state.current = None;
+            state.pending.clear();
+        }
+    }
+
+    fn delay(&self) -> Option<usize> {
+        None
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Writes what is left as a final short block and closes the file
+    fn drain(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Err(err) = self.write_blocks(&mut state, true) {
+            eprintln!("Recording failed: {err}");
+        }
+        if let Some(file) = state.current.take() {
+            self.close(file);
+        }
+        state.pending.clear();
+    }
+}
+
+impl Drop for FlacFileSink {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(write : impl FnOnce(&mut BitWriter)) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        write(&mut bits);
+        bits.into_bytes()
+    }
+
+    #[test]
+    fn crc_check_values() {
+        /* CRC-8 with polynomial 0x07 and CRC-16/UMTS, as FLAC specifies them */
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        assert_eq!(crc8(&[]), 0);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn utf8_numbers() {
+        let utf8 = |value| written(|bits| write_utf8_number(bits, value));
+        assert_eq!(utf8(0), [0x00]);
+        assert_eq!(utf8(0x7F), [0x7F]);
+        assert_eq!(utf8(0x80), [0xC2, 0x80]);
+        assert_eq!(utf8(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(utf8(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(utf8(0xFFFF), [0xEF, 0xBF, 0xBF]);
+        assert_eq!(utf8(0x10000), [0xF0, 0x90, 0x80, 0x80]);
+        assert_eq!(utf8(0x7FFFFFFF), [0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]);
+        assert_eq!(utf8(0xFFFFFFFFF), [0xFE, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]);
+        for value in [0x41, 0xE9, 0x20AC, 0x1F600] {
+            let c = char::from_u32(value as u32).unwrap();
+            assert_eq!(utf8(value), c.to_string().into_bytes());
+        }
+    }
+
+    #[test]
+    fn rice_codes() {
+        /* 5 with k=1 is 001 1, 0 with k=0 is 1, 9 with k=2 is 001 01 */
+        let bytes = written(|bits| {
+            bits.write_rice(5, 1);
+            bits.write_rice(0, 0);
+            bits.write_rice(9, 2);
+        });
+        assert_eq!(bytes, [0b0011_1001, 0b0100_0000]);
+        assert_eq!(written(|bits| bits.write_unary(40)), [0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-3), 5);
+    }
+
+    #[test]
+    fn rice_partitioning() {
+        assert_eq!(rice_partition(&[0; 16]), (0, 16));
+        assert_eq!(rice_partition(&[8; 4]), (3, 20));
+
+        /* Splitting the quiet half from the loud half pays off */
+        let (order, parameters, bits) = rice_partitions(&[0, 0, 0, 0, 100, 100, 100, 100], 8, 0);
+        assert_eq!((order, parameters, bits), (1, vec![0, 6], 50));
+
+        /* Warm up samples are left out of the first partition */
+        let residuals = fixed_residuals(&[3, 5, 7, 9, 11, 13, 15, 17], 2);
+        assert_eq!(residuals, [0; 6]);
+        let (order, parameters, _bits) = rice_partitions(&residuals, 8, 2);
+        assert_eq!(parameters.len(), 1 << order);
+    }
+
+    /// Noise from a linear congruential generator, in [-1.0, 1.0)
+    fn noise(seed : &mut u32) -> f32 {
+        *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (*seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+
+    fn round_trip(name : &str, sample_format : VBanBitResolution, bits_per_sample : u32) {
+        let options = RecordOptions {
+            directory : std::env::temp_dir().join(format!("vban_sink_flac_{}_{name}", std::process::id())),
+            ..Default::default()
+        };
+        /* Three channels: a sine, a constant and noise, more than two blocks in total */
+        let frames = 2 * FLAC_BLOCK_SIZE + 1000;
+        let mut seed = 1;
+        let input : Vec<f32> = (0..frames).flat_map(|n| {
+            [(n as f32 * 0.01).sin() * 0.8, 0.25, noise(&mut seed)]
+        }).collect();
+
+        let sink = FlacFileSink::create(&options, "Test", IpAddr::from([192, 168, 1, 2]), 44100, 3, sample_format).unwrap();
+        for packet in input.chunks(256 * 3) {
+            sink.write(packet);
+        }
+        let path = sink.state.borrow().current.as_ref().unwrap().path.clone();
+        drop(sink);
+
+        let mut reader = claxon::FlacReader::open(&path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (44100, 3, bits_per_sample));
+        assert_eq!(info.samples, Some(frames as u64));
+        assert_eq!(reader.get_tag("VBAN_STREAM_NAME").collect::<Vec<_>>(), ["Test"]);
+        assert_eq!(reader.get_tag("VBAN_SOURCE").collect::<Vec<_>>(), ["192.168.1.2"]);
+        assert_eq!(reader.get_tag("VBAN_SAMPLE_RATE").collect::<Vec<_>>(), ["44100"]);
+        let decoded : Vec<i32> = reader.samples().map(|smp| smp.unwrap()).collect();
+        std::fs::remove_dir_all(&options.directory).unwrap();
+
+        let max = ((1i64 << (bits_per_sample - 1)) - 1) as f64;
+        let expected : Vec<i32> = input.iter().map(|smp| (*smp as f64 * max).round() as i32).collect();
+        assert_eq!(decoded.len(), expected.len());
+        assert!(decoded == expected, "{name}: decoded samples differ");
+    }
+
+    #[test]
+    fn decodes_bit_exact() {
+        round_trip("s8", VBanBitResolution::VbanBitfmt8Int, 8);
+        round_trip("s16", VBanBitResolution::VbanBitfmt16Int, 16);
+        round_trip("s24", VBanBitResolution::VbanBitfmt24Int, 24);
+        round_trip("f32", VBanBitResolution::VbanBitfmt32Float, 24);
+    }
+
+    #[test]
+    fn single_frame_decodes() {
+        /* One short stereo frame of a ramp and silence */
+        let samples : Vec<i64> = (0..64).flat_map(|n| [n * 100 - 3000, 0]).collect();
+        let frame = encode_frame(&samples, 2, 16, 0);
+        assert_eq!(&frame[..2], [0xFF, 0xF8]);
+        assert_eq!(crc16(&frame), 0, "the CRC-16 at the end covers the whole frame");
+
+        let info = {
+            let mut bits = BitWriter::default();
+            bits.write(64, 16);
+            bits.write(64, 16);
+            bits.write(frame.len() as u64, 24);
+            bits.write(frame.len() as u64, 24);
+            bits.write(48000, 20);
+            bits.write(1, 3);
+            bits.write(15, 5);
+            bits.write(64, 36);
+            for _ in 0..4 {
+                bits.write(0, 32);
+            }
+            bits.into_bytes()
+        };
+        let mut stream = b"fLaC".to_vec();
+        stream.extend_from_slice(&metadata_block_header(FLAC_BLOCK_STREAMINFO, true, FLAC_STREAMINFO_SIZE));
+        stream.extend_from_slice(&info);
+        stream.extend_from_slice(&frame);
+
+        let mut reader = claxon::FlacReader::new(std::io::Cursor::new(stream)).unwrap();
+        let decoded : Vec<i64> = reader.samples().map(|smp| smp.unwrap() as i64).collect();
+        assert_eq!(decoded, samples);
+    }
+}
@@ -0,0 +1,215 @@
+/// Level above which the soft limiter starts to compress
+const LIMITER_KNEE : f32 = 0.9;
+
+/// Volume of a stream: an overall gain and a gain per channel in dB, mute and an optional soft
+/// limiter. Changes are ramped over the next block of samples, so adjusting the level while a
+/// stream plays does not click.
+#[derive(Clone, Debug)]
+pub struct Gain {
+    gain_db : f32,
+
+    channel_db : Vec<f32>,
+
+    muted : bool,
+
+    limiter : bool,
+
+    /// Linear factors applied at the end of the last block, one per channel
+    current : Vec<f32>,
+}
+
+impl Gain {
+
+    pub fn new(gain_db : f32, channel_db : &[f32], muted : bool, limiter : bool) -> Self {
+        Self {
+            gain_db,
+            channel_db : channel_db.to_vec(),
+            muted,
+            limiter,
+            current : Vec::new(),
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain_db
+    }
+
+    pub fn set_gain(&mut self, gain_db : f32) {
+        self.gain_db = gain_db;
+    }
+
+    /// Gain of channel `ch` in dB, counted from 0, on top of the overall gain
+    pub fn channel_gain(&self, ch : usize) -> f32 {
+        self.channel_db.get(ch).copied().unwrap_or(0.0)
+    }
+
+    pub fn set_channel_gain(&mut self, ch : usize, gain_db : f32) {
+        if self.channel_db.len() <= ch {
+            self.channel_db.resize(ch + 1, 0.0);
+        }
+        self.channel_db[ch] = gain_db;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_mute(&mut self, muted : bool) {
+        self.muted = muted;
+    }
+
+    pub fn set_limiter(&mut self, limiter : bool) {
+        self.limiter = limiter;
+    }
+
+    /// Linear factor channel `ch` is heading for
+    fn target(&self, ch : usize) -> f32 {
+        match self.muted {
+            true => 0.0,
+            false => 10f32.powf((self.gain_db + self.channel_gain(ch)) / 20.0),
+        }
+    }
+
+    /// Applies the gain to interleaved frames of `num_channels`
+    pub fn process(&mut self, samples : &mut [f32], num_channels : usize) {
+        if self.current.len() != num_channels {
+            /* A new layout starts right at the target level */
+            self.current = (0..num_channels).map(|ch| self.target(ch)).collect();
+        }
+        let frames = samples.len() / num_channels;
+        if frames == 0 {
+            return;
+        }
+
+        for ch in 0..num_channels {
+            let from = self.current[ch];
+            let to = self.target(ch);
+            if from == 1.0 && to == 1.0 {
+                continue;
+            }
+            let step = (to - from) / frames as f32;
+            for (n, smp) in samples.iter_mut().skip(ch).step_by(num_channels).enumerate() {
+                *smp *= from + step * (n + 1) as f32;
+            }
+            self.current[ch] = to;
+        }
+
+        if self.limiter {
+            for smp in samples.iter_mut() {
+                *smp = soft_clip(*smp);
+            }
+        }
+    }
+}
+
+/// Leaves samples below the knee untouched and bends everything above it smoothly towards 1.0
+pub fn soft_clip(smp : f32) -> f32 {
+    let level = smp.abs();
+    if level <= LIMITER_KNEE {
+        return smp;
+    }
+    let headroom = 1.0 - LIMITER_KNEE;
+    smp.signum() * (LIMITER_KNEE + headroom * ((level - LIMITER_KNEE) / headroom).tanh())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a : f32, b : f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn converts_db_to_linear() {
+        for (db, linear) in [(0.0, 1.0), (-6.0, 0.501_187), (6.0, 1.995_262), (-20.0, 0.1), (20.0, 10.0)] {
+            let mut gain = Gain::new(db, &[], false, false);
+            let mut samples = [1.0f32; 4];
+            gain.process(&mut samples, 2);
+            assert!(samples.iter().all(|smp| close(*smp, linear)), "{db} dB gave {samples:?}");
+        }
+    }
+
+    #[test]
+    fn applies_per_channel_gain() {
+        let mut gain = Gain::new(-6.0, &[0.0, 6.0], false, false);
+        assert_eq!(gain.channel_gain(1), 6.0);
+        assert_eq!(gain.channel_gain(2), 0.0);
+
+        let mut samples = [0.5f32; 6];
+        gain.process(&mut samples, 3);
+        for frame in samples.chunks(3) {
+            assert!(close(frame[0], 0.5 * 0.501_187));
+            assert!(close(frame[1], 0.5));
+            assert!(close(frame[2], 0.5 * 0.501_187));
+        }
+
+        gain.set_channel_gain(4, -20.0);
+        assert_eq!(gain.channel_gain(3), 0.0);
+        assert_eq!(gain.channel_gain(4), -20.0);
+    }
+
+    #[test]
+    fn mute_ramps_instead_of_stepping() {
+        let mut gain = Gain::new(0.0, &[], false, false);
+        let mut samples = [1.0f32; 8];
+        gain.process(&mut samples, 1);
+        assert_eq!(samples, [1.0; 8]);
+
+        /* Fades out over the next block */
+        gain.set_mute(true);
+        let mut samples = [1.0f32; 8];
+        gain.process(&mut samples, 1);
+        assert!(samples.windows(2).all(|pair| pair[1] < pair[0]), "{samples:?}");
+        assert!(samples[0] > 0.8);
+        assert_eq!(samples[7], 0.0);
+
+        let mut samples = [1.0f32; 8];
+        gain.process(&mut samples, 1);
+        assert_eq!(samples, [0.0; 8]);
+
+        /* And fades back in */
+        gain.set_mute(false);
+        let mut samples = [1.0f32; 8];
+        gain.process(&mut samples, 1);
+        assert!(samples.windows(2).all(|pair| pair[1] > pair[0]), "{samples:?}");
+        assert!(samples[0] < 0.2);
+        assert_eq!(samples[7], 1.0);
+    }
+
+    #[test]
+    fn starts_muted_without_ramp() {
+        let mut gain = Gain::new(0.0, &[], true, false);
+        let mut samples = [1.0f32; 4];
+        gain.process(&mut samples, 2);
+        assert_eq!(samples, [0.0; 4]);
+    }
+
+    #[test]
+    fn soft_clip_stays_in_range() {
+        for n in -400..=400 {
+            let smp = n as f32 / 100.0;
+            let out = soft_clip(smp);
+            assert!((-1.0..=1.0).contains(&out), "{smp} gave {out}");
+            assert_eq!(out.signum(), smp.signum());
+            if smp.abs() <= LIMITER_KNEE {
+                assert_eq!(out, smp);
+            }
+        }
+        assert!(soft_clip(0.95) > soft_clip(0.92));
+        assert!(soft_clip(100.0) <= 1.0);
+    }
+
+    #[test]
+    fn limiter_is_optional() {
+        let mut gain = Gain::new(6.0, &[], false, false);
+        let mut samples = [0.9f32; 2];
+        gain.process(&mut samples, 1);
+        assert!(samples[0] > 1.0);
+
+        gain.set_limiter(true);
+        let mut samples = [0.9f32; 2];
+        gain.process(&mut samples, 1);
+        assert!(samples.iter().all(|smp| *smp <= 1.0));
+    }
+}
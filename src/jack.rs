@@ -0,0 +1,139 @@
+use std::{cell::RefCell, thread, time::{Duration, Instant}};
+use ::jack::{AsyncClient, AudioOut, Client, ClientOptions, ClosureProcessHandler, Control, PortFlags, PortSpec, ProcessScope, RingBuffer, RingBufferWriter};
+use crate::{error::VbanError, sink::VbanSink};
+
+/// The queue between the sink and the JACK process callback holds this much audio
+const JACK_QUEUE_SECONDS : usize = 2;
+
+/// Longest time `drain` waits for JACK to play out the queue
+const JACK_DRAIN_TIMEOUT : Duration = Duration::from_secs(2);
+
+const JACK_SAMPLE_BYTES : usize = std::mem::size_of::<f32>();
+
+type JackProcessFn = Box<dyn FnMut(&Client, &ProcessScope) -> Control + Send>;
+
+type JackProcess = ClosureProcessHandler<JackProcessFn>;
+
+/// Plays a stream through a JACK client with one output port per channel.
+///
+/// The JACK server dictates the sample rate, `sample_rate` reports it so streams of a different
+/// rate are resampled. Samples are handed to the process callback through a lock free ring
+/// buffer; if it runs dry, the ports play silence.
+pub struct JackSink {
+    client : AsyncClient<(), JackProcess>,
+
+    writer : RefCell<RingBufferWriter>,
+
+    /// Free space of the empty ring buffer, JACK rounds the size up
+    capacity : usize,
+
+    num_channels : usize,
+}
+
+impl JackSink {
+
+    /// Registers a client named after the stream and connects channel n to the n-th entry of
+    /// `connect`. A mono stream is connected to all of them. None connects to the physical
+    /// playback ports.
+    pub fn create(stream_name : &str, connect : Option<&[String]>, num_channels : u16) -> Result<Self, VbanError> {
+        /* Colons separate client and port in JACK names */
+        let client_name = format!("VBAN {}", stream_name.replace(':', "_"));
+        let (client, _status) = Client::new(&client_name, ClientOptions::NO_START_SERVER)?;
+        let num_channels = num_channels as usize;
+
+        let mut ports = Vec::with_capacity(num_channels);
+        for ch in 0..num_channels {
+            ports.push(client.register_port(&format!("out_{}", ch + 1), AudioOut)?);
+        }
+        let port_names : Vec<String> = ports.iter().filter_map(|port| port.name().ok()).collect();
+
+        let frame_bytes = num_channels * JACK_SAMPLE_BYTES;
+        let ring = RingBuffer::new(client.sample_rate() * JACK_QUEUE_SECONDS * frame_bytes)?;
+        let (mut reader, mut writer) = ring.into_reader_writer();
+        let capacity = writer.space();
+
+        /* Sized for the current period, only grows if the server switches to a larger one */
+        let mut cycle = vec![0u8; client.buffer_size() as usize * frame_bytes];
+        let process : JackProcessFn = Box::new(move |_client, scope| {
+            let frames = scope.n_frames() as usize;
+            if cycle.len() < frames * frame_bytes {
+                cycle.resize(frames * frame_bytes, 0);
+            }
+            /* Only whole frames are taken, whatever is missing is played as silence */
+            let available = (reader.space() / frame_bytes).min(frames);
+            reader.read_buffer(&mut cycle[..available * frame_bytes]);
+            for (ch, port) in ports.iter_mut().enumerate() {
+                let output = port.as_mut_slice(scope);
+                for (n, smp) in output.iter_mut().enumerate() {
+                    *smp = match n < available {
+                        true => {
+                            let at = n * frame_bytes + ch * JACK_SAMPLE_BYTES;
+                            f32::from_le_bytes(cycle[at..at + JACK_SAMPLE_BYTES].try_into().unwrap())
+                        },
+                        false => 0.0,
+                    };
+                }
+            }
+            Control::Continue
+        });
+        let client = client.activate_async((), ClosureProcessHandler::new(process))?;
+
+        let targets = match connect {
+            Some(targets) => targets.to_vec(),
+            None => client.as_client().ports(None, Some(AudioOut.jack_port_type()), PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL),
+        };
+        for (ch, target) in targets.iter().enumerate() {
+            let source = match num_channels {
+                1 => port_names.first(),
+                _ => port_names.get(ch),
+            };
+            if let Some(source) = source {
+                if let Err(err) = client.as_client().connect_ports_by_name(source, target) {
+                    eprintln!("Could not connect {source} to {target}: {err}");
+                }
+            }
+        }
+
+        eprintln!("JACK client {} registered with {num_channels} ports at {} Hz.", client.as_client().name(), client.as_client().sample_rate());
+
+        Ok(Self {
+            client,
+            writer : RefCell::new(writer),
+            capacity,
+            num_channels,
+        })
+    }
+
+    /// Bytes in the ring buffer, read from its pointers so it can not race the process callback
+    fn queued(&self) -> usize {
+        self.capacity.saturating_sub(self.writer.borrow_mut().space())
+    }
+}
+
+impl VbanSink for JackSink {
+
+    fn write(&self, buf : &[f32]) {
+        let mut writer = self.writer.borrow_mut();
+        let frame_bytes = self.num_channels * JACK_SAMPLE_BYTES;
+        /* Frames that do not fit are dropped whole, so the channels stay aligned */
+        let frames = (buf.len() / self.num_channels).min(writer.space() / frame_bytes);
+        let bytes : Vec<u8> = buf[..frames * self.num_channels].iter().flat_map(|smp| smp.to_le_bytes()).collect();
+        writer.write_buffer(&bytes);
+    }
+
+    fn delay(&self) -> Option<usize> {
+        let frame_bytes = self.num_channels * JACK_SAMPLE_BYTES;
+        Some(self.queued() / frame_bytes + self.client.as_client().buffer_size() as usize)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.client.as_client().sample_rate() as u32
+    }
+
+    fn drain(&self) {
+        let start = Instant::now();
+        while self.queued() > 0 && start.elapsed() < JACK_DRAIN_TIMEOUT {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
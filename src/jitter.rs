@@ -0,0 +1,364 @@
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+/// Upper bound the target latency may grow to after repeated underruns
+const JITTER_MAX_LATENCY_MS : u32 = 1000;
+
+/// Time without underruns after which the target latency is lowered by one step
+const JITTER_SHRINK_INTERVAL : Duration = Duration::from_secs(60);
+
+/// Snapshot of the fill level and counters of a jitter buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterStatus {
+    /// Audio waiting in the jitter buffer plus the audio queued in the sink
+    pub fill_ms : u32,
+    pub target_ms : u32,
+    pub underruns : u64,
+    /// Packets that arrived after their slot had already been played
+    pub late : u64,
+    /// Missing packets that were given up on
+    pub skipped : u64,
+    /// Packets dropped because the buffer was far above its target
+    pub dropped : u64,
+    /// Packets that were already queued
+    pub duplicates : u64,
+}
+
+impl std::fmt::Display for JitterStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "buffer {}/{} ms, {} underruns, {} late, {} skipped, {} dropped, {} duplicates", self.fill_ms, self.target_ms, self.underruns, self.late, self.skipped, self.dropped, self.duplicates)
+    }
+}
+
+/// Holds received packets ordered by their frame counter until they are due for the sink.
+///
+/// Nothing is released until the buffer plus the audio queued in the sink reaches the target
+/// latency. Afterwards packets are released in order; a missing packet is waited for until
+/// the sink is about to run dry. Every underrun raises the target latency by one step, a
+/// long period without underruns lowers it again down to the configured value.
+pub struct JitterBuffer {
+    sample_rate : u32,
+
+    num_channels : usize,
+
+    packets : BTreeMap<u64, Vec<f32>>,
+
+    /// Frame counter of the next packet to release, extended to 64 bit to survive wrap-around
+    next : Option<u64>,
+
+    /// Number of frames held in `packets`
+    buffered : usize,
+
+    latency_ms : u32,
+
+    target_ms : u32,
+
+    primed : bool,
+
+    /// Frames missing before the packet last returned by `pop`
+    gap : usize,
+
+    last_change : Instant,
+
+    status : JitterStatus,
+}
+
+impl JitterBuffer {
+
+    pub fn new(sample_rate : u32, num_channels : usize, latency_ms : u32) -> Self {
+        Self {
+            sample_rate,
+            num_channels : num_channels.max(1),
+            packets : BTreeMap::new(),
+            next : None,
+            buffered : 0,
+            latency_ms,
+            target_ms : latency_ms,
+            primed : false,
+            gap : 0,
+            last_change : Instant::now(),
+            status : JitterStatus { target_ms : latency_ms, ..Default::default() },
+        }
+    }
+
+    /// Queues a decoded packet. Returns false if the packet was discarded because its slot has
+    /// already been played or it is already queued.
+    pub fn push(&mut self, nu_frame : u32, samples : Vec<f32>) -> bool {
+        let next = *self.next.get_or_insert(nu_frame as u64);
+        let seq = next as i64 + nu_frame.wrapping_sub(next as u32) as i32 as i64;
+
+        if seq < next as i64 {
+            self.status.late += 1;
+            return false;
+        }
+        let seq = seq as u64;
+        if self.packets.contains_key(&seq) {
+            self.status.duplicates += 1;
+            return false;
+        }
+        self.buffered += samples.len() / self.num_channels;
+        self.packets.insert(seq, samples);
+        true
+    }
+
+    /// Returns the next packet that should be written to the sink. `output_delay` is the number
+    /// of frames currently queued in the sink, `None` if the sink does not consume in real time.
+    pub fn pop(&mut self, output_delay : Option<usize>) -> Option<Vec<f32>> {
+        let output_delay = match output_delay {
+            None => return self.pop_unclocked(),
+            Some(delay) => delay,
+        };
+        let target = self.ms_to_frames(self.target_ms);
+
+        if self.primed && output_delay == 0 {
+            self.grow();
+            return None;
+        }
+        if !self.primed {
+            if self.buffered + output_delay < target {
+                return None;
+            }
+            self.primed = true;
+        }
+        self.adapt();
+
+        /* Packets are dropped until the buffer is no more than twice its target */
+        loop {
+            let (&seq, _) = self.packets.first_key_value()?;
+            let next = self.next.unwrap_or(seq);
+
+            let missing = seq.saturating_sub(next);
+            if missing > 0 {
+                /* Wait for the missing packet as long as the sink has enough left to play */
+                let front_len = self.packets[&seq].len() / self.num_channels;
+                if output_delay > front_len && self.buffered < target {
+                    return None;
+                }
+                self.status.skipped += missing;
+            }
+
+            let samples = self.packets.remove(&seq)?;
+            let frames = samples.len() / self.num_channels;
+            self.buffered -= frames;
+            self.next = Some(seq + 1);
+            /* Fill in no more than the sink needs to get back to the target */
+            self.gap = (missing as usize * frames).min(target.saturating_sub(output_delay));
+
+            if output_delay + self.buffered > 2 * target {
+                self.status.dropped += 1;
+                continue;
+            }
+            return Some(samples);
+        }
+    }
+
+    /// Number of frames that went missing right before the packet last returned by `pop`. With
+    /// a clocked sink it is capped at what brings the sink back to the target latency.
+    pub fn gap(&self) -> usize {
+        self.gap
+    }
+
+    /// Releases whatever is queued, in order, e.g. before the sink is closed
+    pub fn drain(&mut self) -> Vec<Vec<f32>> {
+        self.buffered = 0;
+        self.next = None;
+        self.primed = false;
+        std::mem::take(&mut self.packets).into_values().collect()
+    }
+
+    /// Forgets the queued packets and the frame position, e.g. after the sender restarted
+    pub fn reset(&mut self) {
+        self.drain();
+    }
+
+    /// Frames in the buffer plus `output_delay` frames queued in the sink
+    pub fn fill(&self, output_delay : Option<usize>) -> usize {
+        self.buffered + output_delay.unwrap_or(0)
+    }
+
+    /// Current target latency in frames
+    pub fn target(&self) -> usize {
+        self.ms_to_frames(self.target_ms)
+    }
+
+    /// True once the target latency was reached and packets are being released
+    pub fn primed(&self) -> bool {
+        self.primed
+    }
+
+    pub fn status(&self, output_delay : Option<usize>) -> JitterStatus {
+        JitterStatus {
+            fill_ms : self.frames_to_ms(self.fill(output_delay)),
+            target_ms : self.target_ms,
+            ..self.status
+        }
+    }
+
+    fn pop_unclocked(&mut self) -> Option<Vec<f32>> {
+        let (&seq, _) = self.packets.first_key_value()?;
+        /* Without a clock a missing packet is waited for until the buffer reaches the target */
+        if self.next.is_some_and(|next| seq != next) && self.buffered < self.ms_to_frames(self.target_ms) {
+            return None;
+        }
+        let (seq, samples) = self.packets.pop_first()?;
+        let missing = self.next.map_or(0, |next| seq.saturating_sub(next));
+        self.status.skipped += missing;
+        let frames = samples.len() / self.num_channels;
+        self.buffered -= frames;
+        self.next = Some(seq + 1);
+        /* Without a clock the whole gap is filled, so recordings keep their timeline */
+        self.gap = missing as usize * frames;
+        Some(samples)
+    }
+
+    fn grow(&mut self) {
+        self.status.underruns += 1;
+        self.primed = false;
+        self.target_ms = (self.target_ms + self.step_ms()).min(JITTER_MAX_LATENCY_MS.max(self.latency_ms));
+        self.last_change = Instant::now();
+    }
+
+    fn adapt(&mut self) {
+        if self.target_ms > self.latency_ms && self.last_change.elapsed() > JITTER_SHRINK_INTERVAL {
+            self.target_ms = (self.target_ms - self.step_ms()).max(self.latency_ms);
+            self.last_change = Instant::now();
+        }
+    }
+
+    fn step_ms(&self) -> u32 {
+        (self.latency_ms / 4).max(5)
+    }
+
+    fn ms_to_frames(&self, ms : u32) -> usize {
+        (self.sample_rate as u64 * ms as u64 / 1000) as usize
+    }
+
+    fn frames_to_ms(&self, frames : usize) -> u32 {
+        (frames as u64 * 1000 / self.sample_rate.max(1) as u64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* 1 kHz mono with a target of 10 frames, packets of two frames tagged with their number */
+    fn buffer() -> JitterBuffer {
+        JitterBuffer::new(1000, 1, 10)
+    }
+
+    fn packet(tag : u32) -> Vec<f32> {
+        vec![tag as f32; 2]
+    }
+
+    fn tags(jitter : &mut JitterBuffer, output_delay : Option<usize>) -> Vec<f32> {
+        std::iter::from_fn(|| jitter.pop(output_delay)).map(|samples| samples[0]).collect()
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let mut jitter = buffer();
+        for nu_frame in [0, 2, 1, 4, 3] {
+            assert!(jitter.push(nu_frame, packet(nu_frame)));
+        }
+        assert_eq!(tags(&mut jitter, None), [0.0, 1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(jitter.status(None).skipped, 0);
+    }
+
+    #[test]
+    fn waits_for_missing_packet() {
+        let mut jitter = buffer();
+        jitter.push(0, packet(0));
+        jitter.push(2, packet(2));
+        assert_eq!(tags(&mut jitter, None), [0.0]);
+        jitter.push(1, packet(1));
+        assert_eq!(tags(&mut jitter, None), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn survives_frame_counter_wrap() {
+        let mut jitter = buffer();
+        for nu_frame in [u32::MAX - 1, 0, u32::MAX, 1] {
+            assert!(jitter.push(nu_frame, packet(nu_frame % 4)));
+        }
+        /* The tags of u32::MAX - 1 and u32::MAX are 2 and 3 */
+        assert_eq!(tags(&mut jitter, None), [2.0, 3.0, 0.0, 1.0]);
+        assert!(jitter.push(2, packet(2)));
+        assert_eq!(tags(&mut jitter, None), [2.0]);
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut jitter = buffer();
+        assert!(jitter.push(5, packet(5)));
+        assert!(!jitter.push(5, packet(5)));
+        assert_eq!(jitter.status(None).duplicates, 1);
+        assert_eq!(tags(&mut jitter, None), [5.0]);
+    }
+
+    #[test]
+    fn rejects_late_packets() {
+        let mut jitter = buffer();
+        jitter.push(10, packet(10));
+        jitter.push(11, packet(11));
+        assert_eq!(tags(&mut jitter, None), [10.0, 11.0]);
+        assert!(!jitter.push(10, packet(10)));
+        assert!(!jitter.push(3, packet(3)));
+        assert_eq!(jitter.status(None).late, 2);
+        assert!(jitter.pop(None).is_none());
+    }
+
+    #[test]
+    fn fills_to_target_before_release() {
+        let mut jitter = buffer();
+        for nu_frame in 0..4 {
+            jitter.push(nu_frame, packet(nu_frame));
+            assert!(jitter.pop(Some(0)).is_none());
+        }
+        jitter.push(4, packet(4));
+        assert_eq!(jitter.pop(Some(0)).unwrap()[0], 0.0);
+        assert!(jitter.primed());
+    }
+
+    #[test]
+    fn drops_packets_above_twice_the_target() {
+        let mut jitter = buffer();
+        for nu_frame in 0..20 {
+            jitter.push(nu_frame, packet(nu_frame));
+        }
+        /* 40 frames queued: packets go until 5 in the sink plus the rest are at most 20 */
+        assert_eq!(jitter.pop(Some(5)).unwrap()[0], 12.0);
+        assert_eq!(jitter.status(Some(5)).dropped, 12);
+        assert_eq!(jitter.fill(Some(5)), 5 + 14);
+    }
+
+    #[test]
+    fn underrun_raises_target() {
+        let mut jitter = buffer();
+        for nu_frame in 0..5 {
+            jitter.push(nu_frame, packet(nu_frame));
+        }
+        assert!(jitter.pop(Some(0)).is_some());
+        assert!(jitter.pop(Some(0)).is_none());
+        let status = jitter.status(None);
+        assert_eq!(status.underruns, 1);
+        assert_eq!(status.target_ms, 15);
+        assert!(!jitter.primed());
+        /* 8 frames left, the new target of 15 is not reached yet */
+        assert!(jitter.pop(Some(0)).is_none());
+        assert_eq!(jitter.pop(Some(7)).unwrap()[0], 1.0);
+    }
+
+    #[test]
+    fn reports_gap_of_skipped_packets() {
+        let mut jitter = buffer();
+        jitter.push(0, packet(0));
+        jitter.push(3, packet(3));
+        assert_eq!(tags(&mut jitter, None), [0.0]);
+        for nu_frame in 4..8 {
+            jitter.push(nu_frame, packet(nu_frame));
+        }
+        assert_eq!(jitter.pop(None).unwrap()[0], 3.0);
+        assert_eq!(jitter.gap(), 4);
+        assert_eq!(jitter.status(None).skipped, 2);
+    }
+}
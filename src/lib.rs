@@ -1,588 +1,1241 @@
-
+pub mod channels;
+pub mod conceal;
+#[cfg(feature = "alsa")]
+pub mod config;
+pub mod discover;
+pub mod drift;
+pub mod error;
+pub mod flac;
+pub mod gain;
+pub mod jitter;
+#[cfg(feature = "alsa")]
+pub mod mixer;
+pub mod packet;
+pub mod pipe;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+#[cfg(feature = "jack")]
+pub mod jack;
+#[cfg(feature = "pulseaudio")]
+pub mod pulse;
+pub mod record;
+pub mod resample;
+#[cfg(feature = "alsa")]
+pub mod serial;
+pub mod service;
+pub mod sink;
+pub mod text;
+pub mod wav;
+
+#[cfg(feature = "alsa")]
 pub mod vban{
-    use core::panic;
-    use std::{net::{IpAddr, UdpSocket}, str::from_utf8, time::{ Duration, Instant}, usize, process::Command};
+    use std::{io::ErrorKind, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, time::{ Duration, Instant}, process::Command};
     use alsa::{pcm::*, ValueOr};
     use alsa::Direction;
     use byteorder::{ByteOrder, LittleEndian};
+    pub use crate::error::VbanError;
+    pub use crate::sink::VbanSink;
+    use crate::sink::NullSink;
+    use crate::channels::ChannelMap;
+    use crate::conceal::{Concealer, Concealment};
+    use crate::gain::Gain;
+    pub use crate::packet::{AudioFormat, VBanBitResolution, VBanHeader, VBanSampleRates, VbanPacket};
+    use crate::packet::*;
+    use crate::drift::DriftEstimator;
+    use crate::flac::FlacFileSink;
+    use crate::jitter::{JitterBuffer, JitterStatus};
+    use crate::mixer::Mixer;
+    use crate::pipe::{PipeOptions, PipeSink};
+    use crate::record::RecordOptions;
+    use crate::resample::Resampler;
+    use crate::serial::SerialSink;
+    use crate::service::{self, Ping0, VBAN_PINGFEATURE_MIDI, VBAN_PINGFEATURE_SERIAL, VBAN_PINGFEATURE_TXT};
+    use crate::text::{ReceiverCommand, TextHandler, TextMessage};
+    use crate::wav::WavFileSink;
+    use serde::Deserialize;
+
+    /// Receive buffer size, larger than any valid packet so oversized ones can be detected
+    const VBAN_PACKET_MAX_SAMPLES : usize = 1024;
+    const VBAN_PACKET_MAX_LEN_BYTES : usize = VBAN_PACKET_FULL_HEADER_BYTES + VBAN_PACKET_MAX_SAMPLES*2;
 
+    /// Number of frames a packet may be behind the newest one and still be recognized as duplicate
+    const VBAN_FRAME_HISTORY : u32 = 64;
 
-    const VBAN_HEADER_SIZE : usize = 4 + 1 + 1 + 1 + 1 + 16;
-    const VBAN_STREAM_NAME_SIZE : usize = 16;
-    const VBAN_PROTOCOL_MAX_SIZE : usize = 1464;
-    const VBAN_DATA_MAX_SIZE : usize = VBAN_PROTOCOL_MAX_SIZE - VBAN_HEADER_SIZE;
-    const VBAN_CHANNELS_MAX_NB : usize = 256;
-    const VBAN_SAMPLES_MAX_NB : usize = 256;
-
+    /// Frame counter differences above this are treated as a restart of the sender
+    const VBAN_FRAME_RESYNC_LIMIT : u32 = 1000;
 
-    const VBAN_PACKET_NUM_SAMPLES : usize = 256;  
-    const VBAN_PACKET_MAX_SAMPLES : usize = 1024;
-    // const VBAN_PACKET_MAX_SAMPLES : usize = 256;
-    const VBAN_PACKET_HEADER_BYTES : usize = 24;  
-    const VBAN_PACKET_COUNTER_BYTES : usize = 4;  
-    const VBAN_PACKET_MAX_LEN_BYTES : usize = VBAN_PACKET_HEADER_BYTES + VBAN_PACKET_COUNTER_BYTES + VBAN_PACKET_MAX_SAMPLES*2;
-
-
-    struct VBanHeader {
-        preamble : [u8; 4],
-        sample_rate : u8,
-        num_samples : u8,
-
-        // number of channels, where 0 = one channel
-        num_channels : u8,
-        sample_format : u8,
-        stream_name : [u8;16],
-        nu_frame : u32
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct FrameStats {
+        pub received : u64,
+        pub lost : u64,
+        pub duplicated : u64,
+        pub reordered : u64,
     }
 
-    impl From<[u8; 28]> for VBanHeader {
-        fn from (item: [u8; 28]) -> Self {
-
-            // let frame_count : u32 = item[24] as u32 + (item[25] as u32) << 8 + (item[26] as u32) << 16 + (item[27] as u32) << 24;
-            let frame_count  = 0;
-
-            Self {
-                preamble : item[0..4].try_into().unwrap(),
-                sample_rate : item[4],
-                num_samples : item[5],
-                num_channels : item[6],
-                sample_format : item[7],
-                stream_name : [item[8], item[9], item[10], item[11], item[12], item[13], item[14], item[15], item[16], item[17], item[18], item[19], item[20], item[21], item[22], item[23]],
-                nu_frame : frame_count
-            }
+    impl std::fmt::Display for FrameStats {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} received, {} lost, {} duplicated, {} out of order", self.received, self.lost, self.duplicated, self.reordered)
         }
     }
 
-    // VBan struct missing
-
-    const VBAN_SR_MASK : u8 = 0x1F;
-    const VBAN_SR_MAXNUMBER : u8 = 21;
-    const VBAN_SRLIST : [u32; 21] = [
-        6000, 12000, 24000, 48000, 96000, 192000, 384000,
-        8000, 16000, 32000, 64000, 128000, 256000, 512000,
-        11025, 22050, 44100, 88200, 176400, 352800, 705600
-    ];
-
-    #[derive(Copy, Clone, Debug, PartialEq)]
-    pub enum VBanSampleRates {
-        SampleRate6000Hz,
-        SampleRate12000Hz,
-        SampleRate24000Hz,
-        SampleRate48000Hz,
-        SampleRate96000Hz,
-        SampleRate192000Hz,
-        SampleRate384000Hz,
-        SampleRate8000Hz,
-        SampleRate16000Hz,
-        SampleRate32000Hz,
-        SampleRate64000Hz,
-        SampleRate128000Hz,
-        SampleRate256000Hz,
-        SampleRate512000Hz,
-        SampleRate11025Hz,
-        SampleRate22050Hz,
-        SampleRate44100Hz,
-        SampleRate88200Hz,
-        SampleRate176400Hz,
-        SampleRate352800Hz,
-        SampleRate705600Hz
+    #[derive(Debug, PartialEq)]
+    enum FrameOrder {
+        InOrder,
+        Gap(u32),
+        Duplicate,
+        Late,
+        Resync,
     }
 
-    impl std::fmt::Display for VBanSampleRates {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            match self {
-                VBanSampleRates::SampleRate6000Hz => write!(f, "{} Hz", 6000), 
-                VBanSampleRates::SampleRate12000Hz => write!(f, "{} Hz", 12000), 
-                VBanSampleRates::SampleRate24000Hz => write!(f, "{} Hz", 24000), 
-                VBanSampleRates::SampleRate48000Hz => write!(f, "{} Hz", 48000), 
-                VBanSampleRates::SampleRate96000Hz => write!(f, "{} Hz", 96000), 
-                VBanSampleRates::SampleRate192000Hz => write!(f, "{} Hz", 192000), 
-                VBanSampleRates::SampleRate384000Hz => write!(f, "{} Hz", 384000), 
-                VBanSampleRates::SampleRate8000Hz => write!(f, "{} Hz", 8000), 
-                VBanSampleRates::SampleRate16000Hz => write!(f, "{} Hz", 16000), 
-                VBanSampleRates::SampleRate32000Hz => write!(f, "{} Hz", 32000), 
-                VBanSampleRates::SampleRate64000Hz => write!(f, "{} Hz", 64000), 
-                VBanSampleRates::SampleRate128000Hz => write!(f, "{} Hz", 128000), 
-                VBanSampleRates::SampleRate256000Hz => write!(f, "{} Hz", 256000), 
-                VBanSampleRates::SampleRate512000Hz => write!(f, "{} Hz", 512000), 
-                VBanSampleRates::SampleRate11025Hz => write!(f, "{} Hz", 11025), 
-                VBanSampleRates::SampleRate22050Hz => write!(f, "{} Hz", 22050), 
-                VBanSampleRates::SampleRate44100Hz => write!(f, "{} Hz", 44100), 
-                VBanSampleRates::SampleRate88200Hz => write!(f, "{} Hz", 88200), 
-                VBanSampleRates::SampleRate176400Hz => write!(f, "{} Hz", 176400), 
-                VBanSampleRates::SampleRate352800Hz => write!(f, "{} Hz", 352800), 
-                VBanSampleRates::SampleRate705600Hz => write!(f, "{} Hz", 705600),             
+    /// Tracks the frame counter of a stream. Packets behind the newest one are told apart
+    /// from duplicates by a bitmask of the frames received recently (bit n = newest - n).
+    #[derive(Default)]
+    struct FrameCounter {
+        newest : Option<u32>,
+        history : u64,
+        stats : FrameStats,
+    }
+
+    impl FrameCounter {
+
+        fn track(&mut self, nu_frame : u32) -> FrameOrder {
+            let newest = match self.newest {
+                None => {
+                    self.newest = Some(nu_frame);
+                    self.history = 1;
+                    self.stats.received += 1;
+                    return FrameOrder::InOrder;
+                },
+                Some(newest) => newest,
+            };
+
+            let ahead = nu_frame.wrapping_sub(newest);
+            let behind = newest.wrapping_sub(nu_frame);
+
+            if ahead == 0 {
+                self.stats.duplicated += 1;
+                FrameOrder::Duplicate
+            } else if ahead <= VBAN_FRAME_RESYNC_LIMIT {
+                self.history = if ahead < VBAN_FRAME_HISTORY { self.history << ahead | 1 } else { 1 };
+                self.newest = Some(nu_frame);
+                self.stats.received += 1;
+                self.stats.lost += (ahead - 1) as u64;
+                if ahead == 1 { FrameOrder::InOrder } else { FrameOrder::Gap(ahead - 1) }
+            } else if behind < VBAN_FRAME_HISTORY {
+                if self.history & (1 << behind) != 0 {
+                    self.stats.duplicated += 1;
+                    FrameOrder::Duplicate
+                } else {
+                    /* This frame was counted as lost when the newer one arrived */
+                    self.history |= 1 << behind;
+                    self.stats.received += 1;
+                    self.stats.lost = self.stats.lost.saturating_sub(1);
+                    self.stats.reordered += 1;
+                    FrameOrder::Late
+                }
+            } else {
+                self.newest = Some(nu_frame);
+                self.history = 1;
+                self.stats.received += 1;
+                FrameOrder::Resync
             }
         }
-    }
 
-    impl From<u8> for VBanSampleRates {
-
-        fn from(item : u8) -> Self{
-            match item & VBAN_SR_MASK {
-                0 => VBanSampleRates::SampleRate6000Hz,
-                1 => VBanSampleRates::SampleRate12000Hz,
-                2 => VBanSampleRates::SampleRate24000Hz,
-                3 => VBanSampleRates::SampleRate48000Hz,
-                4 => VBanSampleRates::SampleRate96000Hz,
-                5 => VBanSampleRates::SampleRate192000Hz,
-                6 => VBanSampleRates::SampleRate384000Hz,
-                7 => VBanSampleRates::SampleRate8000Hz,
-                8 => VBanSampleRates::SampleRate16000Hz,
-                9 => VBanSampleRates::SampleRate32000Hz,
-                10 => VBanSampleRates::SampleRate64000Hz,
-                11 => VBanSampleRates::SampleRate128000Hz,
-                12 => VBanSampleRates::SampleRate256000Hz,
-                13 => VBanSampleRates::SampleRate512000Hz,
-                14 => VBanSampleRates::SampleRate11025Hz,
-                15 => VBanSampleRates::SampleRate22050Hz,
-                16 => VBanSampleRates::SampleRate44100Hz,
-                17 => VBanSampleRates::SampleRate88200Hz,
-                18 => VBanSampleRates::SampleRate176400Hz,
-                19 => VBanSampleRates::SampleRate352800Hz,
-                20 => VBanSampleRates::SampleRate705600Hz,
-                _ => panic!("Invalid value for enum VBanSampleRates ({:b})", item)
+        fn reset(&mut self) {
+            *self = Self::default();
         }
     }
-}
 
-    const VBAN_PROTOCOL_MASK : u8 = 0xE0;
+    const VBAN_DEFAULT_LATENCY_MS : u32 = 40;
 
-    #[derive(Debug, PartialEq)]
-    enum VBanProtocol {
-        VbanProtocolAudio         =   0x00,
-        VbanProtocolSerial        =   0x20,
-        VbanProtocolTxt           =   0x40,
-        VbanProtocolService      =   0x60,
-        VbanProtocolUndefined1   =   0x80,
-        VbanProtocolUndefined2   =   0xA0,
-        VbanProtocolUndefined3   =   0xC0,
-        VbanProtocolUndefined4   =   0xE0
+    const VBAN_DEFAULT_TIMEOUT : Duration = Duration::from_secs(2);
+
+    #[derive (PartialEq)]
+    enum PlayerState {
+        Idle,
+        Playing,
     }
 
-    impl From<u8> for VBanProtocol {
+    /// Decides which incoming streams are played and where they go. A packet is handled by
+    /// the first route whose stream name and source address match, unset fields match anything.
+    #[derive(Clone, Debug)]
+    pub struct StreamRoute {
+        pub stream_name : Option<String>,
 
-        fn from(value: u8) -> Self {
-            match value & VBAN_PROTOCOL_MASK {
-                0x00 => VBanProtocol::VbanProtocolAudio,
-                0x20 => VBanProtocol::VbanProtocolSerial,
-                0x40 => VBanProtocol::VbanProtocolTxt,
-                0x60 => VBanProtocol::VbanProtocolService,
-                0x80 => VBanProtocol::VbanProtocolUndefined1,
-                0xA0 => VBanProtocol::VbanProtocolUndefined2,
-                0xC0 => VBanProtocol::VbanProtocolUndefined3,
-                0xE0 => VBanProtocol::VbanProtocolUndefined4,
-                _ => panic!("Invalid value for enum VBanProtocol ({:x})", value & VBAN_PROTOCOL_MASK),
-            }
-        }
+        pub source : Option<IpAddr>,
+
+        /// Name of the audio device the stream is played on
+        pub device_name : String,
+
+        /// Device channel that receives the first channel of the stream
+        pub channel_offset : u16,
+
+        /// Number of channels the device is opened with, by default just enough for the stream
+        pub device_channels : Option<u16>,
+
+        /// Selects, reorders or mixes the channels of the stream before they are placed at the offset
+        pub channel_map : Option<ChannelMap>,
+
+        /// Silence prepended when playback starts, in milliseconds
+        pub silence : u32,
+
+        /// Playback stops when no packet arrived for this long
+        pub timeout : Duration,
+
+        /// Target latency of the jitter buffer in milliseconds
+        pub latency : u32,
+
+        pub drift_compensation : bool,
+
+        /// How the audio of packets that never arrived is replaced
+        pub concealment : Concealment,
+
+        /// Rate the output is opened with, streams of other rates are resampled to it. By default
+        /// the output follows the rate of the stream and is reopened when it changes.
+        pub output_rate : Option<u32>,
+
+        /// Gain of the stream in dB
+        pub gain : f32,
+
+        /// Gain per channel in dB on top of `gain`, counted after the channel map. Channels
+        /// that are not listed stay at 0 dB.
+        pub channel_gains : Vec<f32>,
+
+        pub mute : bool,
+
+        /// Soft limit the samples after the gain, so a boosted stream does not clip hard
+        pub limiter : bool,
+
+        pub output : Output,
+
+        /// Where streams are recorded if `output` is a file format
+        pub record : RecordOptions,
+
+        /// Where raw PCM goes if `output` is `Pipe`. Only one stream at a time should write to stdout.
+        pub pipe : PipeOptions,
+
+        /// Ports the JACK output connects its channels to, the physical playback ports if None
+        pub jack_ports : Option<Vec<String>>,
+
+        /// Buffer latency of the PulseAudio output in milliseconds, a default if None
+        pub pulse_latency : Option<u32>,
     }
 
-    const VBAN_BIT_RESOLUTION_MASK : u8 = 0x07;
-
-    #[derive(Clone, Copy, Debug)]
-    enum VBanBitResolution {
-        VbanBitfmt8Int = 0,
-        VbanBitfmt16Int,
-        VbanBitfmt24Int,
-        VbanBitfmt32Int,
-        VbanBitfmt32Float,
-        VbanBitfmt64Float,
-        VbanBitfmt12Int,
-        VbanBitfmt10Int,
-        VbanBitResolutionMax
+    /// What a route does with the streams it receives
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Output {
+        /// Play on the audio device of the route
+        #[default]
+        Alsa,
+
+        /// Record to WAV files
+        Wav,
+
+        /// Record to FLAC files
+        Flac,
+
+        /// Write raw PCM to stdout or a named pipe
+        Pipe,
+
+        /// Discard the audio, only monitor the streams
+        Null,
+
+        /// Play as a node of its own in the PipeWire graph
+        #[cfg(feature = "pipewire")]
+        #[serde(rename = "pipewire")]
+        PipeWire,
+
+        /// Play through a JACK client with one port per channel
+        #[cfg(feature = "jack")]
+        Jack,
+
+        /// Play through the PulseAudio server
+        #[cfg(feature = "pulseaudio")]
+        #[serde(rename = "pulseaudio")]
+        PulseAudio,
     }
 
-    impl From<u8> for VBanBitResolution {
-        fn from(item : u8) -> Self {
-            match  item & VBAN_BIT_RESOLUTION_MASK  {
-                0 => VBanBitResolution::VbanBitfmt8Int,
-                1 => VBanBitResolution::VbanBitfmt16Int,
-                2 => VBanBitResolution::VbanBitfmt24Int,
-                3 => VBanBitResolution::VbanBitfmt32Int,
-                4 => VBanBitResolution::VbanBitfmt32Float,
-                5 => VBanBitResolution::VbanBitfmt64Float,
-                6 => VBanBitResolution::VbanBitfmt12Int,
-                7 => VBanBitResolution::VbanBitfmt10Int,
-                8 => VBanBitResolution::VbanBitResolutionMax,
-                _ => panic!("Invalid value for enum VBanBitResolution ({item})"),
+    impl std::str::FromStr for Output {
+        type Err = String;
+
+        fn from_str(s : &str) -> Result<Self, Self::Err> {
+            match s {
+                "alsa" => Ok(Output::Alsa),
+                "wav" => Ok(Output::Wav),
+                "flac" => Ok(Output::Flac),
+                "pipe" => Ok(Output::Pipe),
+                "null" => Ok(Output::Null),
+                #[cfg(feature = "pipewire")]
+                "pipewire" => Ok(Output::PipeWire),
+                #[cfg(feature = "jack")]
+                "jack" => Ok(Output::Jack),
+                #[cfg(feature = "pulseaudio")]
+                "pulseaudio" => Ok(Output::PulseAudio),
+                _ => Err(format!("unknown output {s} (expected alsa, wav, flac, pipe or null)")),
             }
         }
     }
 
-    const VBAN_BIT_RESOLUTION_SIZE : [u8; 6] = [ 1, 2, 3, 4, 4, 8, ];
+    impl StreamRoute {
 
-    const VBAN_RESERVED_MASK : u8 = 0x08;
-    const VBAN_CODEC_MASK : u8 = 0xF0;
+        pub fn new(device_name : String) -> Self {
+            Self {
+                stream_name : None,
+                source : None,
+                device_name,
+                channel_offset : 0,
+                device_channels : None,
+                channel_map : None,
+                silence : 0,
+                timeout : VBAN_DEFAULT_TIMEOUT,
+                latency : VBAN_DEFAULT_LATENCY_MS,
+                drift_compensation : true,
+                concealment : Concealment::default(),
+                output_rate : None,
+                gain : 0.0,
+                channel_gains : Vec::new(),
+                mute : false,
+                limiter : false,
+                output : Output::Alsa,
+                record : RecordOptions::default(),
+                pipe : PipeOptions::default(),
+                jack_ports : None,
+                pulse_latency : None,
+            }
+        }
 
-    #[derive(Debug, PartialEq)]
-    enum VBanCodec {
-        VbanCodecPcm              =   0x00,
-        VbanCodecVbca             =   0x10,
-        VbanCodecVbcv             =   0x20,
-        VbanCodecUndefined3      =   0x30,
-        VbanCodecUndefined4      =   0x40,
-        VbanCodecUndefined5      =   0x50,
-        VbanCodecUndefined6      =   0x60,
-        VbanCodecUndefined7      =   0x70,
-        VbanCodecUndefined8      =   0x80,
-        VbanCodecUndefined9      =   0x90,
-        VbanCodecUndefined10     =   0xA0,
-        VbanCodecUndefined11     =   0xB0,
-        VbanCodecUndefined12     =   0xC0,
-        VbanCodecUndefined13     =   0xD0,
-        VbanCodecUndefined14     =   0xE0,
-        VbanCodecUser             =   0xF0
-    }
+        /// Where the audio of the route goes, for status messages
+        pub fn target(&self) -> String {
+            match self.output {
+                Output::Alsa => self.device_name.clone(),
+                Output::Wav => format!("WAV files in {}", self.record.directory.display()),
+                Output::Flac => format!("FLAC files in {}", self.record.directory.display()),
+                Output::Pipe => self.pipe.path.as_ref().map(|path| path.display().to_string()).unwrap_or(String::from("stdout")),
+                Output::Null => String::from("nowhere (monitor only)"),
+                #[cfg(feature = "pipewire")]
+                Output::PipeWire => format!("PipeWire (target {})", self.device_name),
+                #[cfg(feature = "jack")]
+                Output::Jack => match &self.jack_ports {
+                    None => String::from("JACK (physical ports)"),
+                    Some(ports) => format!("JACK ({})", ports.join(", ")),
+                },
+                #[cfg(feature = "pulseaudio")]
+                Output::PulseAudio => format!("PulseAudio sink {}", self.device_name),
+            }
+        }
 
-    impl From<u8> for VBanCodec {
-        fn from(value: u8) -> Self {
-            match value & VBAN_CODEC_MASK {
-                0x00 => VBanCodec::VbanCodecPcm,
-                0x10 => VBanCodec::VbanCodecVbca,
-                0x20 => VBanCodec::VbanCodecVbcv,
-                0x30 => VBanCodec::VbanCodecUndefined3,
-                0x40 => VBanCodec::VbanCodecUndefined4,
-                0x50 => VBanCodec::VbanCodecUndefined5,
-                0x60 => VBanCodec::VbanCodecUndefined6,
-                0x70 => VBanCodec::VbanCodecUndefined7,
-                0x80 => VBanCodec::VbanCodecUndefined8,
-                0x90 => VBanCodec::VbanCodecUndefined9,
-                0xA0 => VBanCodec::VbanCodecUndefined10,
-                0xB0 => VBanCodec::VbanCodecUndefined11,
-                0xC0 => VBanCodec::VbanCodecUndefined12,
-                0xD0 => VBanCodec::VbanCodecUndefined13,
-                0xE0 => VBanCodec::VbanCodecUndefined14,
-                0xF0 => VBanCodec::VbanCodecUser,
-                _ => VBanCodec::VbanCodecUser
+        /// Opens the output of the route for the stream `stream_name` from `source`
+        pub fn open_output(&self, stream_name : &str, source : IpAddr, rate : u32, num_channels : u16, sample_format : VBanBitResolution) -> Result<Box<dyn VbanSink>, VbanError> {
+            match self.output {
+                Output::Alsa => Ok(Box::new(AlsaSink::init(&self.device_name, Some(num_channels as u32), Some(rate), Some(sample_format))?)),
+                Output::Wav => Ok(Box::new(WavFileSink::create(&self.record, stream_name, rate, num_channels, sample_format)?)),
+                Output::Flac => Ok(Box::new(FlacFileSink::create(&self.record, stream_name, source, rate, num_channels, sample_format)?)),
+                Output::Pipe => Ok(Box::new(PipeSink::create(&self.pipe, rate, num_channels)?)),
+                Output::Null => Ok(Box::new(NullSink::new(rate))),
+                #[cfg(feature = "pipewire")]
+                Output::PipeWire => {
+                    /* The device name picks the node to connect to, PipeWire chooses for "default" */
+                    let target = Some(self.device_name.as_str()).filter(|name| *name != "default");
+                    Ok(Box::new(crate::pipewire::PipeWireSink::create(stream_name, target, rate, num_channels)?))
+                },
+                #[cfg(feature = "jack")]
+                Output::Jack => Ok(Box::new(crate::jack::JackSink::create(stream_name, self.jack_ports.as_deref(), num_channels)?)),
+                #[cfg(feature = "pulseaudio")]
+                Output::PulseAudio => {
+                    /* "default" leaves the choice of the sink to the server */
+                    let device = Some(self.device_name.as_str()).filter(|name| *name != "default");
+                    let latency = self.pulse_latency.unwrap_or(crate::pulse::PULSE_DEFAULT_LATENCY_MS);
+                    Ok(Box::new(crate::pulse::PulseSink::create(stream_name, device, rate, num_channels, latency)?))
+                },
             }
         }
+
+        fn matches(&self, source : IpAddr, stream_name : &[u8; 16]) -> bool {
+            let name_matches = match &self.stream_name {
+                None => true,
+                Some(name) => stream_name_bytes(name).is_ok_and(|bytes| bytes == *stream_name),
+            };
+            name_matches && self.source.is_none_or(|addr| addr == source)
+        }
     }
 
-    #[derive (PartialEq)]
-    enum PlayerState {
-        Idle,
-        Playing,
+    /// Runs the state change script with the event and the stream name as arguments
+    fn run_command(command : Option<&Command>, event : &str, stream_name : &str) {
+        if let Some(cmd) = command {
+            _ = Command::new(cmd.get_program()).args(cmd.get_args()).arg(event).arg(stream_name).output();
+        }
     }
 
-    pub struct VbanRecipient {
+    /// Playback state of the stream handled by one route
+    pub struct VbanStream {
 
-        socket : UdpSocket,
+        route : StreamRoute,
+
+        /// Sender address and stream name of the stream that is currently played
+        source : Option<(IpAddr, [u8; 16])>,
 
         sample_rate : Option<VBanSampleRates>,
 
-        num_channels : Option<u8>,
+        num_channels : Option<u16>,
 
         sample_format : Option<VBanBitResolution>,
 
-        stream_name : Option<[u8;16]>,
-
-        nu_frame : u32,
+        frames : FrameCounter,
 
         state : PlayerState,
 
         timer : Instant,
 
-        sink : Option<AlsaSink>,
+        sink : Option<Box<dyn VbanSink>>,
 
-        sink_name : String,
+        /// Sink rate divided by stream rate
+        rate_ratio : f64,
 
-        silence : u32,
+        jitter : Option<JitterBuffer>,
 
-        command : Option<Command>,
+        drift : Option<DriftEstimator>,
+
+        resampler : Option<Resampler>,
+
+        concealer : Option<Concealer>,
+
+        /// Volume of the stream, kept across sessions so runtime changes stick
+        gain : Gain,
+
+        /// Added in mix mode for another stream of a busy route, removed when it goes idle
+        spawned : bool,
     }
 
-    impl VbanRecipient {
+    impl VbanStream {
+
+        fn new(route : StreamRoute) -> Self {
+            Self {
+                gain : Gain::new(route.gain, &route.channel_gains, route.mute, route.limiter),
+                route,
+                source : None,
+                sample_rate : None,
+                num_channels : None,
+                sample_format : None,
+                frames : FrameCounter::default(),
+                state : PlayerState::Idle,
+                timer : Instant::now(),
+                sink : None,
+                rate_ratio : 1.0,
+                jitter : None,
+                drift : None,
+                resampler : None,
+                concealer : None,
+                spawned : false,
+            }
+        }
 
-        pub fn create(ip_addr : IpAddr, port: u16, stream_name : Option<String>, numch : Option<u8>, sample_rate : Option<VBanSampleRates>, sink_name : String, silence : Option<u32>) -> Option<Self> {
+        /// A copy of the route and the current volume for one more stream of the route
+        fn spawn(&self) -> Self {
+            let mut stream = Self::new(self.route.clone());
+            stream.gain = self.gain.clone();
+            stream.spawned = true;
+            stream
+        }
 
-            let mut _sn: Option<[u8; 16]> = match stream_name {
-                None => None,
-                Some(name) => {
-                    if name.len() > VBAN_STREAM_NAME_SIZE {
-                        dbg!("Stream name exceeds the limit of {} characters", VBAN_STREAM_NAME_SIZE);
-                        return None;
+        /// True if the stream `stream_name` from `source` is the one that plays
+        fn plays(&self, source : IpAddr, stream_name : &[u8; 16]) -> bool {
+            self.state == PlayerState::Playing && self.source == Some((source, *stream_name))
+        }
+
+        fn play(&mut self, source : IpAddr, packet : &VbanPacket, format : &AudioFormat, command : Option<&Command>, mixer : Option<&Mixer>) -> Result<(), VbanError> {
+            let num_samples = format.num_samples;
+            let sample_format = format.bit_resolution;
+            let sr = format.sample_rate;
+            let num_channels = format.num_channels;
+            let name_incoming = packet.stream_name_str();
+            let nu_frame = packet.nu_frame();
+
+            if self.state == PlayerState::Playing && self.source != Some((source, *packet.stream_name())) {
+                eprintln!("Discarding packet of stream {} from {} because stream {} is already playing on {}.", name_incoming, source, self.name_str(), self.route.device_name);
+                return Ok(());
+            }
+
+            match self.frames.track(nu_frame) {
+                FrameOrder::InOrder => (),
+                FrameOrder::Gap(count) => eprintln!("Lost {count} packet(s) before frame {}.\n", nu_frame),
+                FrameOrder::Duplicate => {
+                    eprintln!("Discarding duplicate of frame {}.\n", nu_frame);
+                    return Ok(());
+                },
+                FrameOrder::Late => eprintln!("Frame {} arrived out of order.\n", nu_frame),
+                FrameOrder::Resync => {
+                    eprintln!("Frame counter jumped to {}, resynchronizing.\n", nu_frame);
+                    if let Some(jitter) = &mut self.jitter {
+                        jitter.reset();
                     }
-                    let mut _sn: [u8; 16] = [0u8; 16];
-                    for (idx, b) in name.bytes().enumerate(){
-                        if idx >= VBAN_STREAM_NAME_SIZE {
-                            break;
+                    if let Some(drift) = &mut self.drift {
+                        drift.reset();
+                    }
+                },
+            }
+
+            let to_sink = sample_format.decode(packet.payload());
+
+            let mut left : f32 = 0.0;
+            let mut right : f32 = 0.0;
+            for frame in to_sink.chunks(num_channels as usize) {
+                left = left.max(frame[0].abs());
+                right = right.max(frame[frame.len().min(2) - 1].abs());
+            }
+
+
+            self.timer = Instant::now();
+            if self.state == PlayerState::Idle {
+                match &self.sink {
+                    Some(_sink) => eprintln!("Something's wrong. Sink is Some() although it should be None"),
+                    None => {
+                        self.sample_rate = Some(sr);
+                        self.num_channels = Some(num_channels);
+                        self.sample_format = Some(sample_format);
+                        /* File names are made from the stream name, so it has to be known first */
+                        self.source = Some((source, *packet.stream_name()));
+                        self.sink = match self.open_sink(mixer, sample_format) {
+                            Ok(sink) => Some(sink),
+                            Err(err) => {
+                                self.source = None;
+                                return Err(err);
+                            },
+                        };
+
+                        self.start_session();
+
+                        eprintln!("Connected to stream {} from {} on {}: \nSR: {} \t Ch: {} \t BPS: {}\n", name_incoming, source, self.route.target(), self.sample_rate(), self.num_channels(), self.bits_per_sample());
+
+                        /* Push silence before the data */
+                        let silence_buf = vec![0f32; (self.sink_rate() / 1000 * self.route.silence) as usize * self.device_channels() as usize];
+                        if let Some(sink) = &mut self.sink {
+                            sink.write(&silence_buf);
                         }
-                        _sn[idx] = b;
                     }
-                    Some(_sn)
                 }
+                run_command(command, "playback_started", &name_incoming);
+                self.state = PlayerState::Playing;
+            } else if let Some((old_sr, old_channels, old_format)) = self.format().filter(|old| *old != (sr, num_channels, sample_format)) {
+                eprintln!("SR: {} -> {}, Ch: {} -> {}, BPS: {} -> {}", old_sr, sr, old_channels, num_channels, old_format.bits(), sample_format.bits());
+                self.flush_jitter();
+                /* An output of fixed rate keeps playing if only the rate of the stream changed */
+                let fixed_rate = self.route.output_rate.is_some() || mixer.is_some();
+                let reopen = !fixed_rate || num_channels != old_channels || sample_format != old_format;
+                self.sample_rate = Some(sr);
+                self.num_channels = Some(num_channels);
+                self.sample_format = Some(sample_format);
+                if reopen {
+                    if let Some(sink) = self.sink.take() {
+                        sink.drain();
+                    }
+                    match self.open_sink(mixer, sample_format) {
+                        Ok(sink) => self.sink = Some(sink),
+                        Err(err) => {
+                            self.stop(command);
+                            return Err(err);
+                        },
+                    }
+                }
+                self.start_session();
+            }
+            let device_channels = self.device_channels() as usize;
+            let route = &self.route;
+            let gain = &mut self.gain;
+            let rate_ratio = self.rate_ratio;
+            let (Some(sink), Some(jitter)) = (self.sink.as_mut(), self.jitter.as_mut()) else {
+                return Ok(());
             };
-            
-            let to_addr = (ip_addr, port);
-            let result  = VbanRecipient{
-                socket :  match UdpSocket::bind(to_addr){
-                    Ok(sock) => sock,
-                    Err(_) => {
-                        dbg!("Could not create socket");
-                        return None;
-                    },
-                },
-                
-                sample_rate : sample_rate,
-                
-                num_channels : numch,
-                
-                sample_format : None,
-                
-                stream_name : _sn,
+            if !jitter.push(nu_frame, to_sink) {
+                eprintln!("Frame {} came too late for the jitter buffer or is already queued, discarding it.\n", nu_frame);
+            }
 
-                nu_frame : 0,
-                
-                state : PlayerState::Idle,
+            /* The jitter buffer counts in frames of the stream, the sink in its own rate */
+            let sink_delay = || sink.delay().map(|frames| (frames as f64 / rate_ratio) as usize);
+            let mut delay = sink_delay();
+            while let Some(samples) = jitter.pop(delay) {
+                let samples = match &mut self.concealer {
+                    None => samples,
+                    Some(concealer) => concealer.process(jitter.gap(), samples),
+                };
+                delay = delay.map(|frames| frames + samples.len() / num_channels as usize);
+                let samples = match &mut self.resampler {
+                    None => samples,
+                    Some(resampler) => resampler.process(&samples),
+                };
+                sink.write(&spread_channels(samples, num_channels as usize, route, gain, device_channels));
+            }
 
-                timer : Instant::now(),
+            /* Steer the playback speed so the fill level stays at the target */
+            let mut ppm = 0.0;
+            if let (Some(drift), Some(resampler)) = (&mut self.drift, &mut self.resampler) {
+                if jitter.primed() {
+                    let fill = jitter.fill(sink_delay()) + resampler.latency();
+                    resampler.set_ratio(self.rate_ratio * drift.update(fill, jitter.target()));
+                } else {
+                    drift.reset();
+                }
+                ppm = drift.ppm();
+            }
 
-                sink : None,
+            let status = jitter.status(sink_delay());
+            eprintln!("\x1B[1A{}: Left {:.4}, Right {:.4} (from {num_samples} samples), buffer {}/{} ms, drift {:+.0} ppm", name_incoming, left, right, status.fill_ms, status.target_ms, ppm);
+            Ok(())
+        }
+
+        /// Stops playback if no packet arrived within the timeout of the route
+        fn check_timeout(&mut self, command : Option<&Command>) {
+            if self.state == PlayerState::Playing && self.timer.elapsed() > self.route.timeout {
+                self.stop(command);
+            }
+        }
+
+        /// Plays what is left in the buffers, closes the sink and goes back to idle
+        fn stop(&mut self, command : Option<&Command>){
+            self.state = PlayerState::Idle;
+           
+            self.flush_jitter();
+            /* The sink is gone already if reopening it failed */
+            if let Some(sink) = self.sink.take() {
+                sink.drain();
+            }
+            self.jitter = None;
+            self.drift = None;
+            self.resampler = None;
+            self.concealer = None;
+            run_command(command, "playback_stopped", &self.name_str());
+            eprintln!("{} idle (packets: {})", self.name_str(), self.frames.stats);
+            self.frames.reset();
+            self.source = None;
+        }
 
-                sink_name,
+        /// Writes what is left in the jitter buffer to the sink and drops the buffer
+        fn flush_jitter(&mut self) {
+            let num_channels = self.num_channels() as usize;
+            let device_channels = self.device_channels() as usize;
+            if let (Some(sink), Some(jitter)) = (&self.sink, &mut self.jitter) {
+                for samples in jitter.drain() {
+                    let samples = match &mut self.concealer {
+                        None => samples,
+                        Some(concealer) => concealer.process(0, samples),
+                    };
+                    let samples = match &mut self.resampler {
+                        None => samples,
+                        Some(resampler) => resampler.process(&samples),
+                    };
+                    sink.write(&spread_channels(samples, num_channels, &self.route, &mut self.gain, device_channels));
+                }
+                eprintln!("Jitter buffer: {}", jitter.status(None));
+            }
+            self.jitter = None;
+        }
+
+        /// Sets up the buffers of a playback session for the current stream parameters
+        fn start_session(&mut self){
+            let num_channels = self.num_channels() as usize;
+            self.drift = None;
+            self.resampler = None;
+            self.jitter = Some(JitterBuffer::new(self.sample_rate(), num_channels, self.route.latency));
+            self.concealer = Some(Concealer::new(self.route.concealment, self.sample_rate(), num_channels));
+            self.rate_ratio = self.sink_rate() as f64 / self.sample_rate() as f64;
+            /* Sinks without a clock of their own, like files, have no drift to follow */
+            let clocked = self.sink.as_ref().is_some_and(|sink| sink.delay().is_some());
+            if self.route.drift_compensation && clocked {
+                self.drift = Some(DriftEstimator::new(self.sample_rate()));
+            }
+            if self.drift.is_some() || self.sink_rate() != self.sample_rate() {
+                self.resampler = Some(Resampler::new(num_channels, self.rate_ratio));
+            }
+        }
 
-                silence : match silence {
-                    None => 0,
-                    Some(val) => val,
+        /// Opens the output of the route, or an input of the mixer if streams are mixed
+        fn open_sink(&self, mixer : Option<&Mixer>, sample_format : VBanBitResolution) -> Result<Box<dyn VbanSink>, VbanError> {
+            match mixer {
+                Some(mixer) => Ok(Box::new(mixer.input(self.device_channels() as usize)?)),
+                None => {
+                    let rate = self.route.output_rate.unwrap_or(self.sample_rate());
+                    let source = self.source().unwrap_or(IpAddr::from([0, 0, 0, 0]));
+                    self.route.open_output(&self.name_str(), source, rate, self.device_channels(), sample_format)
                 },
+            }
+        }
+
+        /// Volume of the stream, changes apply to the audio that is played next
+        pub fn gain_mut(&mut self) -> &mut Gain {
+            &mut self.gain
+        }
+
+        // GETTER
+        pub fn route(&self) -> &StreamRoute {
+            &self.route
+        }
+
+        pub fn gain(&self) -> &Gain {
+            &self.gain
+        }
+
+        pub fn is_playing(&self) -> bool {
+            self.state == PlayerState::Playing
+        }
+
+        /// Address of the sender of the stream that is currently played
+        pub fn source(&self) -> Option<IpAddr> {
+            self.source.map(|(addr, _name)| addr)
+        }
+
+        pub fn frame_stats(&self) -> FrameStats {
+            self.frames.stats
+        }
+
+        pub fn jitter_status(&self) -> Option<JitterStatus> {
+            let delay = self.sink.as_ref().and_then(|sink| sink.delay()).map(|frames| (frames as f64 / self.rate_ratio) as usize);
+            self.jitter.as_ref().map(|jitter| jitter.status(delay))
+        }
+
+        pub fn name(&self) -> Option<[u8;16]>{
+            self.source.map(|(_addr, name)| name)
+        }
+ 
+        /// True if `name` is the stream that is played or the stream the route accepts. None
+        /// matches every stream.
+        fn is_named(&self, name : Option<&str>) -> bool {
+            name.is_none_or(|name| self.name_str() == name || self.route.stream_name.as_deref() == Some(name))
+        }
+
+        /// Name of the stream that is currently played, empty when idle
+        pub fn name_str(&self) -> String{
+            match &self.source {
+                None => String::from(""),
+                Some((_addr, name)) => String::from(stream_name_str(name))
+            }
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate.map_or(0, |sr| sr.hz())
+        }
+
+        /// Rate, channels and sample format of the stream, None while idle
+        fn format(&self) -> Option<(VBanSampleRates, u16, VBanBitResolution)> {
+            Some((self.sample_rate?, self.num_channels?, self.sample_format?))
+        }
+
+        /// Rate of the sink, the stream rate while no sink is open
+        fn sink_rate(&self) -> u32 {
+            match &self.sink {
+                None => self.sample_rate(),
+                Some(sink) => sink.sample_rate(),
+            }
+        }
+
+        fn bits_per_sample(&self) -> u8 {
+            self.sample_format.map_or(0, |format| format.bits())
+        }
+
+        fn num_channels(&self) -> u16 {
+            self.num_channels.unwrap_or(0)
+        }
+
+        fn device_channels(&self) -> u16 {
+            let mapped = self.route.channel_map.as_ref().map_or(self.num_channels(), |map| map.num_outputs() as u16);
+            match self.route.device_channels {
+                None => self.route.channel_offset + mapped,
+                Some(channels) => channels,
+            }
+        }
+    }
+
+    /// Applies the channel map of the route and the gain to interleaved frames of `num_channels`
+    /// and places the result into frames of `device_channels`, starting at the channel offset of
+    /// the route. Channels outside the device are dropped, the others are silent.
+    fn spread_channels(samples : Vec<f32>, num_channels : usize, route : &StreamRoute, gain : &mut Gain, device_channels : usize) -> Vec<f32> {
+        let (mut samples, num_channels) = match &route.channel_map {
+            None => (samples, num_channels),
+            Some(map) => (map.apply(&samples, num_channels), map.num_outputs()),
+        };
+        gain.process(&mut samples, num_channels);
+        let offset = route.channel_offset as usize;
+        if offset == 0 && num_channels == device_channels {
+            return samples;
+        }
+        let mut out = vec![0f32; samples.len() / num_channels * device_channels];
+        for (frame, out_frame) in samples.chunks_exact(num_channels).zip(out.chunks_exact_mut(device_channels)) {
+            for (ch, smp) in frame.iter().enumerate() {
+                if let Some(out_smp) = out_frame.get_mut(offset + ch) {
+                    *out_smp = *smp;
+                }
+            }
+        }
+        out
+    }
+
+    pub struct VbanRecipient {
+
+        socket : UdpSocket,
+
+        streams : Vec<VbanStream>,
+
+        command : Option<Command>,
+
+        /// Streams are summed into this mixer instead of opening a device per route
+        mixer : Option<Mixer>,
+
+        /// Receives text packets, they are discarded if None
+        text_handler : Option<Box<dyn TextHandler>>,
+
+        /// Only text packets of this stream are handled, any if None
+        text_stream : Option<[u8; 16]>,
+
+        /// Receives the payload of MIDI packets, they are discarded if None
+        midi_sink : Option<Box<dyn SerialSink>>,
+
+        /// Receives the payload of generic serial packets, they are discarded if None
+        serial_sink : Option<Box<dyn SerialSink>>,
+
+        /// Only serial packets of this stream are handled, any if None
+        serial_stream : Option<[u8; 16]>,
+
+        /// Identification sent in reply to PING0 requests, they are not answered if None
+        ping : Option<Ping0>,
+    }
+
+    impl VbanRecipient {
+
+        /// Creates a recipient listening on `ip_addr` and `port` that plays streams as `routes`
+        /// say. `set_routes` replaces them later.
+        pub fn create(ip_addr : IpAddr, port: u16, routes : Vec<StreamRoute>) -> Result<Self, VbanError> {
+            let to_addr = (ip_addr, port);
+            let mut result  = VbanRecipient{
+                socket : UdpSocket::bind(to_addr)?,
+
+                streams : Vec::new(),
 
                 command : None,
+
+                mixer : None,
+
+                text_handler : None,
+
+                text_stream : None,
+
+                midi_sink : None,
+
+                serial_sink : None,
+
+                serial_stream : None,
+
+                ping : None,
             };
 
-            result.socket.set_read_timeout(Some(Duration::new(1, 0))).expect("Could not set timeout of socket");
+            result.set_routes(routes)?;
 
-            println!("VBAN recepipient ready. Waiting for incoming audio packets...");
-            Some(result)
+            eprintln!("VBAN recepipient ready. Waiting for incoming audio packets...");
+            Ok(result)
         }
         
 
-        pub fn handle(&mut self){
+        /// Receives one packet and plays it on the stream whose route matches. Waits at most
+        /// one second for it to arrive. Packets that are malformed or can't be played are
+        /// reported as error, the recipient stays usable afterwards.
+        pub fn handle(&mut self) -> Result<(), VbanError> {
             let mut buf :[u8; VBAN_PACKET_MAX_LEN_BYTES] = [0; VBAN_PACKET_MAX_LEN_BYTES];
             let packet = self.socket.recv_from(&mut buf);
             // let buf = Vec::from(buf);
 
-            if self.state == PlayerState::Playing && self.timer.elapsed().as_secs() > 2 {
-                self.state = PlayerState::Idle;
-               
-                match &self.sink{
-                    None => println!("Something's wrong. Expected to find a pcm but it is unitialized."),
-                    Some(sink) => {
-                        match sink.pcm.drain(){
-                            Err(errno) => println!("Error while draining pcm: {errno}"),
-                            Ok(()) => (),
-                        }
-                        match sink.pcm.drop(){
-                            Err(errno) => println!("Error while closing pcm: {errno}"),
-                            Ok(()) => (),
-                        }
-                        self.sink = None;
-                    }
-                }
-                match &mut self.command {
-                    None => (),
-                    Some(cmd) => _ = cmd.arg("playback_stopped").output(),
-                }
-                println!("idle");
+            for stream in self.streams.iter_mut() {
+                stream.check_timeout(self.command.as_ref());
             }
+            self.streams.retain(|stream| !stream.spawned || stream.is_playing());
 
-            let size = match packet {
-                Ok((size, _addr)) => {
-                    size
+            let (size, addr) = match packet {
+                Ok((size, addr)) => {
+                    (size, addr)
                 },
-                _ => return,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(()),
+                Err(err) => return Err(err.into()),
             };
 
-            if buf[..4] == *b"VBAN" {
-
-                let head : [u8; 28] = buf[0..28].try_into().unwrap();
-                let head = VBanHeader::from(head);
-
-                self.sample_format = Some(head.sample_format.into());
-                
-                let num_samples = head.num_samples + 1;
-                let bits_per_sample = VBAN_BIT_RESOLUTION_SIZE[self.sample_format.unwrap() as usize];
-                let codec = VBanCodec::from(head.sample_format);
-                let protocol = VBanProtocol::from(head.sample_rate);
-                let name_incoming : &str = from_utf8(&head.stream_name).unwrap();
-                
-                // println!("DEBUG: bps={bits_per_sample}, codec={:?}", codec);
-                
-                if protocol != VBanProtocol::VbanProtocolAudio {
-                    println!("Discarding packet with protocol {:?} because it is not supported.", protocol);
-                    return;
-                }
-                if codec != VBanCodec::VbanCodecPcm {
-                    println!("Any codecs other than PCM are not supported (found {:?}).", codec);
-                    return;
-                }
-                if bits_per_sample != 2{
-                    println!("Bitwidth other than 16 bits not supported (found {}).", bits_per_sample * 8);
-                    return;
-                }
-                
-                let sr : VBanSampleRates  = head.sample_rate.into();
-                self.num_channels = Some(head.num_channels + 1);
-
-                match self.stream_name {
-                    None => (),
-                    Some(name) => {
-                        if from_utf8(&name).unwrap() != name_incoming {
-                            println!("Discarding packet because stream names don't match.");
-                            return;
-                        }
-                    }
-                }
+            let packet = VbanPacket::parse(&buf[..size])?;
+            /* Senders like Voicemeeter mix text, MIDI and service packets into the audio, the
+             * ones nothing is set up for are skipped quietly */
+            match packet.protocol() {
+                VBanProtocol::VbanProtocolAudio => (),
+                VBanProtocol::VbanProtocolTxt => return self.handle_text(addr.ip(), &packet),
+                VBanProtocol::VbanProtocolSerial => return self.handle_serial(&packet),
+                VBanProtocol::VbanProtocolService => return self.handle_service(addr, &packet),
+                _ => return Ok(()),
+            }
+            let format = packet.audio_format()?;
 
-                let audio_data : Vec<u8> = Vec::from(&buf[VBAN_PACKET_HEADER_BYTES + VBAN_PACKET_COUNTER_BYTES..size]);
-                let mut to_sink = vec![0; audio_data.len() / bits_per_sample as usize];
+            match self.stream_for(addr.ip(), packet.stream_name()).map(|index| &mut self.streams[index]) {
+                None => {
+                    eprintln!("Discarding packet of stream {} from {} because no route matches.", packet.stream_name_str(), addr.ip());
+                    Ok(())
+                },
+                Some(stream) => stream.play(addr.ip(), &packet, &format, self.command.as_ref(), self.mixer.as_ref()),
+            }
+        }
 
-                let mut left : i16 = 0;
-                let mut right : i16 = 0;
-                for (idx, _smp) in audio_data.iter().enumerate() {
-                    if idx % 2 == 1 {
-                        continue;
-                    }
+        /// Index of the stream a packet goes to. Without a mixer it is the first route that matches. When
+        /// mixing, every stream gets a mixer input of its own: a packet goes to the stream already
+        /// playing it, else to an idle route that matches, else to a copy of the first matching
+        /// route.
+        fn stream_for(&mut self, source : IpAddr, stream_name : &[u8; 16]) -> Option<usize> {
+            let matching = |stream : &VbanStream| stream.route.matches(source, stream_name);
+            match self.mixer {
+                None => self.streams.iter().position(matching),
+                Some(_) => match self.streams.iter().position(|stream| stream.plays(source, stream_name))
+                    .or_else(|| self.streams.iter().position(|stream| !stream.is_playing() && matching(stream))) {
+                    Some(index) => Some(index),
+                    None => {
+                        let route = self.streams.iter().position(matching)?;
+                        let stream = self.streams[route].spawn();
+                        self.streams.push(stream);
+                        Some(self.streams.len() - 1)
+                    },
+                },
+            }
+        }
 
-                    let amplitude_le = LittleEndian::read_i16(&audio_data[idx..idx+2]);
 
-                    if idx % 4 == 0 {
-                        if amplitude_le > left {
-                            left = amplitude_le;
-                        }
-                    } else {
-                        if amplitude_le > right {
-                            right = amplitude_le;
-                        }
-                    }
+        /// Passes a text packet to the text handler and carries out the commands it returns
+        fn handle_text(&mut self, source : IpAddr, packet : &VbanPacket) -> Result<(), VbanError> {
+            if self.text_handler.is_none() || self.text_stream.is_some_and(|name| name != *packet.stream_name()) {
+                return Ok(());
+            }
+            let message = TextMessage {
+                source,
+                stream_name : packet.stream_name_str().into_owned(),
+                text : packet.text()?,
+            };
+            let commands = match &mut self.text_handler {
+                None => return Ok(()),
+                Some(handler) => handler.handle(&message),
+            };
+            for command in commands.iter() {
+                self.execute(command);
+            }
+            Ok(())
+        }
 
-                    to_sink[idx / 2] = amplitude_le;
-                }
+        /// Passes the payload of a serial packet to the sink for its type
+        fn handle_serial(&mut self, packet : &VbanPacket) -> Result<(), VbanError> {
+            if (self.midi_sink.is_none() && self.serial_sink.is_none()) || self.serial_stream.is_some_and(|name| name != *packet.stream_name()) {
+                return Ok(());
+            }
+            let sink = match packet.serial_format()?.kind {
+                VBanSerialType::Midi => &mut self.midi_sink,
+                VBanSerialType::Generic | VBanSerialType::User => &mut self.serial_sink,
+            };
+            if let Some(sink) = sink {
+                sink.write(packet.payload());
+            }
+            Ok(())
+        }
 
+        /// Answers PING0 requests, other service packets are ignored
+        fn handle_service(&mut self, addr : SocketAddr, packet : &VbanPacket) -> Result<(), VbanError> {
+            let Some(template) = &self.ping else {
+                return Ok(());
+            };
+            if !service::is_ping_request(packet) {
+                return Ok(());
+            }
+            let mut info = template.clone();
+            /* Features follow what the recipient is set up for right now */
+            if self.text_handler.is_some() {
+                info.features |= VBAN_PINGFEATURE_TXT;
+            }
+            if self.midi_sink.is_some() {
+                info.features |= VBAN_PINGFEATURE_MIDI;
+            }
+            if self.serial_sink.is_some() {
+                info.features |= VBAN_PINGFEATURE_SERIAL;
+            }
+            if let Some(source) = self.streams.iter().find_map(|stream| stream.source()) {
+                info.distant_ip = source.to_string();
+            }
+            self.socket.send_to(&service::ping_reply(packet, &info), addr)?;
+            Ok(())
+        }
 
-                self.timer = Instant::now();
-                if self.state == PlayerState::Idle {
-                    match &self.sink {
-                        Some(_sink) => println!("Something's wrong. Sink is Some() although it should be None"),
-                        None => {
-                            self.sample_rate = Some(sr);
-                            self.sink = match AlsaSink::init(&self.sink_name, Some(self.num_channels() as u32), Some(self.sample_rate())){
-                                None => {
-                                    println!("Could not grab audio device");
-                                    return
-                                },
-                                Some(sink) => Some(sink)
-                            };
-
-                            println!("Connected to stream {}: \nSR: {} \t Ch: {} \t BPS: {}\n", name_incoming, self.sample_rate(), self.num_channels(), self.bits_per_sample());
-
-                            /* Push silence before the data */
-                            let silence_buf = vec![0i16; (self.sample_rate() / 1000 * self.silence) as usize];
-                            self.sink.as_mut().unwrap().write(&silence_buf);
+        /// Carries out a command, e.g. one received as text
+        pub fn execute(&mut self, command : &ReceiverCommand) {
+            match command {
+                ReceiverCommand::Gain(name, db) => {
+                    for stream in self.streams.iter_mut().filter(|stream| stream.is_named(name.as_deref())) {
+                        stream.gain.set_gain(*db);
+                    }
+                    eprintln!("Gain of {} set to {db} dB.", name.as_deref().unwrap_or("all streams"));
+                },
+                ReceiverCommand::Mute(name, state) => {
+                    for stream in self.streams.iter_mut().filter(|stream| stream.is_named(name.as_deref())) {
+                        let muted = state.unwrap_or(!stream.gain.is_muted());
+                        stream.gain.set_mute(muted);
+                        /* Toggling can leave the streams in different states */
+                        if state.is_none() {
+                            let playing = Some(stream.name_str()).filter(|name| !name.is_empty());
+                            eprintln!("Stream {} {}.", playing.as_deref().or(stream.route.stream_name.as_deref()).unwrap_or("*"), if muted { "muted" } else { "unmuted" });
                         }
                     }
-                    match &mut self.command {
-                        None => (),
-                        Some(cmd) => _ = cmd.arg("playback_started").output(),
+                    if let Some(muted) = state {
+                        eprintln!("{} {}.", name.as_deref().unwrap_or("All streams"), if *muted { "muted" } else { "unmuted" });
                     }
-                    self.state = PlayerState::Playing;
-                } else {
-                    if sr != self.sample_rate.unwrap(){
-                        println!("SR: {} -> {}", self.sample_rate.unwrap(), sr);
-                        self.sample_rate = Some(sr);
-                        let sink = self.sink.as_mut().unwrap();
-                        sink.pcm.drain();
-                        self.sink = Some(AlsaSink::init(&self.sink_name, Some(self.num_channels() as u32), Some(self.sample_rate())).expect("Could not create audio device with the required specs."));
+                },
+                ReceiverCommand::Select(name) => {
+                    if let Some(Err(err)) = name.as_deref().map(stream_name_bytes) {
+                        eprintln!("Can not select stream: {err}");
+                        return;
                     }
-                }
-                let sink = self.sink.as_mut().unwrap();
-                sink.write(&to_sink);
-                println!("\x1B[1ALeft {:.4}, Right {:.4} (from {num_samples} samples)", (left as f32 / i16::MAX as f32), (right as f32 / i16::MAX as f32));
-            } else{
-                println!("Packet is not VBAN");
+                    if self.streams.iter().filter(|stream| !stream.spawned).count() > 1 {
+                        eprintln!("Select only changes the first route, the other routes keep their streams.");
+                    }
+                    let command = self.command.as_ref();
+                    let Some(stream) = self.streams.first_mut() else {
+                        return;
+                    };
+                    if stream.route.stream_name == *name {
+                        return;
+                    }
+                    /* The stream that plays now is not accepted any more */
+                    if stream.is_playing() && name.as_deref().is_some_and(|name| stream.name_str() != name) {
+                        stream.stop(command);
+                    }
+                    stream.route.stream_name = name.clone();
+                    eprintln!("Selected stream {}.", name.as_deref().unwrap_or("*"));
+                },
             }
         }
 
-
         // SETTER
         pub fn set_command(&mut self, cmd : Command){
             self.command = Some(cmd);
         }
 
-        // GETTER
-        fn name(&self) -> Option<[u8;16]>{
-            self.stream_name
+        /// Replaces the routes. Streams that are playing are stopped first.
+        pub fn set_routes(&mut self, routes : Vec<StreamRoute>) -> Result<(), VbanError> {
+            for route in routes.iter() {
+                if let Some(name) = &route.stream_name {
+                    stream_name_bytes(name)?;
+                }
+            }
+            for stream in self.streams.iter_mut().filter(|stream| stream.is_playing()) {
+                stream.stop(self.command.as_ref());
+            }
+            self.streams = routes.into_iter().map(VbanStream::new).collect();
+
+            /* Wake up often enough to notice the shortest timeout */
+            let wakeup = self.streams.iter().map(|stream| stream.route.timeout).min().unwrap_or(VBAN_DEFAULT_TIMEOUT);
+            self.socket.set_read_timeout(Some(wakeup.clamp(Duration::from_millis(10), Duration::new(1, 0))))?;
+            Ok(())
         }
- 
-        fn name_str(&self) -> String{
-            match &self.stream_name {
-                None => String::from(""),
-                Some(name) => String::from(from_utf8(name).unwrap())
+
+        /// Hands text packets of `stream_name`, or of every stream if None, to `handler`
+        pub fn set_text_handler(&mut self, handler : Box<dyn TextHandler>, stream_name : Option<&str>) -> Result<(), VbanError> {
+            self.text_stream = stream_name.map(stream_name_bytes).transpose()?;
+            self.text_handler = Some(handler);
+            Ok(())
+        }
+
+        /// Sends the MIDI and the generic data of serial packets of `stream_name`, or of every
+        /// stream if None, to these sinks. Packets of a type without sink are discarded.
+        pub fn set_serial_sinks(&mut self, midi : Option<Box<dyn SerialSink>>, serial : Option<Box<dyn SerialSink>>, stream_name : Option<&str>) -> Result<(), VbanError> {
+            self.serial_stream = stream_name.map(stream_name_bytes).transpose()?;
+            self.midi_sink = midi;
+            self.serial_sink = serial;
+            Ok(())
+        }
+
+        /// Answers PING0 requests with `info`, so the recipient shows up in the VBAN dialog of
+        /// Voicemeeter. None stops answering.
+        pub fn set_ping_reply(&mut self, info : Option<Ping0>) {
+            self.ping = info;
+        }
+
+        /// Mixes all streams into `mixer` instead of playing each route on its own device.
+        /// Streams that are playing are stopped first.
+        pub fn set_mixer(&mut self, mixer : Mixer){
+            for stream in self.streams.iter_mut().filter(|stream| stream.is_playing()) {
+                stream.stop(self.command.as_ref());
             }
+            self.mixer = Some(mixer);
         }
 
-        fn sample_rate(&self) -> u32 {
-            VBAN_SRLIST[self.sample_rate.unwrap() as usize]
+        /// Target latency of the jitter buffer for all routes, applies from the next start of playback
+        pub fn set_latency(&mut self, ms : u32){
+            for stream in self.streams.iter_mut() {
+                stream.route.latency = ms;
+            }
         }
 
-        fn bits_per_sample(&self) -> u8 {
-            self.sample_format.unwrap() as u8 + 1
+        /// Resample the streams of all routes to follow the clock drift between sender and sound card
+        pub fn set_drift_compensation(&mut self, enable : bool){
+            for stream in self.streams.iter_mut() {
+                stream.route.drift_compensation = enable;
+            }
+        }
+
+        // GETTER
+        pub fn streams(&self) -> &[VbanStream] {
+            &self.streams
         }
 
-        fn num_channels(&self) -> u8 {
-            self.num_channels.unwrap() as u8
+        /// Routes in the order they were set, e.g. to change their volume while they play
+        pub fn streams_mut(&mut self) -> &mut [VbanStream] {
+            &mut self.streams
         }
+    }
+
+    /// Samples per packet are limited by the 8 bit sample count of the header
+    const VBAN_EMITTER_MAX_SAMPLES : usize = 256;
 
 
+    /// Packetizes audio into VBAN audio packets and sends them to a unicast, broadcast or
+    /// multicast destination.
+    pub struct VbanEmitter {
+
+        socket : UdpSocket,
+
+        destination : SocketAddr,
+
+        stream_name : [u8; 16],
+
+        format : AudioFormat,
+
+        nu_frame : u32,
+
+        /// Interleaved samples that do not fill a whole packet yet
+        pending : Vec<f32>,
     }
 
+    impl VbanEmitter {
+
+        /// Creates an emitter for a stream. `samples_per_packet` is reduced if packets of that
+        /// size would exceed the protocol limit. Fails if not even one sample per channel fits.
+        pub fn create(destination : SocketAddr, stream_name : &str, sample_rate : VBanSampleRates, num_channels : u16, sample_format : VBanBitResolution, samples_per_packet : usize) -> Result<Self, VbanError> {
+            let stream_name = stream_name_bytes(stream_name)?;
+            if num_channels as usize > VBAN_CHANNELS_MAX_NB {
+                return Err(VbanError::TooManyChannels(num_channels));
+            }
+            if num_channels == 0 || sample_format.payload_size(num_channels as usize) > VBAN_DATA_MAX_SIZE {
+                return Err(VbanError::UnsupportedChannelCount(num_channels));
+            }
+
+            let mut samples_per_packet = samples_per_packet.clamp(1, VBAN_EMITTER_MAX_SAMPLES);
+            while samples_per_packet > 1 && sample_format.payload_size(samples_per_packet * num_channels as usize) > VBAN_DATA_MAX_SIZE {
+                samples_per_packet -= 1;
+            }
+
+            let local : IpAddr = match destination {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let socket = UdpSocket::bind((local, 0))?;
+            if destination.is_ipv4() {
+                socket.set_broadcast(true)?;
+            }
+
+            Ok(Self {
+                socket,
+                destination,
+                stream_name,
+                format : AudioFormat {
+                    sample_rate,
+                    num_samples : samples_per_packet as u16,
+                    num_channels,
+                    bit_resolution : sample_format,
+                },
+                nu_frame : 0,
+                pending : Vec::new(),
+            })
+        }
+
+        /// Queues interleaved samples and sends every packet that is complete
+        pub fn send(&mut self, samples : &[f32]) -> Result<(), VbanError> {
+            self.pending.extend_from_slice(samples);
+            let packet_len = self.samples_per_packet() * self.format.num_channels as usize;
+            let mut sent = 0;
+            while self.pending.len() - sent >= packet_len {
+                let result = self.send_packet(sent, packet_len);
+                sent += packet_len;
+                if let Err(err) = result {
+                    self.pending.drain(..sent);
+                    return Err(err);
+                }
+            }
+            self.pending.drain(..sent);
+            Ok(())
+        }
+
+        fn send_packet(&mut self, start : usize, len : usize) -> Result<(), VbanError> {
+            let head = VBanHeader::audio(self.stream_name, &self.format, self.nu_frame);
+            let packet = head.to_packet(&self.format.bit_resolution.encode(&self.pending[start..start + len]));
+
+            self.nu_frame = self.nu_frame.wrapping_add(1);
+            self.socket.send_to(&packet, self.destination)?;
+            Ok(())
+        }
 
-    pub trait VbanSink {
-        fn write(&self, buf : &[i16]);
+        // GETTER
+        pub fn samples_per_packet(&self) -> usize {
+            self.format.num_samples as usize
+        }
+
+        pub fn destination(&self) -> SocketAddr {
+            self.destination
+        }
     }
 
+
     // ALSA SINK
 
     pub struct AlsaSink {
         pcm : PCM,
+        format : Format,
+        rate : u32,
     }
 
     impl AlsaSink {
 
-        pub fn init(device : &str, num_channels : Option<u32>, sample_rate : Option<u32>) -> Option<Self> {
+        pub fn init(device : &str, num_channels : Option<u32>, sample_rate : Option<u32>, sample_format : Option<VBanBitResolution>) -> Result<Self, VbanError> {
 
-            let sink = Self {
-                pcm : {
-                    PCM::new(device, Direction::Playback, false).expect("Could not create PCM.")
-                },
-            };
+            let pcm = PCM::new(device, Direction::Playback, false)?;
 
-            let num_channels = match num_channels {
-                None => {2},
-                Some(ch) => ch,
-            };
-            let rate = match sample_rate {
-                None => 44100,
-                Some(r) => r,
-            };
+            let num_channels = num_channels.unwrap_or(2);
+            let rate = sample_rate.unwrap_or(44100);
+            let sample_format = sample_format.unwrap_or(VBanBitResolution::VbanBitfmt16Int);
 
-            {
-                let hwp = HwParams::any(&sink.pcm).expect("Could not get hwp.");
+            let format = {
+                let hwp = HwParams::any(&pcm)?;
 
-                hwp.set_channels(num_channels).expect("Could not set channel number.");
-                hwp.set_rate(rate, ValueOr::Nearest).expect("Could not set sample rate.");
-                hwp.set_format(Format::s16()).expect("Could not set sample format.");
-                hwp.set_access(Access::RWInterleaved).expect("Could not set access.");
-                sink.pcm.hw_params(&hwp).expect("Could not attach hwp to PCM.");
-            }
+                hwp.set_channels(num_channels)?;
+                hwp.set_rate(rate, ValueOr::Nearest)?;
+
+                /* Use the first format the device accepts, samples are converted on write */
+                let format = match alsa_formats(sample_format).iter().find(|fmt| hwp.test_format(**fmt).is_ok()) {
+                    None => return Err(VbanError::UnsupportedDeviceFormat(sample_format.bits())),
+                    Some(fmt) => *fmt,
+                };
+                hwp.set_format(format)?;
+                hwp.set_access(Access::RWInterleaved)?;
+                pcm.hw_params(&hwp)?;
+                format
+            };
+            let rate = pcm.hw_params_current().and_then(|hwp| hwp.get_rate())?;
+
+            let sink = Self {
+                pcm,
+                format,
+                rate,
+            };
 
             match sink.pcm.start(){
                 Ok(()) => (),
                 Err(errno) => {
-                    println!("Error: {errno}");
-                    sink.pcm.drain().expect("Drain failed");
+                    eprintln!("Error: {errno}");
+                    sink.pcm.drain()?;
                     match sink.pcm.recover(errno.errno(), true){
                         Ok(()) => (),
-                        Err(errno) => println!("Recovering after failed start failed too."),
+                        Err(_errno) => eprintln!("Recovering after failed start failed too."),
                     }
                 },
             }
@@ -592,59 +1245,479 @@ pub mod vban{
 
             // {
             //     let params = sink.pcm.hw_params_current().unwrap();
-            //     println!("(Debug) HwParams: {:?}", params);
+            //     eprintln!("(Debug) HwParams: {:?}", params);
             //     let sr = params.get_rate().unwrap();
             //     let nch = params.get_channels().unwrap();
             //     let fmt = params.get_format().unwrap();
             //     let bsize = params.get_buffer_size().unwrap();
             //     let psize = params.get_period_size().unwrap();
                 
-            //     println!("Created playback device with sr={sr}, channels={nch}, format={fmt}, period size={psize} and buffer size={bsize}.\n");
+            //     eprintln!("Created playback device with sr={sr}, channels={nch}, format={fmt}, period size={psize} and buffer size={bsize}.\n");
             // }
 
             {
-                let swp = sink.pcm.sw_params_current().unwrap();
+                let swp = sink.pcm.sw_params_current()?;
                 match swp.set_start_threshold(512) {
                     Ok(()) => (),
-                    Err(errno) => println!("Could not set start_threshold sw parameter (error {errno})."),
+                    Err(errno) => eprintln!("Could not set start_threshold sw parameter (error {errno})."),
                 }
 
-                let thr = swp.get_start_threshold().unwrap();
+                let thr = swp.get_start_threshold()?;
                 // todo? set silence threshold?
-                println!("Start threshold is {thr}.");
+                eprintln!("Start threshold is {thr}.");
             }
-            Some(sink)
+            Ok(sink)
+        }
+
+        /// Converts samples to the byte layout of the format the device was opened with
+        fn encode(&self, buf : &[f32]) -> Vec<u8> {
+            alsa_encode(self.format, buf)
         }
     
     }
 
+    /// ALSA formats that can carry a resolution, best match first
+    fn alsa_formats(resolution : VBanBitResolution) -> &'static [Format] {
+        match resolution {
+            VBanBitResolution::VbanBitfmt8Int => &[Format::S8, Format::S16LE, Format::S32LE, Format::FloatLE],
+            VBanBitResolution::VbanBitfmt16Int
+            | VBanBitResolution::VbanBitfmt12Int
+            | VBanBitResolution::VbanBitfmt10Int => &[Format::S16LE, Format::S32LE, Format::FloatLE],
+            VBanBitResolution::VbanBitfmt24Int => &[Format::S243LE, Format::S24LE, Format::S32LE, Format::FloatLE, Format::S16LE],
+            VBanBitResolution::VbanBitfmt32Int => &[Format::S32LE, Format::FloatLE, Format::S243LE, Format::S24LE, Format::S16LE],
+            VBanBitResolution::VbanBitfmt32Float => &[Format::FloatLE, Format::S32LE, Format::S243LE, Format::S24LE, Format::S16LE],
+            VBanBitResolution::VbanBitfmt64Float => &[Format::Float64LE, Format::FloatLE, Format::S32LE, Format::S243LE, Format::S24LE, Format::S16LE],
+            VBanBitResolution::VbanBitResolutionMax => &[Format::S16LE],
+        }
+    }
+
+    /// Size in bytes of a sample of one of the formats the sinks and sources open devices with
+    fn alsa_sample_width(format : Format) -> usize {
+        match format {
+            Format::S8 => 1,
+            Format::S16LE => 2,
+            Format::S243LE => 3,
+            Format::Float64LE => 8,
+            _ => 4,
+        }
+    }
+
+    fn alsa_encode(format : Format, buf : &[f32]) -> Vec<u8> {
+        let width = alsa_sample_width(format);
+        let mut bytes = vec![0u8; buf.len() * width];
+
+        for (smp, out) in buf.iter().zip(bytes.chunks_exact_mut(width)) {
+            let smp = smp.clamp(-1.0, 1.0) as f64;
+            match format {
+                Format::S8 => out[0] = (smp * i8::MAX as f64) as i8 as u8,
+                Format::S16LE => LittleEndian::write_i16(out, (smp * i16::MAX as f64) as i16),
+                Format::S243LE => LittleEndian::write_i24(out, (smp * 8388607.0) as i32),
+                Format::S24LE => LittleEndian::write_i32(out, (smp * 8388607.0) as i32),
+                Format::S32LE => LittleEndian::write_i32(out, (smp * i32::MAX as f64) as i32),
+                Format::Float64LE => LittleEndian::write_f64(out, smp),
+                _ => LittleEndian::write_f32(out, smp as f32),
+            }
+        }
+        bytes
+    }
+
+    fn alsa_decode(format : Format, bytes : &[u8]) -> Vec<f32> {
+        bytes.chunks_exact(alsa_sample_width(format)).map(|smp| match format {
+            Format::S8 => smp[0] as i8 as f32 / 128.0,
+            Format::S16LE => LittleEndian::read_i16(smp) as f32 / 32768.0,
+            Format::S243LE => LittleEndian::read_i24(smp) as f32 / 8388608.0,
+            Format::S24LE => sign_extend(LittleEndian::read_u32(smp), 24) as f32 / 8388608.0,
+            Format::S32LE => (LittleEndian::read_i32(smp) as f64 / 2147483648.0) as f32,
+            Format::Float64LE => LittleEndian::read_f64(smp) as f32,
+            _ => LittleEndian::read_f32(smp),
+        }).collect()
+    }
+
     impl VbanSink for AlsaSink {
 
-        fn write(&self, buf : &[i16]){
-            let io = self.pcm.io_i16().unwrap();
+        fn write(&self, buf : &[f32]){
+            let io = self.pcm.io_bytes();
+            let buf = self.encode(buf);
+            let buf = buf.as_slice();
 
             match io.writei(buf){
                 Err(errno) => {
                     // Maybe try to investigate the pcm device here and try to reopen it (because broken pipe)
 
-                    println!("Write did not work. Error: {errno}");
+                    eprintln!("Write did not work. Error: {errno}");
                     // let state = self.pcm.state();
 
                     match self.pcm.recover(errno.errno(), true){
                         Ok(()) => {
-                            println!("Was able to recover from error");
+                            eprintln!("Was able to recover from error");
                             match io.writei(buf){
                                 Ok(_) => (),
-                                Err(errno) => println!("Second attempt to write buffer failed ({errno})."),
+                                Err(errno) => eprintln!("Second attempt to write buffer failed ({errno})."),
                             }
                         },
-                        Err(errno2) => println!("Could not recover from error (errno2={errno2}"),
+                        Err(errno2) => eprintln!("Could not recover from error (errno2={errno2}"),
                     }
                 },
                 Ok(_size) => (),
             }
 
         }
+
+        fn delay(&self) -> Option<usize> {
+            match self.pcm.delay() {
+                Ok(frames) => Some(frames.max(0) as usize),
+                Err(_) => Some(0),
+            }
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.rate
+        }
+
+        fn drain(&self) {
+            if let Err(errno) = self.pcm.drain() {
+                eprintln!("Error while draining pcm: {errno}");
+            }
+        }
+    }
+
+    // ALSA SOURCE
+
+    /// Captures audio from an ALSA device as interleaved samples in the range [-1.0, 1.0]
+    pub struct AlsaSource {
+        pcm : PCM,
+        format : Format,
+        rate : u32,
+        num_channels : u32,
+    }
+
+    impl AlsaSource {
+
+        pub fn init(device : &str, num_channels : u32, sample_rate : u32, sample_format : VBanBitResolution) -> Result<Self, VbanError> {
+
+            let pcm = PCM::new(device, Direction::Capture, false)?;
+
+            let format = {
+                let hwp = HwParams::any(&pcm)?;
+
+                hwp.set_channels(num_channels)?;
+                hwp.set_rate(sample_rate, ValueOr::Nearest)?;
+
+                let format = match alsa_formats(sample_format).iter().find(|fmt| hwp.test_format(**fmt).is_ok()) {
+                    None => return Err(VbanError::UnsupportedDeviceFormat(sample_format.bits())),
+                    Some(fmt) => *fmt,
+                };
+                hwp.set_format(format)?;
+                hwp.set_access(Access::RWInterleaved)?;
+                pcm.hw_params(&hwp)?;
+                format
+            };
+            let rate = pcm.hw_params_current().and_then(|hwp| hwp.get_rate())?;
+            pcm.start()?;
+
+            Ok(Self {
+                pcm,
+                format,
+                rate,
+                num_channels,
+            })
+        }
+
+        /// Blocks until `frames` frames are captured. Overruns are recovered from and reported.
+        pub fn read(&self, frames : usize) -> Result<Vec<f32>, VbanError> {
+            let io = self.pcm.io_bytes();
+            let frame_bytes = alsa_sample_width(self.format) * self.num_channels as usize;
+            let mut buf = vec![0u8; frames * frame_bytes];
+            let mut filled = 0;
+            while filled < frames {
+                match io.readi(&mut buf[filled * frame_bytes..]) {
+                    Ok(count) => filled += count,
+                    Err(errno) => {
+                        eprintln!("Capture overrun ({errno}), recovering.");
+                        self.pcm.recover(errno.errno(), true)?;
+                    },
+                }
+            }
+            Ok(alsa_decode(self.format, &buf))
+        }
+
+        /// Rate the device actually captures at
+        pub fn sample_rate(&self) -> u32 {
+            self.rate
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::{cell::RefCell, rc::Rc};
+
+        /// Keeps everything written to it
+        struct CaptureSink {
+            samples : Rc<RefCell<Vec<f32>>>,
+        }
+
+        impl VbanSink for CaptureSink {
+            fn write(&self, buf : &[f32]) {
+                self.samples.borrow_mut().extend_from_slice(buf);
+            }
+
+            fn delay(&self) -> Option<usize> {
+                None
+            }
+
+            fn sample_rate(&self) -> u32 {
+                48000
+            }
+        }
+
+        #[test]
+        fn frame_counter_classifies_packets() {
+            let mut counter = FrameCounter::default();
+            assert_eq!(counter.track(0), FrameOrder::InOrder);
+            assert_eq!(counter.track(1), FrameOrder::InOrder);
+            assert_eq!(counter.track(3), FrameOrder::Gap(1));
+            assert_eq!(counter.track(3), FrameOrder::Duplicate);
+            assert_eq!(counter.track(2), FrameOrder::Late);
+            assert_eq!(counter.track(2), FrameOrder::Duplicate);
+            assert_eq!(counter.track(1), FrameOrder::Duplicate);
+            assert_eq!(counter.stats, FrameStats { received : 4, lost : 0, duplicated : 3, reordered : 1 });
+
+            assert_eq!(counter.track(8), FrameOrder::Gap(4));
+            assert_eq!(counter.track(5), FrameOrder::Late);
+            assert_eq!(counter.stats, FrameStats { received : 6, lost : 3, duplicated : 3, reordered : 2 });
+        }
+
+        #[test]
+        fn frame_counter_wraps() {
+            let mut counter = FrameCounter::default();
+            assert_eq!(counter.track(u32::MAX - 1), FrameOrder::InOrder);
+            assert_eq!(counter.track(u32::MAX), FrameOrder::InOrder);
+            assert_eq!(counter.track(0), FrameOrder::InOrder);
+            assert_eq!(counter.track(2), FrameOrder::Gap(1));
+            assert_eq!(counter.track(1), FrameOrder::Late);
+            assert_eq!(counter.track(u32::MAX), FrameOrder::Duplicate);
+            assert_eq!(counter.stats, FrameStats { received : 5, lost : 0, duplicated : 1, reordered : 1 });
+        }
+
+        #[test]
+        fn frame_counter_resyncs_on_jumps() {
+            let mut counter = FrameCounter::default();
+            counter.track(5);
+            /* Up to the limit ahead it is a gap, beyond it the sender restarted */
+            assert_eq!(counter.track(5 + VBAN_FRAME_RESYNC_LIMIT), FrameOrder::Gap(VBAN_FRAME_RESYNC_LIMIT - 1));
+            assert_eq!(counter.track(100_000), FrameOrder::Resync);
+            assert_eq!(counter.track(100_001), FrameOrder::InOrder);
+            /* A restart from 0 is too far behind to be late */
+            assert_eq!(counter.track(0), FrameOrder::Resync);
+            assert_eq!(counter.track(1), FrameOrder::InOrder);
+            /* Behind but older than the history is not a duplicate either */
+            counter.track(200);
+            assert_eq!(counter.track(200 - VBAN_FRAME_HISTORY), FrameOrder::Resync);
+            assert_eq!(counter.stats.lost, (VBAN_FRAME_RESYNC_LIMIT - 1 + 198) as u64);
+
+            counter.reset();
+            assert_eq!(counter.track(7), FrameOrder::InOrder);
+            assert_eq!(counter.stats, FrameStats { received : 1, ..Default::default() });
+        }
+
+        fn emitter_to_local_socket(num_channels : u16, sample_format : VBanBitResolution, samples_per_packet : usize) -> (VbanEmitter, UdpSocket) {
+            let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let emitter = VbanEmitter::create(receiver.local_addr().unwrap(), "Stream1", VBanSampleRates::SampleRate48000Hz, num_channels, sample_format, samples_per_packet).unwrap();
+            (emitter, receiver)
+        }
+
+        #[test]
+        fn emitter_sends_whole_packets() {
+            let (mut emitter, receiver) = emitter_to_local_socket(2, VBanBitResolution::VbanBitfmt16Int, 64);
+            assert_eq!(emitter.samples_per_packet(), 64);
+            let samples : Vec<f32> = (0..400).map(|n| (n as f32 / 400.0) - 0.5).collect();
+            /* 200 frames in uneven parts give three packets, the rest waits */
+            emitter.send(&samples[..50]).unwrap();
+            emitter.send(&samples[50..300]).unwrap();
+            emitter.send(&samples[300..]).unwrap();
+
+            let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE];
+            for nu_frame in 0..3 {
+                let size = receiver.recv(&mut buf).unwrap();
+                let packet = VbanPacket::parse(&buf[..size]).unwrap();
+                assert_eq!(packet.protocol(), VBanProtocol::VbanProtocolAudio);
+                assert_eq!(packet.stream_name_str(), "Stream1");
+                assert_eq!(packet.nu_frame(), nu_frame);
+                assert_eq!(packet.audio_format().unwrap(), AudioFormat {
+                    sample_rate : VBanSampleRates::SampleRate48000Hz,
+                    num_samples : 64,
+                    num_channels : 2,
+                    bit_resolution : VBanBitResolution::VbanBitfmt16Int,
+                });
+                let start = nu_frame as usize * 128;
+                for (smp, sent) in packet.audio_format().unwrap().bit_resolution.decode(packet.payload()).iter().zip(&samples[start..start + 128]) {
+                    assert!((smp - sent).abs() < 1.0 / 16384.0, "{smp} != {sent}");
+                }
+            }
+            receiver.set_nonblocking(true).unwrap();
+            assert!(receiver.recv(&mut buf).is_err());
+        }
+
+        #[test]
+        fn emitter_fits_packets_to_the_protocol_limit() {
+            for (num_channels, sample_format, samples) in [
+                (8, VBanBitResolution::VbanBitfmt24Int, 59),
+                (8, VBanBitResolution::VbanBitfmt64Float, 22),
+                (2, VBanBitResolution::VbanBitfmt16Int, 256),
+                (256, VBanBitResolution::VbanBitfmt8Int, 5),
+            ] {
+                let (mut emitter, receiver) = emitter_to_local_socket(num_channels, sample_format, 1000);
+                assert_eq!(emitter.samples_per_packet(), samples, "{num_channels} channels of {sample_format:?}");
+                emitter.send(&vec![0.5; samples * num_channels as usize]).unwrap();
+                let mut buf = [0u8; VBAN_PROTOCOL_MAX_SIZE + 1];
+                let size = receiver.recv(&mut buf).unwrap();
+                assert!(size <= VBAN_PROTOCOL_MAX_SIZE);
+                assert_eq!(VbanPacket::parse(&buf[..size]).unwrap().audio_format().unwrap().num_samples as usize, samples);
+            }
+        }
+
+        #[test]
+        fn emitter_rejects_channel_counts() {
+            let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 6980));
+            let create = |num_channels, sample_format| VbanEmitter::create(destination, "Stream1", VBanSampleRates::SampleRate48000Hz, num_channels, sample_format, 256);
+            assert!(matches!(create(0, VBanBitResolution::VbanBitfmt16Int), Err(VbanError::UnsupportedChannelCount(0))));
+            assert!(matches!(create(257, VBanBitResolution::VbanBitfmt16Int), Err(VbanError::TooManyChannels(257))));
+            /* One frame of 256 channels at 64 bit is larger than a packet */
+            assert!(matches!(create(256, VBanBitResolution::VbanBitfmt64Float), Err(VbanError::UnsupportedChannelCount(256))));
+        }
+
+        #[test]
+        fn packets_without_handler_are_skipped() {
+            let mut recipient = VbanRecipient::create(IpAddr::from([127, 0, 0, 1]), 0, vec![StreamRoute::new(String::from("default"))]).unwrap();
+            let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let destination = recipient.socket.local_addr().unwrap();
+            let name = stream_name_bytes("Command1").unwrap();
+
+            let text = VBanHeader::text(name, VBanTextFormat::Utf8, 0).to_packet(b"mute");
+            let mut undefined = text.clone();
+            undefined[4] = VBanProtocol::VbanProtocolUndefined1 as u8;
+            let serial = VBanHeader::serial(name, &SerialFormat {
+                bps : 31250,
+                stop_bits : 0,
+                start_bit : true,
+                parity : false,
+                multipart : false,
+                channel : 0,
+                kind : VBanSerialType::Midi,
+            }, 0).to_packet(&[0x90, 0x3C, 0x7F]);
+            for packet in [text, serial, undefined] {
+                sender.send_to(&packet, destination).unwrap();
+                recipient.handle().unwrap();
+            }
+            assert!(recipient.streams().iter().all(|stream| !stream.is_playing()));
+        }
+
+        fn named_route(name : &str) -> StreamRoute {
+            let mut route = StreamRoute::new(String::from("default"));
+            route.stream_name = Some(String::from(name));
+            route
+        }
+
+        #[test]
+        fn mute_commands_reach_every_matching_route() {
+            let mut recipient = VbanRecipient::create(IpAddr::from([127, 0, 0, 1]), 0, vec![named_route("Stream1"), named_route("Stream2")]).unwrap();
+            let muted = |recipient : &VbanRecipient| recipient.streams().iter().map(|stream| stream.gain.is_muted()).collect::<Vec<_>>();
+
+            recipient.execute(&ReceiverCommand::Mute(None, Some(true)));
+            assert_eq!(muted(&recipient), [true, true]);
+            recipient.execute(&ReceiverCommand::Mute(Some(String::from("Stream2")), Some(false)));
+            assert_eq!(muted(&recipient), [true, false]);
+            recipient.execute(&ReceiverCommand::Mute(None, None));
+            assert_eq!(muted(&recipient), [false, true]);
+        }
+
+        #[test]
+        fn mix_sums_streams_of_one_route() {
+            let mixed = Rc::new(RefCell::new(Vec::new()));
+            let output = mixed.clone();
+            let mut recipient = VbanRecipient::create(IpAddr::from([127, 0, 0, 1]), 0, vec![StreamRoute::new(String::from("default"))]).unwrap();
+            recipient.set_mixer(Mixer::with_output(48000, 2, move || Ok(Box::new(CaptureSink { samples : output.clone() }))));
+
+            let format = AudioFormat {
+                sample_rate : VBanSampleRates::SampleRate48000Hz,
+                num_samples : 64,
+                num_channels : 2,
+                bit_resolution : VBanBitResolution::VbanBitfmt32Float,
+            };
+            let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let destination = recipient.socket.local_addr().unwrap();
+            for nu_frame in 0..20 {
+                for (name, level) in [("Stream1", 0.25), ("Stream2", 0.125)] {
+                    let head = VBanHeader::audio(stream_name_bytes(name).unwrap(), &format, nu_frame);
+                    sender.send_to(&head.to_packet(&format.bit_resolution.encode(&[level; 128])), destination).unwrap();
+                }
+            }
+            for _ in 0..40 {
+                recipient.handle().unwrap();
+            }
+
+            assert_eq!(recipient.streams().iter().filter(|stream| stream.is_playing()).count(), 2);
+            let mixed = mixed.borrow();
+            /* Only the first packet of Stream1 is mixed before Stream2 started */
+            let summed = mixed.iter().filter(|smp| **smp == 0.375).count();
+            assert!(summed >= 18 * 128, "{summed} of {} samples are the sum", mixed.len());
+            assert!(mixed.iter().all(|smp| *smp == 0.375 || *smp == 0.25), "{mixed:?}");
+        }
+
+        #[test]
+        fn fixed_output_rate_resamples_instead_of_reopening() {
+            let mut route = StreamRoute::new(String::from("default"));
+            route.output = Output::Wav;
+            route.record.directory = std::env::temp_dir().join(format!("vban_sink_rate_{}", std::process::id()));
+            route.output_rate = Some(48000);
+            route.silence = 0;
+            let directory = route.record.directory.clone();
+            let mut recipient = VbanRecipient::create(IpAddr::from([127, 0, 0, 1]), 0, vec![route]).unwrap();
+            let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let destination = recipient.socket.local_addr().unwrap();
+            let send = |recipient : &mut VbanRecipient, sample_rate, num_samples, frames : std::ops::Range<u32>| {
+                let format = AudioFormat {
+                    sample_rate,
+                    num_samples,
+                    num_channels : 2,
+                    bit_resolution : VBanBitResolution::VbanBitfmt32Float,
+                };
+                for nu_frame in frames {
+                    let head = VBanHeader::audio(stream_name_bytes("Stream1").unwrap(), &format, nu_frame);
+                    sender.send_to(&head.to_packet(&format.bit_resolution.encode(&vec![0.25; num_samples as usize * 2])), destination).unwrap();
+                    recipient.handle().unwrap();
+                }
+            };
+            let sink_of = |recipient : &VbanRecipient| {
+                let sink = recipient.streams()[0].sink.as_ref().unwrap();
+                (sink.sample_rate(), &**sink as *const dyn VbanSink as *const u8)
+            };
+
+            /* 40 packets of 147 frames at 44.1 kHz last as long as 40 × 160 frames at 48 kHz */
+            send(&mut recipient, VBanSampleRates::SampleRate44100Hz, 147, 0..40);
+            let (rate, sink) = sink_of(&recipient);
+            assert_eq!(rate, 48000);
+            assert_eq!(recipient.streams()[0].sample_rate(), 44100);
+
+            /* The stream switching to 48 kHz keeps the sink */
+            send(&mut recipient, VBanSampleRates::SampleRate48000Hz, 160, 40..50);
+            assert_eq!(sink_of(&recipient), (48000, sink));
+            recipient.streams_mut()[0].stop(None);
+
+            let files : Vec<_> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+            assert_eq!(files.len(), 1, "{files:?}");
+            let bytes = std::fs::read(&files[0]).unwrap();
+            std::fs::remove_dir_all(&directory).unwrap();
+            assert_eq!(LittleEndian::read_u32(&bytes[24..]), 48000);
+            let data = bytes.windows(4).position(|id| id == b"data").unwrap();
+            let frames = LittleEndian::read_u32(&bytes[data + 4..]) as usize / 8;
+            assert_eq!(bytes.len(), data + 8 + frames * 8);
+            /* Up to the latency of the resampler, unresampled it would be 7480 frames */
+            let expected = 40 * 160 + 10 * 160;
+            assert!(frames.abs_diff(expected) < 32, "{frames} frames instead of {expected}");
+        }
     }
 
 }
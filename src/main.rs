@@ -1,27 +1,27 @@
-use std::{net::IpAddr, path::PathBuf, process::Command};
-use vban_sink::vban;
-use clap::Parser;
+use std::{io::ErrorKind, net::{IpAddr, SocketAddr}, path::PathBuf, process::Command};
+use std::time::Duration;
+use vban_sink::{channels::ChannelMap, conceal::Concealment, config::Config, discover::Discovery, error::VbanError, text::{CommandInterpreter, LogHandler, ScriptHandler, TextHandler, TextMode}, mixer::Mixer, pipe::PipeFormat, serial::{MidiOutput, PtySink, SerialSink}, service::Ping0, vban::{self, AlsaSource, Output, StreamRoute, VBanBitResolution, VBanSampleRates, VbanEmitter}};
+use clap::{Parser, Subcommand, ValueEnum};
 
 
-/// VBAN sink - by Lennard Jönsson
-/// Receive VBAN UDP streams on port 6980 (default) and play them on your ALSA audio device.
-/// All credit for developing the VBAN protocol goes to vb-audio.com.
-
-/**
+/*
  * Notes:
  * ALSA buffer may be tweaked via hardware and software parameters, namely pcm.sw_params_current() or pcm.hw_params_current(). The swp.set_start_threshold(x) may be used to determine the amount of frames that have to be available in order for playback to start. 
  * 
  * ToDo: 
  * - Support multiple sample rates
- * - Support multiple sample formats
  * - Check and discriminate stream names
- * - Support config files (if necessary)
  */
 
-
-
+/// VBAN sink - by Lennard Jönsson
+/// Receive VBAN UDP streams on port 6980 (default) and play them on your ALSA audio device.
+/// All credit for developing the VBAN protocol goes to vb-audio.com.
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    mode : Option<Mode>,
+
     /// Specify an IP-address if you don't want to bind to all interfaces
     addr : Option<IpAddr>,
 
@@ -29,7 +29,7 @@ struct Cli {
     #[arg(short, long)]
     port : Option<u16>,
 
-    /// Use a config file
+    /// Use a TOML config file. Options given on the command line override the file.
     #[arg(short, long, value_name = "file")]
     config: Option<PathBuf>,
 
@@ -41,6 +41,146 @@ struct Cli {
     #[arg(short='x', long, value_name = "duration")]
     silence : Option<u32>,
 
+    /// Select, reorder or mix the channels of the stream: a comma separated list of stream channels per output channel (e.g. "2,1", "1,1", "0.5*1+0.5*2") or a preset (mono-stereo, stereo-mono, quad-stereo, 5.1-stereo, 7.1-stereo)
+    #[arg(long, value_name = "map")]
+    channel_map : Option<ChannelMap>,
+
+    /// Open the output at this rate and resample streams of other rates to it, instead of following the rate of the stream
+    #[arg(long, value_name = "rate")]
+    output_rate : Option<u32>,
+
+    /// Gain of the stream in dB (default is 0)
+    #[arg(long, value_name = "dB", allow_hyphen_values = true)]
+    gain : Option<f32>,
+
+    /// Gain per channel in dB on top of --gain, counted after the channel map
+    #[arg(long, value_name = "dB,...", value_delimiter = ',', allow_hyphen_values = true)]
+    channel_gains : Option<Vec<f32>>,
+
+    /// Soft limit the samples after the gain instead of letting them clip (default is false)
+    #[arg(long, value_name = "bool", num_args = 0..=1, require_equals = true, default_missing_value = "true", conflicts_with = "no_limiter")]
+    limiter : Option<bool>,
+
+    /// Same as --limiter=false
+    #[arg(long)]
+    no_limiter : bool,
+
+    /// Start the stream muted, it can be unmuted with a VBAN-TEXT command (default is false)
+    #[arg(long, value_name = "bool", num_args = 0..=1, require_equals = true, default_missing_value = "true", conflicts_with = "no_mute")]
+    mute : Option<bool>,
+
+    /// Same as --mute=false
+    #[arg(long)]
+    no_mute : bool,
+
+    /// Stop playback when no packet arrived for this many milliseconds (default is 2000)
+    #[arg(short, long, value_name = "ms")]
+    timeout : Option<u64>,
+
+    /// Target latency of the jitter buffer in milliseconds (default is 40). It grows automatically on buffer underruns.
+    #[arg(short, long, value_name = "ms")]
+    latency : Option<u32>,
+
+    /// Resample the stream slightly to compensate the clock drift between sender and sound card (default is true)
+    #[arg(long, value_name = "bool", num_args = 0..=1, require_equals = true, default_missing_value = "true", conflicts_with = "no_drift_compensation")]
+    drift_compensation : Option<bool>,
+
+    /// Same as --drift-compensation=false
+    #[arg(long)]
+    no_drift_compensation : bool,
+
+    /// How the audio of lost packets is replaced (default is silence)
+    #[arg(long, value_name = "silence|repeat|similarity")]
+    concealment : Option<Concealment>,
+
+    /// Mix all incoming streams into one output on the audio device instead of playing only one (default is false)
+    #[arg(long, value_name = "bool", num_args = 0..=1, require_equals = true, default_missing_value = "true", conflicts_with = "no_mix")]
+    mix : Option<bool>,
+
+    /// Same as --mix=false
+    #[arg(long)]
+    no_mix : bool,
+
+    /// Sample rate of the mixer output (default is 48000)
+    #[arg(long, value_name = "rate")]
+    mix_rate : Option<u32>,
+
+    /// Number of channels of the mixer output (default is 2)
+    #[arg(long, value_name = "channels")]
+    mix_channels : Option<u16>,
+
+    /// What to do with the streams: play them (alsa), record them to files (wav, flac), write raw PCM to stdout (pipe) or only monitor them (null). Builds with the pipewire, jack or pulseaudio feature also accept that output.
+    #[arg(short, long, value_name = "alsa|wav|flac|pipe|null")]
+    output : Option<Output>,
+
+    /// Directory recordings are written to (default is the working directory)
+    #[arg(long, value_name = "dir")]
+    record_dir : Option<PathBuf>,
+
+    /// Start a new recording after this many seconds
+    #[arg(long, value_name = "seconds")]
+    rotate_time : Option<u64>,
+
+    /// Start a new recording before a file exceeds this many megabytes
+    #[arg(long, value_name = "MB")]
+    rotate_size : Option<u64>,
+
+    /// Named pipe the pipe output writes to instead of stdout
+    #[arg(long, value_name = "path")]
+    pipe : Option<PathBuf>,
+
+    /// Sample format of the pipe output (default is s16le)
+    #[arg(long, value_name = "s16le|s24le|f32le")]
+    pipe_format : Option<PipeFormat>,
+
+    /// JACK ports the jack output connects to, one per channel (default are the physical playback ports)
+    #[arg(long, value_name = "port,...", value_delimiter = ',')]
+    jack_ports : Option<Vec<String>>,
+
+    /// Buffer latency of the pulseaudio output in milliseconds (default is 40)
+    #[arg(long, value_name = "ms")]
+    pulse_latency : Option<u32>,
+
+    /// Handle VBAN-TEXT packets: print them, carry out the receiver commands gain, mute and select, or pass them to --text-script
+    #[arg(long, value_name = "log|command|script")]
+    text : Option<TextMode>,
+
+    /// Only handle text packets of this stream (default is any stream)
+    #[arg(long, value_name = "name")]
+    text_stream : Option<String>,
+
+    /// Script that is run with the stream name, the sender address and the text of every text packet
+    #[arg(long, value_name = "script")]
+    text_script : Option<String>,
+
+    /// Play MIDI received in serial packets on a rawmidi device (e.g. hw:1,0,0) or an ALSA sequencer port (seq, or seq:client:port to connect it)
+    #[arg(long, value_name = "device|seq[:client:port]")]
+    midi : Option<MidiOutput>,
+
+    /// Make generic serial data received over VBAN available on a pseudo terminal
+    #[arg(long)]
+    serial_pty : bool,
+
+    /// Create a symlink to the pseudo terminal at this path, implies --serial-pty
+    #[arg(long, value_name = "path")]
+    serial_link : Option<PathBuf>,
+
+    /// Only handle serial packets of this stream (default is any stream)
+    #[arg(long, value_name = "name")]
+    serial_stream : Option<String>,
+
+    /// Do not answer VBAN PING0 requests, so the receiver does not show up in the VBAN dialog of Voicemeeter
+    #[arg(long)]
+    no_ping_reply : bool,
+
+    /// Name the receiver is listed with in replies to PING0 requests (default is the host name)
+    #[arg(long, value_name = "name")]
+    ping_name : Option<String>,
+
+    /// Comment shown in replies to PING0 requests
+    #[arg(long, value_name = "text")]
+    ping_comment : Option<String>,
+
     /// Name of the audio device that is used as a sink (default is "default")
     #[arg(short, long)]
     device_name : Option<String>,
@@ -50,68 +190,364 @@ struct Cli {
     command : Option<String>,
 }
 
-// #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-fn main() -> Result<(), i32> {
+#[derive(Subcommand)]
+enum Mode {
+    /// Capture audio from an ALSA device and send it as VBAN stream
+    Send(SendArgs),
 
-    let cli = Cli::parse();
+    /// Ask the VBAN devices on the network to identify themselves and list the streams arriving on the port
+    Discover(DiscoverArgs),
+}
+
+#[derive(clap::Args)]
+struct SendArgs {
+    /// Destination address, may be a unicast, broadcast or multicast address
+    addr : IpAddr,
+
+    /// Destination port
+    #[arg(short, long, default_value_t = 6980)]
+    port : u16,
+
+    /// Name of the stream
+    #[arg(short, long, value_name = "name", default_value = "Stream1")]
+    stream_name : String,
+
+    /// Name of the audio device to capture from
+    #[arg(short, long, default_value = "default")]
+    device_name : String,
+
+    /// Sample rate in Hz
+    #[arg(short, long, default_value_t = 48000)]
+    rate : u32,
+
+    /// Number of channels
+    #[arg(short, long, default_value_t = 2)]
+    channels : u16,
+
+    /// Sample format of the stream
+    #[arg(short, long, value_enum, default_value_t = SendFormat::S16)]
+    format : SendFormat,
+
+    /// Samples per channel in each packet (at most 256)
+    #[arg(short='n', long, value_name = "count", default_value_t = 256)]
+    samples_per_packet : usize,
+}
+
+#[derive(clap::Args)]
+struct DiscoverArgs {
+    /// Port to listen on and to send the request to
+    #[arg(short, long, default_value_t = 6980)]
+    port : u16,
+
+    /// How long to collect replies and streams, in seconds
+    #[arg(short, long, value_name = "seconds", default_value_t = 3)]
+    time : u64,
+
+    /// Address the request is sent to
+    #[arg(short, long, value_name = "addr", default_value = "255.255.255.255")]
+    broadcast : IpAddr,
+
+    /// Do not send a request, only list the streams
+    #[arg(long)]
+    no_ping : bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SendFormat {
+    S8,
+    S16,
+    S24,
+    S32,
+    F32,
+    F64,
+}
+
+impl From<SendFormat> for VBanBitResolution {
+    fn from(format : SendFormat) -> Self {
+        match format {
+            SendFormat::S8 => VBanBitResolution::VbanBitfmt8Int,
+            SendFormat::S16 => VBanBitResolution::VbanBitfmt16Int,
+            SendFormat::S24 => VBanBitResolution::VbanBitfmt24Int,
+            SendFormat::S32 => VBanBitResolution::VbanBitfmt32Int,
+            SendFormat::F32 => VBanBitResolution::VbanBitfmt32Float,
+            SendFormat::F64 => VBanBitResolution::VbanBitfmt64Float,
+        }
+    }
+}
+
+fn send(args : SendArgs) -> Result<(), i32> {
+    let format = VBanBitResolution::from(args.format);
+    let source = match AlsaSource::init(&args.device_name, args.channels as u32, args.rate, format) {
+        Err(err) => {
+            eprintln!("Could not open capture device {}: {err}", args.device_name);
+            return Err(-1)
+        },
+        Ok(source) => source,
+    };
+
+    /* The device may not support the requested rate exactly, the stream carries the actual one */
+    let sample_rate = match VBanSampleRates::from_hz(source.sample_rate()) {
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(-1)
+        },
+        Ok(sr) => sr,
+    };
 
-    let use_config = match cli.config {
-        None => false,
-        Some(_) => panic!("Config files are currently not supported."),
+    let destination = SocketAddr::new(args.addr, args.port);
+    let mut emitter = match VbanEmitter::create(destination, &args.stream_name, sample_rate, args.channels, format, args.samples_per_packet) {
+        Err(err) => {
+            eprintln!("Could not create VBAN emitter: {err}");
+            return Err(-1)
+        },
+        Ok(emitter) => emitter,
     };
 
-    let addr : IpAddr;
-    let port : u16;
-    let stream_name : Option<String>;
-    let mut device_name = String::from("default");
-    
-    if use_config {
-        // todo 
-        addr = "127.0.0.1".parse().unwrap();
-        port = 6980;
-        stream_name = None;
-    } else {
-        addr = match cli.addr {
-            None => "0.0.0.0".parse().unwrap(),
-            Some(addr) => {
-                println!("Using {addr} as address to bind to.");
-                addr
+    eprintln!("Sending stream {} from {} to {destination}: \nSR: {} \t Ch: {} \t Samples per packet: {}", args.stream_name, args.device_name, sample_rate, args.channels, emitter.samples_per_packet());
+
+    loop {
+        let samples = match source.read(emitter.samples_per_packet()) {
+            Err(err) => {
+                eprintln!("Capture failed: {err}");
+                return Err(-1)
             },
+            Ok(samples) => samples,
         };
-        port = match cli.port {
-            None => 6980,
-            Some(num) => {
-                println!("Using port {num}.");
-                num
-            },
+        if let Err(err) = emitter.send(&samples) {
+            eprintln!("{err}");
+        }
+    }
+}
+
+fn discover(args : DiscoverArgs) -> Result<(), i32> {
+    let mut discovery = match Discovery::bind(SocketAddr::from(([0, 0, 0, 0], args.port))) {
+        Err(VbanError::Io(err)) if err.kind() == ErrorKind::AddrInUse => {
+            eprintln!("Port {} is in use, stop the receiver listening there or use --port.", args.port);
+            return Err(-1)
+        },
+        Err(err) => {
+            eprintln!("Could not listen on port {}: {err}", args.port);
+            return Err(-1)
+        },
+        Ok(discovery) => discovery,
+    };
+
+    if !args.no_ping {
+        if let Err(err) = discovery.ping(SocketAddr::new(args.broadcast, args.port)) {
+            eprintln!("Could not send request to {}: {err}", args.broadcast);
+            return Err(-1)
+        }
+    }
+    eprintln!("Listening on port {} for {} s...", args.port, args.time);
+    if let Err(err) = discovery.listen(Duration::from_secs(args.time)) {
+        eprintln!("{err}");
+        return Err(-1)
+    }
+
+    if !args.no_ping {
+        println!("Devices:");
+        if discovery.devices().is_empty() {
+            println!("  none answered");
+        }
+        for device in discovery.devices() {
+            let info = &device.info;
+            let version = info.version.map(|part| part.to_string()).join(".");
+            println!("  {:<21} {} ({} {version}) on {}{}", device.address, info.user_name, info.application_name, info.host_name,
+                Some(&info.user_comment).filter(|comment| !comment.is_empty()).map(|comment| format!(" - {comment}")).unwrap_or_default());
+        }
+    }
+
+    println!("Streams:");
+    if discovery.streams().is_empty() {
+        println!("  none seen");
+    }
+    for stream in discovery.streams() {
+        let format = &stream.format;
+        let sample_format = match format.bit_resolution {
+            VBanBitResolution::VbanBitfmt32Float | VBanBitResolution::VbanBitfmt64Float => format!("{} bit float", format.bit_resolution.bits()),
+            _ => format!("{} bit", format.bit_resolution.bits()),
         };
-        stream_name = match cli.stream_name {
-            None => None,
-            Some(name) => {
-                println!("Using {name} as stream name.");
-                Some(name)
+        println!("  {:<15} {:<16} {:>9} {:>3} ch  {sample_format:<12} {:>6.1} packets/s", stream.source, stream.stream_name, format.sample_rate.to_string(), format.num_channels, stream.packet_rate());
+    }
+    Ok(())
+}
+
+// #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn main() -> Result<(), i32> {
+
+    let cli = Cli::parse();
+
+    match cli.mode {
+        Some(Mode::Send(args)) => return send(args),
+        Some(Mode::Discover(args)) => return discover(args),
+        None => (),
+    }
+
+    let config = match &cli.config {
+        None => Config::default(),
+        Some(path) => match Config::load(path) {
+            Ok(config) => {
+                eprintln!("Using config file {}.", path.display());
+                config
             },
-        };
-        device_name = match cli.device_name {
-            None => String::from("default"),
-            Some(name) => name,
-        };
+            Err(err) => {
+                eprintln!("{err}");
+                return Err(-1)
+            },
+        },
+    };
+
+    /* Options on the command line take precedence over the config file */
+    let addr = match cli.addr.or(config.address) {
+        None => "0.0.0.0".parse().unwrap(),
+        Some(addr) => {
+            eprintln!("Using {addr} as address to bind to.");
+            addr
+        },
+    };
+    let port = match cli.port.or(config.port) {
+        None => 6980,
+        Some(num) => {
+            eprintln!("Using port {num}.");
+            num
+        },
+    };
+    if let Some(name) = cli.stream_name.as_ref().or(config.stream_name.as_ref()) {
+        eprintln!("Using {name} as stream name.");
     }
+    let device_name = cli.device_name.clone().or(config.device_name.clone()).unwrap_or(String::from("default"));
+    let command = cli.command.or(config.command.clone());
 
+    /* The route settings of the command line are layered over every route of the file */
+    let overrides = Config {
+        stream_name : cli.stream_name,
+        device_name : cli.device_name,
+        silence : cli.silence,
+        channel_map : cli.channel_map,
+        output_rate : cli.output_rate,
+        gain : cli.gain,
+        channel_gains : cli.channel_gains,
+        mute : cli.mute.or(cli.no_mute.then_some(false)),
+        limiter : cli.limiter.or(cli.no_limiter.then_some(false)),
+        timeout : cli.timeout,
+        latency : cli.latency,
+        drift_compensation : cli.drift_compensation.or(cli.no_drift_compensation.then_some(false)),
+        concealment : cli.concealment,
+        output : cli.output,
+        record_dir : cli.record_dir,
+        rotate_time : cli.rotate_time,
+        rotate_size : cli.rotate_size,
+        pipe : cli.pipe,
+        pipe_format : cli.pipe_format,
+        jack_ports : cli.jack_ports,
+        pulse_latency : cli.pulse_latency,
+        ..Default::default()
+    };
+    let routes = config.routes(&overrides);
 
-    let mut vbr = match vban::VbanRecipient::create(
-    addr, port, stream_name, None, None,
-    device_name, cli.silence){
-        None => {
-            println!("Could not create VBAN recipient.");
+    let mix = cli.mix.or(cli.no_mix.then_some(false)).or(config.mix).unwrap_or(false);
+
+    let mut vbr = match vban::VbanRecipient::create(addr, port, routes) {
+        Err(err) => {
+            eprintln!("Could not create VBAN recipient: {err}");
             return Err(-1)
         },
-        Some(_vbr) => {
+        Ok(_vbr) => {
             _vbr
         }
     };
 
-    match cli.command {
+    for stream in vbr.streams() {
+        let route = stream.route();
+        eprintln!("Route: stream {} from {} -> {} (channel offset {})",
+            route.stream_name.as_deref().unwrap_or("*"),
+            route.source.map(|addr| addr.to_string()).unwrap_or(String::from("*")),
+            route.target(), route.channel_offset);
+    }
+
+    if mix {
+        let rate = cli.mix_rate.or(config.mix_rate).unwrap_or(48000);
+        let channels = cli.mix_channels.or(config.mix_channels).unwrap_or(2);
+        /* The mix goes to the output of the top level settings */
+        let mut mix_route = StreamRoute::new(String::from("default"));
+        config.apply(&mut mix_route);
+        overrides.apply(&mut mix_route);
+        eprintln!("Mixing all streams into {channels} channels at {rate} Hz on {}.", mix_route.target());
+        vbr.set_mixer(Mixer::new(mix_route, rate, channels as usize));
+    }
+
+    let text_script = cli.text_script.or(config.text_script);
+    let text_handler : Option<Box<dyn TextHandler>> = match (cli.text.or(config.text), text_script) {
+        (None, _) => None,
+        (Some(TextMode::Log), _) => Some(Box::new(LogHandler)),
+        (Some(TextMode::Command), _) => Some(Box::new(CommandInterpreter)),
+        (Some(TextMode::Script), Some(script)) => Some(Box::new(ScriptHandler::new(script))),
+        (Some(TextMode::Script), None) => {
+            eprintln!("The script text handler needs --text-script.");
+            return Err(-1)
+        },
+    };
+    if let Some(handler) = text_handler {
+        if let Err(err) = vbr.set_text_handler(handler, cli.text_stream.or(config.text_stream).as_deref()) {
+            eprintln!("Invalid text stream: {err}");
+            return Err(-1)
+        }
+    }
+
+    let midi = match cli.midi.or(config.midi).map(|output| output.open()).transpose() {
+        Ok(midi) => midi,
+        Err(err) => {
+            eprintln!("Could not open MIDI output: {err}");
+            return Err(-1)
+        },
+    };
+    let serial_link = cli.serial_link.or(config.serial_link);
+    let serial : Option<Box<dyn SerialSink>> = match cli.serial_pty || config.serial_pty.unwrap_or(false) || serial_link.is_some() {
+        false => None,
+        true => match PtySink::create(serial_link.as_deref()) {
+            Ok(pty) => Some(Box::new(pty)),
+            Err(err) => {
+                eprintln!("Could not create pseudo terminal: {err}");
+                return Err(-1)
+            },
+        },
+    };
+    if midi.is_some() || serial.is_some() {
+        if let Err(err) = vbr.set_serial_sinks(midi, serial, cli.serial_stream.or(config.serial_stream).as_deref()) {
+            eprintln!("Invalid serial stream: {err}");
+            return Err(-1)
+        }
+    }
+
+    if !cli.no_ping_reply && config.ping.enable.unwrap_or(true) {
+        let ping = &config.ping;
+        let mut info = Ping0::receptor();
+        info.preferred_rate = match mix {
+            true => cli.mix_rate.or(config.mix_rate).unwrap_or(48000),
+            false => cli.output_rate.or(config.output_rate).unwrap_or(info.preferred_rate),
+        };
+        info.device_name = ping.device_name.clone().unwrap_or(device_name.clone());
+        info.manufacturer_name = ping.manufacturer.clone().unwrap_or_default();
+        info.user_name = cli.ping_name.or(ping.user_name.clone()).unwrap_or(info.host_name.clone());
+        info.user_comment = cli.ping_comment.or(ping.comment.clone()).unwrap_or_default();
+        info.gps_position = ping.gps_position.clone().unwrap_or_default();
+        info.user_position = ping.user_position.clone().unwrap_or_default();
+        if let Some(lang) = &ping.lang {
+            info.lang_code = lang.clone();
+        }
+        if let Some(color) = &ping.color {
+            match u32::from_str_radix(color.trim_start_matches('#'), 16) {
+                Ok(rgb) => info.color_rgb = rgb,
+                Err(_) => {
+                    eprintln!("Invalid ping color {color} (expected #RRGGBB).");
+                    return Err(-1)
+                },
+            }
+        }
+        vbr.set_ping_reply(Some(info));
+    }
+
+    match command {
         None => (),
         Some(cmd) => {
             let handle = Command::new(cmd);
@@ -121,7 +557,9 @@ fn main() -> Result<(), i32> {
 
 
     loop {
-        vbr.handle();
+        if let Err(err) = vbr.handle() {
+            eprintln!("{err}");
+        }
     }
 
 }
@@ -0,0 +1,260 @@
+use std::{cell::RefCell, collections::VecDeque, net::IpAddr, rc::Rc};
+use crate::{gain::soft_clip, vban::{StreamRoute, VBanBitResolution, VbanError, VbanSink}};
+
+/// Stream name the mix is recorded or announced as
+const MIXER_STREAM_NAME : &str = "Mix";
+
+/// An input that falls behind the others by more than this is mixed as silence
+const MIXER_MAX_SKEW_MS : u32 = 100;
+
+/// Sums several streams into one output, usually an audio device.
+///
+/// Every stream writes into its own `MixerInput`, which converts the channel layout and
+/// queues the audio. Whenever new audio arrives, as many frames as all inputs can provide are
+/// summed, soft clipped and written to the output. The level of each stream is set by its
+/// `Gain` before it reaches the mixer. The output is
+/// opened with the first input and closed again when the last input is gone.
+pub struct Mixer {
+    shared : Rc<MixerShared>,
+}
+
+/// Opens the sink the mix is written to
+type OpenOutput = Box<dyn Fn() -> Result<Box<dyn VbanSink>, VbanError>>;
+
+struct MixerShared {
+    open_output : OpenOutput,
+
+    sample_rate : u32,
+
+    num_channels : usize,
+
+    output : RefCell<Option<Box<dyn VbanSink>>>,
+
+    inputs : RefCell<Vec<Rc<MixerQueue>>>,
+}
+
+struct MixerQueue {
+    num_channels : usize,
+
+    samples : RefCell<VecDeque<f32>>,
+
+    /// Inputs only hold back the mix once they delivered their first audio
+    started : RefCell<bool>,
+}
+
+/// Mixer input of a single stream. Dropping it removes the stream from the mix.
+pub struct MixerInput {
+    shared : Rc<MixerShared>,
+
+    queue : Rc<MixerQueue>,
+}
+
+impl Mixer {
+
+    /// Mixes into the output of `route`, e.g. its audio device, opened at `sample_rate` with
+    /// `num_channels`
+    pub fn new(route : StreamRoute, sample_rate : u32, num_channels : usize) -> Self {
+        Self::with_output(sample_rate, num_channels, move || {
+            let output = route.open_output(MIXER_STREAM_NAME, IpAddr::from([0, 0, 0, 0]), sample_rate, num_channels as u16, VBanBitResolution::VbanBitfmt32Float)?;
+            eprintln!("Mixer output opened on {} with {num_channels} channels at {} Hz.", route.target(), output.sample_rate());
+            Ok(output)
+        })
+    }
+
+    /// Mixes into the sink `open_output` returns. It is called when the first input is added.
+    pub fn with_output(sample_rate : u32, num_channels : usize, open_output : impl Fn() -> Result<Box<dyn VbanSink>, VbanError> + 'static) -> Self {
+        Self {
+            shared : Rc::new(MixerShared {
+                open_output : Box::new(open_output),
+                sample_rate,
+                num_channels : num_channels.max(1),
+                output : RefCell::new(None),
+                inputs : RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Adds an input for a stream with `num_channels` channels
+    pub fn input(&self, num_channels : usize) -> Result<MixerInput, VbanError> {
+        let shared = &self.shared;
+        if shared.output.borrow().is_none() {
+            *shared.output.borrow_mut() = Some((shared.open_output)()?);
+        }
+
+        let queue = Rc::new(MixerQueue {
+            num_channels : num_channels.max(1),
+            samples : RefCell::new(VecDeque::new()),
+            started : RefCell::new(false),
+        });
+        shared.inputs.borrow_mut().push(queue.clone());
+        Ok(MixerInput {
+            shared : shared.clone(),
+            queue,
+        })
+    }
+}
+
+impl MixerShared {
+
+    fn output_rate(&self) -> u32 {
+        match &*self.output.borrow() {
+            None => self.sample_rate,
+            Some(output) => output.sample_rate(),
+        }
+    }
+
+    /// Mixes what all started inputs have in common and writes it to the device
+    fn mix(&self) {
+        let inputs = self.inputs.borrow();
+        let nch = self.num_channels;
+        let fills : Vec<usize> = inputs.iter()
+            .filter(|queue| *queue.started.borrow())
+            .map(|queue| queue.samples.borrow().len() / nch)
+            .collect();
+
+        let min = fills.iter().copied().min().unwrap_or(0);
+        let max = fills.iter().copied().max().unwrap_or(0);
+        let skew = (self.output_rate() * MIXER_MAX_SKEW_MS / 1000) as usize;
+        let frames = min.max(max.saturating_sub(skew));
+        if frames == 0 {
+            return;
+        }
+
+        let mut mixed = vec![0f32; frames * nch];
+        for queue in inputs.iter() {
+            let mut samples = queue.samples.borrow_mut();
+            let take = samples.len().min(frames * nch);
+            for (out, smp) in mixed.iter_mut().zip(samples.drain(..take)) {
+                *out += smp;
+            }
+        }
+        for smp in mixed.iter_mut() {
+            *smp = soft_clip(*smp);
+        }
+
+        if let Some(output) = &*self.output.borrow() {
+            output.write(&mixed);
+        }
+    }
+}
+
+impl VbanSink for MixerInput {
+
+    fn write(&self, buf : &[f32]) {
+        let in_ch = self.queue.num_channels;
+        let out_ch = self.shared.num_channels;
+        {
+            let mut samples = self.queue.samples.borrow_mut();
+            for frame in buf.chunks_exact(in_ch) {
+                for ch in 0..out_ch {
+                    /* Mono is played on all channels, otherwise channels map one to one */
+                    let smp = match in_ch {
+                        1 => frame[0],
+                        _ => frame.get(ch).copied().unwrap_or(0.0),
+                    };
+                    samples.push_back(smp);
+                }
+            }
+        }
+        *self.queue.started.borrow_mut() = true;
+        self.shared.mix();
+    }
+
+    fn delay(&self) -> Option<usize> {
+        let queued = self.queue.samples.borrow().len() / self.shared.num_channels;
+        let output = match &*self.shared.output.borrow() {
+            None => Some(0),
+            Some(output) => output.delay(),
+        };
+        output.map(|frames| frames + queued)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.shared.output_rate()
+    }
+}
+
+impl Drop for MixerInput {
+    fn drop(&mut self) {
+        let mut inputs = self.shared.inputs.borrow_mut();
+        inputs.retain(|queue| !Rc::ptr_eq(queue, &self.queue));
+        if inputs.is_empty() {
+            if let Some(output) = self.shared.output.borrow_mut().take() {
+                output.drain();
+                eprintln!("Mixer output closed.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vban::Output;
+
+    /// Keeps everything written to it
+    struct CaptureSink {
+        samples : Rc<RefCell<Vec<f32>>>,
+    }
+
+    impl VbanSink for CaptureSink {
+        fn write(&self, buf : &[f32]) {
+            self.samples.borrow_mut().extend_from_slice(buf);
+        }
+
+        fn delay(&self) -> Option<usize> {
+            None
+        }
+
+        fn sample_rate(&self) -> u32 {
+            48000
+        }
+    }
+
+    fn capture_mixer() -> (Mixer, Rc<RefCell<Vec<f32>>>) {
+        let mixed = Rc::new(RefCell::new(Vec::new()));
+        let output = mixed.clone();
+        (Mixer::with_output(48000, 2, move || Ok(Box::new(CaptureSink { samples : output.clone() }))), mixed)
+    }
+
+    #[test]
+    fn sums_inputs() {
+        let (mixer, mixed) = capture_mixer();
+        let stereo = mixer.input(2).unwrap();
+        let mono = mixer.input(1).unwrap();
+        /* Until the mono input delivers audio it does not hold back the mix */
+        stereo.write(&[0.25, -0.25].repeat(64));
+        assert_eq!(mixed.borrow().len(), 128);
+        mono.write(&[0.125; 64]);
+        assert_eq!(mixed.borrow().len(), 128);
+        stereo.write(&[0.25, -0.25].repeat(64));
+        assert_eq!(mixed.borrow()[128..], [0.375, -0.125].repeat(64));
+    }
+
+    #[test]
+    fn clips_the_sum_softly() {
+        let (mixer, mixed) = capture_mixer();
+        let first = mixer.input(2).unwrap();
+        let second = mixer.input(2).unwrap();
+        first.write(&[0.0; 2]);
+        second.write(&[0.8, 0.3].repeat(16));
+        first.write(&[0.8, 0.3].repeat(16));
+        let mixed = mixed.borrow();
+        assert_eq!(mixed.len(), 34);
+        for frame in mixed[2..].chunks(2) {
+            assert!(frame[0] > 0.9 && frame[0] < 1.0, "{}", frame[0]);
+            assert!((frame[1] - 0.6).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn mixes_into_the_output_of_the_route() {
+        let mut route = StreamRoute::new(String::from("no such device"));
+        route.output = Output::Null;
+        let mixer = Mixer::new(route, 44100, 2);
+        let input = mixer.input(2).unwrap();
+        input.write(&[0.5; 128]);
+        assert_eq!(input.sample_rate(), 44100);
+        assert_eq!(input.delay(), None);
+    }
+}
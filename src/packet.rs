@@ -0,0 +1,809 @@
+//! VBAN packet layout and sample formats. This module does not depend on ALSA, so the
+//! protocol handling can be used on its own with `default-features = false`.
+
+use std::borrow::Cow;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::error::VbanError;
+
+pub const VBAN_STREAM_NAME_SIZE : usize = 16;
+pub const VBAN_PROTOCOL_MAX_SIZE : usize = 1464;
+pub const VBAN_CHANNELS_MAX_NB : usize = 256;
+pub const VBAN_SAMPLES_MAX_NB : usize = 256;
+
+pub const VBAN_PACKET_HEADER_BYTES : usize = 24;  
+pub const VBAN_PACKET_COUNTER_BYTES : usize = 4;  
+
+/// Header plus frame counter
+pub const VBAN_PACKET_FULL_HEADER_BYTES : usize = VBAN_PACKET_HEADER_BYTES + VBAN_PACKET_COUNTER_BYTES;
+
+/// Largest payload that fits a packet after the header and frame counter
+pub const VBAN_DATA_MAX_SIZE : usize = VBAN_PROTOCOL_MAX_SIZE - VBAN_PACKET_FULL_HEADER_BYTES;
+
+
+/// Packet header as it is laid out on the wire. The protocol shares a byte with the sample
+/// rate and the codec shares one with the bit resolution, see the accessors of `VbanPacket`
+/// for the decoded values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VBanHeader {
+    pub preamble : [u8; 4],
+    pub sample_rate : u8,
+    pub num_samples : u8,
+
+    // number of channels, where 0 = one channel
+    pub num_channels : u8,
+    pub sample_format : u8,
+    pub stream_name : [u8;16],
+    pub nu_frame : u32
+}
+
+impl VBanHeader {
+
+    /// Header of an audio packet in the given format
+    pub fn audio(stream_name : [u8; 16], format : &AudioFormat, nu_frame : u32) -> Self {
+        Self {
+            preamble : *b"VBAN",
+            sample_rate : VBanProtocol::VbanProtocolAudio as u8 | format.sample_rate as u8,
+            num_samples : (format.num_samples - 1) as u8,
+            num_channels : (format.num_channels - 1) as u8,
+            sample_format : VBanCodec::VbanCodecPcm as u8 | format.bit_resolution as u8,
+            stream_name,
+            nu_frame,
+        }
+    }
+
+    /// Header of a text packet in the given encoding
+    pub fn text(stream_name : [u8; 16], format : VBanTextFormat, nu_frame : u32) -> Self {
+        Self {
+            preamble : *b"VBAN",
+            sample_rate : VBanProtocol::VbanProtocolTxt as u8,
+            num_samples : 0,
+            num_channels : 0,
+            sample_format : format as u8,
+            stream_name,
+            nu_frame,
+        }
+    }
+
+    /// Header of a serial packet with the given line settings
+    pub fn serial(stream_name : [u8; 16], format : &SerialFormat, nu_frame : u32) -> Self {
+        let bps_index = VBAN_BPSLIST.iter().position(|bps| *bps == format.bps).unwrap_or(0) as u8;
+        let mut line = format.stop_bits & VBAN_SERIAL_STOP_BITS_MASK;
+        if format.start_bit {
+            line |= VBAN_SERIAL_START_BIT;
+        }
+        if format.parity {
+            line |= VBAN_SERIAL_PARITY;
+        }
+        if format.multipart {
+            line |= VBAN_SERIAL_MULTIPART;
+        }
+        Self {
+            preamble : *b"VBAN",
+            sample_rate : VBanProtocol::VbanProtocolSerial as u8 | bps_index,
+            num_samples : line,
+            num_channels : format.channel,
+            sample_format : format.kind as u8,
+            stream_name,
+            nu_frame,
+        }
+    }
+
+    /// Header of a service packet, see the `service` module for the values
+    pub fn service(stream_name : [u8; 16], service_type : u8, function : u8, nu_frame : u32) -> Self {
+        Self {
+            preamble : *b"VBAN",
+            sample_rate : VBanProtocol::VbanProtocolService as u8,
+            num_samples : function,
+            num_channels : service_type,
+            sample_format : 0,
+            stream_name,
+            nu_frame,
+        }
+    }
+
+    /// Serializes the header followed by `payload` into a packet
+    pub fn to_packet(&self, payload : &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(VBAN_PACKET_FULL_HEADER_BYTES + payload.len());
+        packet.extend_from_slice(&<[u8; 28]>::from(self));
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+impl From<[u8; 28]> for VBanHeader {
+    fn from (item: [u8; 28]) -> Self {
+
+        let frame_count = LittleEndian::read_u32(&item[24..28]);
+
+        Self {
+            preamble : item[0..4].try_into().unwrap(),
+            sample_rate : item[4],
+            num_samples : item[5],
+            num_channels : item[6],
+            sample_format : item[7],
+            stream_name : [item[8], item[9], item[10], item[11], item[12], item[13], item[14], item[15], item[16], item[17], item[18], item[19], item[20], item[21], item[22], item[23]],
+            nu_frame : frame_count
+        }
+    }
+}
+
+impl From<&VBanHeader> for [u8; 28] {
+    fn from (head: &VBanHeader) -> Self {
+        let mut item = [0u8; 28];
+        item[0..4].copy_from_slice(&head.preamble);
+        item[4] = head.sample_rate;
+        item[5] = head.num_samples;
+        item[6] = head.num_channels;
+        item[7] = head.sample_format;
+        item[8..24].copy_from_slice(&head.stream_name);
+        LittleEndian::write_u32(&mut item[24..28], head.nu_frame);
+        item
+    }
+}
+
+/// Audio format described by the header of an audio packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub sample_rate : VBanSampleRates,
+
+    /// Samples per channel, 1 to 256
+    pub num_samples : u16,
+
+    /// 1 to 256
+    pub num_channels : u16,
+
+    pub bit_resolution : VBanBitResolution,
+}
+
+/// A received packet, borrowed from the receive buffer. `parse` only checks what every
+/// sub protocol has in common, `audio_format` validates the audio specific fields.
+#[derive(Clone, Copy, Debug)]
+pub struct VbanPacket<'a> {
+    header : &'a [u8; 28],
+
+    payload : &'a [u8],
+}
+
+impl<'a> VbanPacket<'a> {
+
+    pub fn parse(buf : &'a [u8]) -> Result<Self, VbanError> {
+        if buf.len() < 4 || buf[..4] != *b"VBAN" {
+            return Err(VbanError::NotVban);
+        }
+        if buf.len() < VBAN_PACKET_FULL_HEADER_BYTES {
+            return Err(VbanError::PacketTooShort(buf.len()));
+        }
+        if buf.len() - VBAN_PACKET_FULL_HEADER_BYTES > VBAN_DATA_MAX_SIZE {
+            return Err(VbanError::PacketTooLong(buf.len()));
+        }
+        let (header, payload) = buf.split_at(VBAN_PACKET_FULL_HEADER_BYTES);
+        Ok(Self {
+            header : header.try_into().unwrap(),
+            payload,
+        })
+    }
+
+    /// Copy of the raw header fields
+    pub fn header(&self) -> VBanHeader {
+        VBanHeader::from(*self.header)
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    pub fn protocol(&self) -> VBanProtocol {
+        VBanProtocol::from(self.header[4])
+    }
+
+    pub fn stream_name(&self) -> &'a [u8; 16] {
+        self.header[8..24].try_into().unwrap()
+    }
+
+    pub fn stream_name_str(&self) -> Cow<'a, str> {
+        stream_name_str(self.stream_name())
+    }
+
+    pub fn nu_frame(&self) -> u32 {
+        LittleEndian::read_u32(&self.header[24..28])
+    }
+
+    /// Validates the header of an audio packet and returns the format it describes
+    pub fn audio_format(&self) -> Result<AudioFormat, VbanError> {
+        let header = self.header();
+        if self.protocol() != VBanProtocol::VbanProtocolAudio {
+            return Err(VbanError::UnsupportedProtocol(header.sample_rate & VBAN_PROTOCOL_MASK));
+        }
+        if VBanCodec::from(header.sample_format) != VBanCodec::VbanCodecPcm {
+            return Err(VbanError::UnsupportedCodec(header.sample_format & VBAN_CODEC_MASK));
+        }
+        if header.sample_format & VBAN_RESERVED_MASK != 0 {
+            return Err(VbanError::ReservedBitSet);
+        }
+        Ok(AudioFormat {
+            sample_rate : VBanSampleRates::try_from(header.sample_rate)?,
+            num_samples : header.num_samples as u16 + 1,
+            num_channels : header.num_channels as u16 + 1,
+            bit_resolution : VBanBitResolution::try_from(header.sample_format)?,
+        })
+    }
+
+    /// Validates the header of a serial packet and returns the line settings it describes
+    pub fn serial_format(&self) -> Result<SerialFormat, VbanError> {
+        let header = self.header();
+        if self.protocol() != VBanProtocol::VbanProtocolSerial {
+            return Err(VbanError::UnsupportedProtocol(header.sample_rate & VBAN_PROTOCOL_MASK));
+        }
+        Ok(SerialFormat {
+            bps : VBAN_BPSLIST.get((header.sample_rate & VBAN_SR_MASK) as usize).copied().unwrap_or(0),
+            stop_bits : header.num_samples & VBAN_SERIAL_STOP_BITS_MASK,
+            start_bit : header.num_samples & VBAN_SERIAL_START_BIT != 0,
+            parity : header.num_samples & VBAN_SERIAL_PARITY != 0,
+            multipart : header.num_samples & VBAN_SERIAL_MULTIPART != 0,
+            channel : header.num_channels,
+            kind : VBanSerialType::try_from(header.sample_format)?,
+        })
+    }
+
+    /// Service type and function of a service packet
+    pub fn service(&self) -> Result<(u8, u8), VbanError> {
+        let header = self.header();
+        if self.protocol() != VBanProtocol::VbanProtocolService {
+            return Err(VbanError::UnsupportedProtocol(header.sample_rate & VBAN_PROTOCOL_MASK));
+        }
+        Ok((header.num_channels, header.num_samples))
+    }
+
+    /// Validates the header of a text packet and decodes the text it carries
+    pub fn text(&self) -> Result<String, VbanError> {
+        let header = self.header();
+        if self.protocol() != VBanProtocol::VbanProtocolTxt {
+            return Err(VbanError::UnsupportedProtocol(header.sample_rate & VBAN_PROTOCOL_MASK));
+        }
+        Ok(VBanTextFormat::try_from(header.sample_format)?.decode(self.payload))
+    }
+}
+
+
+const VBAN_SR_MASK : u8 = 0x1F;
+pub const VBAN_SR_MAXNUMBER : u8 = 21;
+pub const VBAN_SRLIST : [u32; 21] = [
+    6000, 12000, 24000, 48000, 96000, 192000, 384000,
+    8000, 16000, 32000, 64000, 128000, 256000, 512000,
+    11025, 22050, 44100, 88200, 176400, 352800, 705600
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VBanSampleRates {
+    SampleRate6000Hz,
+    SampleRate12000Hz,
+    SampleRate24000Hz,
+    SampleRate48000Hz,
+    SampleRate96000Hz,
+    SampleRate192000Hz,
+    SampleRate384000Hz,
+    SampleRate8000Hz,
+    SampleRate16000Hz,
+    SampleRate32000Hz,
+    SampleRate64000Hz,
+    SampleRate128000Hz,
+    SampleRate256000Hz,
+    SampleRate512000Hz,
+    SampleRate11025Hz,
+    SampleRate22050Hz,
+    SampleRate44100Hz,
+    SampleRate88200Hz,
+    SampleRate176400Hz,
+    SampleRate352800Hz,
+    SampleRate705600Hz
+}
+
+impl std::fmt::Display for VBanSampleRates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VBanSampleRates::SampleRate6000Hz => write!(f, "{} Hz", 6000), 
+            VBanSampleRates::SampleRate12000Hz => write!(f, "{} Hz", 12000), 
+            VBanSampleRates::SampleRate24000Hz => write!(f, "{} Hz", 24000), 
+            VBanSampleRates::SampleRate48000Hz => write!(f, "{} Hz", 48000), 
+            VBanSampleRates::SampleRate96000Hz => write!(f, "{} Hz", 96000), 
+            VBanSampleRates::SampleRate192000Hz => write!(f, "{} Hz", 192000), 
+            VBanSampleRates::SampleRate384000Hz => write!(f, "{} Hz", 384000), 
+            VBanSampleRates::SampleRate8000Hz => write!(f, "{} Hz", 8000), 
+            VBanSampleRates::SampleRate16000Hz => write!(f, "{} Hz", 16000), 
+            VBanSampleRates::SampleRate32000Hz => write!(f, "{} Hz", 32000), 
+            VBanSampleRates::SampleRate64000Hz => write!(f, "{} Hz", 64000), 
+            VBanSampleRates::SampleRate128000Hz => write!(f, "{} Hz", 128000), 
+            VBanSampleRates::SampleRate256000Hz => write!(f, "{} Hz", 256000), 
+            VBanSampleRates::SampleRate512000Hz => write!(f, "{} Hz", 512000), 
+            VBanSampleRates::SampleRate11025Hz => write!(f, "{} Hz", 11025), 
+            VBanSampleRates::SampleRate22050Hz => write!(f, "{} Hz", 22050), 
+            VBanSampleRates::SampleRate44100Hz => write!(f, "{} Hz", 44100), 
+            VBanSampleRates::SampleRate88200Hz => write!(f, "{} Hz", 88200), 
+            VBanSampleRates::SampleRate176400Hz => write!(f, "{} Hz", 176400), 
+            VBanSampleRates::SampleRate352800Hz => write!(f, "{} Hz", 352800), 
+            VBanSampleRates::SampleRate705600Hz => write!(f, "{} Hz", 705600),             
+        }
+    }
+}
+
+impl VBanSampleRates {
+
+    /// Looks up the rate in the VBAN sample rate list
+    pub fn from_hz(rate : u32) -> Result<Self, VbanError> {
+        match VBAN_SRLIST.iter().position(|sr| *sr == rate) {
+            None => Err(VbanError::UnsupportedSampleRate(rate)),
+            Some(index) => Self::try_from(index as u8),
+        }
+    }
+
+    pub fn hz(&self) -> u32 {
+        VBAN_SRLIST[*self as usize]
+    }
+}
+
+impl TryFrom<u8> for VBanSampleRates {
+    type Error = VbanError;
+
+    fn try_from(item : u8) -> Result<Self, Self::Error> {
+        Ok(match item & VBAN_SR_MASK {
+            0 => VBanSampleRates::SampleRate6000Hz,
+            1 => VBanSampleRates::SampleRate12000Hz,
+            2 => VBanSampleRates::SampleRate24000Hz,
+            3 => VBanSampleRates::SampleRate48000Hz,
+            4 => VBanSampleRates::SampleRate96000Hz,
+            5 => VBanSampleRates::SampleRate192000Hz,
+            6 => VBanSampleRates::SampleRate384000Hz,
+            7 => VBanSampleRates::SampleRate8000Hz,
+            8 => VBanSampleRates::SampleRate16000Hz,
+            9 => VBanSampleRates::SampleRate32000Hz,
+            10 => VBanSampleRates::SampleRate64000Hz,
+            11 => VBanSampleRates::SampleRate128000Hz,
+            12 => VBanSampleRates::SampleRate256000Hz,
+            13 => VBanSampleRates::SampleRate512000Hz,
+            14 => VBanSampleRates::SampleRate11025Hz,
+            15 => VBanSampleRates::SampleRate22050Hz,
+            16 => VBanSampleRates::SampleRate44100Hz,
+            17 => VBanSampleRates::SampleRate88200Hz,
+            18 => VBanSampleRates::SampleRate176400Hz,
+            19 => VBanSampleRates::SampleRate352800Hz,
+            20 => VBanSampleRates::SampleRate705600Hz,
+            _ => return Err(VbanError::InvalidSampleRate(item & VBAN_SR_MASK)),
+        })
+    }
+}
+
+const VBAN_PROTOCOL_MASK : u8 = 0xE0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanProtocol {
+    VbanProtocolAudio         =   0x00,
+    VbanProtocolSerial        =   0x20,
+    VbanProtocolTxt           =   0x40,
+    VbanProtocolService      =   0x60,
+    VbanProtocolUndefined1   =   0x80,
+    VbanProtocolUndefined2   =   0xA0,
+    VbanProtocolUndefined3   =   0xC0,
+    VbanProtocolUndefined4   =   0xE0
+}
+
+impl From<u8> for VBanProtocol {
+
+    fn from(value: u8) -> Self {
+        match value & VBAN_PROTOCOL_MASK {
+            0x00 => VBanProtocol::VbanProtocolAudio,
+            0x20 => VBanProtocol::VbanProtocolSerial,
+            0x40 => VBanProtocol::VbanProtocolTxt,
+            0x60 => VBanProtocol::VbanProtocolService,
+            0x80 => VBanProtocol::VbanProtocolUndefined1,
+            0xA0 => VBanProtocol::VbanProtocolUndefined2,
+            0xC0 => VBanProtocol::VbanProtocolUndefined3,
+            _ => VBanProtocol::VbanProtocolUndefined4,
+        }
+    }
+}
+
+const VBAN_BIT_RESOLUTION_MASK : u8 = 0x07;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanBitResolution {
+    VbanBitfmt8Int = 0,
+    VbanBitfmt16Int,
+    VbanBitfmt24Int,
+    VbanBitfmt32Int,
+    VbanBitfmt32Float,
+    VbanBitfmt64Float,
+    VbanBitfmt12Int,
+    VbanBitfmt10Int,
+    VbanBitResolutionMax
+}
+
+impl TryFrom<u8> for VBanBitResolution {
+    type Error = VbanError;
+
+    fn try_from(item : u8) -> Result<Self, Self::Error> {
+        Ok(match  item & VBAN_BIT_RESOLUTION_MASK  {
+            0 => VBanBitResolution::VbanBitfmt8Int,
+            1 => VBanBitResolution::VbanBitfmt16Int,
+            2 => VBanBitResolution::VbanBitfmt24Int,
+            3 => VBanBitResolution::VbanBitfmt32Int,
+            4 => VBanBitResolution::VbanBitfmt32Float,
+            5 => VBanBitResolution::VbanBitfmt64Float,
+            6 => VBanBitResolution::VbanBitfmt12Int,
+            7 => VBanBitResolution::VbanBitfmt10Int,
+            _ => return Err(VbanError::InvalidBitResolution(item & VBAN_BIT_RESOLUTION_MASK)),
+        })
+    }
+}
+
+const VBAN_BIT_RESOLUTION_SIZE : [u8; 6] = [ 1, 2, 3, 4, 4, 8, ];
+
+impl VBanBitResolution {
+
+    /// Number of significant bits of a single sample
+    pub fn bits(&self) -> u8 {
+        match self {
+            VBanBitResolution::VbanBitfmt8Int => 8,
+            VBanBitResolution::VbanBitfmt16Int => 16,
+            VBanBitResolution::VbanBitfmt24Int => 24,
+            VBanBitResolution::VbanBitfmt32Int => 32,
+            VBanBitResolution::VbanBitfmt32Float => 32,
+            VBanBitResolution::VbanBitfmt64Float => 64,
+            VBanBitResolution::VbanBitfmt12Int => 12,
+            VBanBitResolution::VbanBitfmt10Int => 10,
+            VBanBitResolution::VbanBitResolutionMax => 0,
+        }
+    }
+
+    /// Decodes a packet payload into interleaved samples in the range [-1.0, 1.0].
+    /// The packed formats are expected to be little endian bit streams: 12 bit stores
+    /// two samples in three bytes, 10 bit stores three samples in four bytes.
+    pub fn decode(&self, data : &[u8]) -> Vec<f32> {
+        let width = VBAN_BIT_RESOLUTION_SIZE.get(*self as usize).copied().unwrap_or(1) as usize;
+        match self {
+            VBanBitResolution::VbanBitfmt8Int => data.iter().map(|b| *b as i8 as f32 / 128.0).collect(),
+            VBanBitResolution::VbanBitfmt16Int => data.chunks_exact(width).map(|s| LittleEndian::read_i16(s) as f32 / 32768.0).collect(),
+            VBanBitResolution::VbanBitfmt24Int => data.chunks_exact(width).map(|s| LittleEndian::read_i24(s) as f32 / 8388608.0).collect(),
+            VBanBitResolution::VbanBitfmt32Int => data.chunks_exact(width).map(|s| (LittleEndian::read_i32(s) as f64 / 2147483648.0) as f32).collect(),
+            VBanBitResolution::VbanBitfmt32Float => data.chunks_exact(width).map(LittleEndian::read_f32).collect(),
+            VBanBitResolution::VbanBitfmt64Float => data.chunks_exact(width).map(|s| LittleEndian::read_f64(s) as f32).collect(),
+            VBanBitResolution::VbanBitfmt12Int => data.chunks_exact(3).flat_map(|s| {
+                let word = LittleEndian::read_u24(s);
+                [sign_extend(word, 12), sign_extend(word >> 12, 12)]
+            }).map(|smp| smp as f32 / 2048.0).collect(),
+            VBanBitResolution::VbanBitfmt10Int => data.chunks_exact(4).flat_map(|s| {
+                let word = LittleEndian::read_u32(s);
+                [sign_extend(word, 10), sign_extend(word >> 10, 10), sign_extend(word >> 20, 10)]
+            }).map(|smp| smp as f32 / 512.0).collect(),
+            VBanBitResolution::VbanBitResolutionMax => Vec::new(),
+        }
+    }
+
+    /// Encodes interleaved samples in the range [-1.0, 1.0] into a packet payload, the
+    /// inverse of `decode`. Packed formats are padded with silence to a whole group.
+    pub fn encode(&self, samples : &[f32]) -> Vec<u8> {
+        let mut data = vec![0u8; self.payload_size(samples.len())];
+        let quantize = |smp : f32, max : f64| (smp.clamp(-1.0, 1.0) as f64 * max).round().clamp(-max - 1.0, max) as i32;
+        match self {
+            VBanBitResolution::VbanBitfmt8Int => {
+                for (out, smp) in data.iter_mut().zip(samples) {
+                    *out = quantize(*smp, 127.0) as i8 as u8;
+                }
+            },
+            VBanBitResolution::VbanBitfmt16Int => {
+                for (out, smp) in data.chunks_exact_mut(2).zip(samples) {
+                    LittleEndian::write_i16(out, quantize(*smp, 32767.0) as i16);
+                }
+            },
+            VBanBitResolution::VbanBitfmt24Int => {
+                for (out, smp) in data.chunks_exact_mut(3).zip(samples) {
+                    LittleEndian::write_i24(out, quantize(*smp, 8388607.0));
+                }
+            },
+            VBanBitResolution::VbanBitfmt32Int => {
+                for (out, smp) in data.chunks_exact_mut(4).zip(samples) {
+                    LittleEndian::write_i32(out, quantize(*smp, 2147483647.0));
+                }
+            },
+            VBanBitResolution::VbanBitfmt32Float => {
+                for (out, smp) in data.chunks_exact_mut(4).zip(samples) {
+                    LittleEndian::write_f32(out, *smp);
+                }
+            },
+            VBanBitResolution::VbanBitfmt64Float => {
+                for (out, smp) in data.chunks_exact_mut(8).zip(samples) {
+                    LittleEndian::write_f64(out, *smp as f64);
+                }
+            },
+            VBanBitResolution::VbanBitfmt12Int => {
+                for (out, pair) in data.chunks_exact_mut(3).zip(samples.chunks(2)) {
+                    let word = pair.iter().enumerate().fold(0u32, |word, (i, smp)| word | (quantize(*smp, 2047.0) as u32 & 0xFFF) << (12 * i));
+                    LittleEndian::write_u24(out, word);
+                }
+            },
+            VBanBitResolution::VbanBitfmt10Int => {
+                for (out, triple) in data.chunks_exact_mut(4).zip(samples.chunks(3)) {
+                    let word = triple.iter().enumerate().fold(0u32, |word, (i, smp)| word | (quantize(*smp, 511.0) as u32 & 0x3FF) << (10 * i));
+                    LittleEndian::write_u32(out, word);
+                }
+            },
+            VBanBitResolution::VbanBitResolutionMax => (),
+        }
+        data
+    }
+
+    /// Number of payload bytes needed for `num_samples` samples
+    pub fn payload_size(&self, num_samples : usize) -> usize {
+        match self {
+            VBanBitResolution::VbanBitfmt12Int => num_samples.div_ceil(2) * 3,
+            VBanBitResolution::VbanBitfmt10Int => num_samples.div_ceil(3) * 4,
+            _ => num_samples * VBAN_BIT_RESOLUTION_SIZE.get(*self as usize).copied().unwrap_or(0) as usize,
+        }
+    }
+}
+
+/// Stream names are zero padded and may be cut in the middle of a multi-byte character
+pub fn stream_name_str(name : &[u8; 16]) -> Cow<'_, str> {
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len])
+}
+
+pub(crate) fn sign_extend(value : u32, bits : u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// Converts a stream name to its zero padded form used in the packet header
+pub fn stream_name_bytes(name : &str) -> Result<[u8; 16], VbanError> {
+    if name.len() > VBAN_STREAM_NAME_SIZE {
+        return Err(VbanError::StreamNameTooLong(name.len()));
+    }
+    let mut bytes = [0u8; VBAN_STREAM_NAME_SIZE];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(bytes)
+}
+
+const VBAN_RESERVED_MASK : u8 = 0x08;
+const VBAN_CODEC_MASK : u8 = 0xF0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanCodec {
+    VbanCodecPcm              =   0x00,
+    VbanCodecVbca             =   0x10,
+    VbanCodecVbcv             =   0x20,
+    VbanCodecUndefined3      =   0x30,
+    VbanCodecUndefined4      =   0x40,
+    VbanCodecUndefined5      =   0x50,
+    VbanCodecUndefined6      =   0x60,
+    VbanCodecUndefined7      =   0x70,
+    VbanCodecUndefined8      =   0x80,
+    VbanCodecUndefined9      =   0x90,
+    VbanCodecUndefined10     =   0xA0,
+    VbanCodecUndefined11     =   0xB0,
+    VbanCodecUndefined12     =   0xC0,
+    VbanCodecUndefined13     =   0xD0,
+    VbanCodecUndefined14     =   0xE0,
+    VbanCodecUser             =   0xF0
+}
+
+impl From<u8> for VBanCodec {
+    fn from(value: u8) -> Self {
+        match value & VBAN_CODEC_MASK {
+            0x00 => VBanCodec::VbanCodecPcm,
+            0x10 => VBanCodec::VbanCodecVbca,
+            0x20 => VBanCodec::VbanCodecVbcv,
+            0x30 => VBanCodec::VbanCodecUndefined3,
+            0x40 => VBanCodec::VbanCodecUndefined4,
+            0x50 => VBanCodec::VbanCodecUndefined5,
+            0x60 => VBanCodec::VbanCodecUndefined6,
+            0x70 => VBanCodec::VbanCodecUndefined7,
+            0x80 => VBanCodec::VbanCodecUndefined8,
+            0x90 => VBanCodec::VbanCodecUndefined9,
+            0xA0 => VBanCodec::VbanCodecUndefined10,
+            0xB0 => VBanCodec::VbanCodecUndefined11,
+            0xC0 => VBanCodec::VbanCodecUndefined12,
+            0xD0 => VBanCodec::VbanCodecUndefined13,
+            0xE0 => VBanCodec::VbanCodecUndefined14,
+            0xF0 => VBanCodec::VbanCodecUser,
+            _ => VBanCodec::VbanCodecUser
+        }
+    }
+}
+
+/// Bit rates of serial and text packets, indexed like the sample rates of audio packets
+pub const VBAN_BPSLIST : [u32; 25] = [
+    0, 110, 150, 300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 31250, 38400, 57600, 115200,
+    128000, 230400, 250000, 256000, 460800, 921600, 1000000, 1500000, 2000000, 3000000,
+];
+
+const VBAN_SERIAL_STOP_BITS_MASK : u8 = 0x03;
+const VBAN_SERIAL_START_BIT : u8 = 0x04;
+const VBAN_SERIAL_PARITY : u8 = 0x08;
+const VBAN_SERIAL_MULTIPART : u8 = 0x80;
+
+/// What a serial packet carries, stored where audio packets keep their codec
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanSerialType {
+    Generic = 0x00,
+    Midi = 0x10,
+    User = 0xF0,
+}
+
+impl TryFrom<u8> for VBanSerialType {
+    type Error = VbanError;
+
+    fn try_from(item : u8) -> Result<Self, Self::Error> {
+        match item & VBAN_CODEC_MASK {
+            0x00 => Ok(VBanSerialType::Generic),
+            0x10 => Ok(VBanSerialType::Midi),
+            0xF0 => Ok(VBanSerialType::User),
+            kind => Err(VbanError::UnsupportedSerialType(kind)),
+        }
+    }
+}
+
+/// Line settings described by the header of a serial packet. They document the line the data
+/// came from, the payload is plain bytes either way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerialFormat {
+    /// Bit rate of the line, 0 if the sender did not set one
+    pub bps : u32,
+
+    /// 0 for one stop bit, 1 for one and a half, 2 for two
+    pub stop_bits : u8,
+
+    pub start_bit : bool,
+
+    pub parity : bool,
+
+    /// The payload is part of a larger block that continues in the next packet
+    pub multipart : bool,
+
+    /// Distinguishes several serial lines sent under the same stream name
+    pub channel : u8,
+
+    pub kind : VBanSerialType,
+}
+
+/// Encoding of a text packet, stored where audio packets keep their codec
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VBanTextFormat {
+    Ascii = 0x00,
+    Utf8 = 0x10,
+    /// UTF-16 in little endian, as Windows stores wide characters
+    Wchar = 0x20,
+}
+
+impl TryFrom<u8> for VBanTextFormat {
+    type Error = VbanError;
+
+    fn try_from(item : u8) -> Result<Self, Self::Error> {
+        match item & VBAN_CODEC_MASK {
+            0x00 => Ok(VBanTextFormat::Ascii),
+            0x10 => Ok(VBanTextFormat::Utf8),
+            0x20 => Ok(VBanTextFormat::Wchar),
+            format => Err(VbanError::UnsupportedTextFormat(format)),
+        }
+    }
+}
+
+impl VBanTextFormat {
+
+    /// Decodes a payload. Invalid characters are replaced and trailing NULs, which some
+    /// senders pad with, are dropped.
+    pub fn decode(&self, data : &[u8]) -> String {
+        let text = match self {
+            VBanTextFormat::Ascii => data.iter().map(|byte| if byte.is_ascii() { *byte as char } else { char::REPLACEMENT_CHARACTER }).collect(),
+            VBanTextFormat::Utf8 => String::from_utf8_lossy(data).into_owned(),
+            VBanTextFormat::Wchar => {
+                let units : Vec<u16> = data.chunks_exact(2).map(LittleEndian::read_u16).collect();
+                String::from_utf16_lossy(&units)
+            },
+        };
+        String::from(text.trim_end_matches('\0'))
+    }
+
+    /// Encodes text, characters ASCII can not represent become '?'
+    pub fn encode(&self, text : &str) -> Vec<u8> {
+        match self {
+            VBanTextFormat::Ascii => text.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }).collect(),
+            VBanTextFormat::Utf8 => text.as_bytes().to_vec(),
+            VBanTextFormat::Wchar => text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio_format() -> AudioFormat {
+        AudioFormat {
+            sample_rate : VBanSampleRates::SampleRate44100Hz,
+            num_samples : 3,
+            num_channels : 2,
+            bit_resolution : VBanBitResolution::VbanBitfmt16Int,
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let format = audio_format();
+        let head = VBanHeader::audio(stream_name_bytes("Stream1").unwrap(), &format, 0xDEADBEEF);
+        let payload = format.bit_resolution.encode(&[0.0, 0.5, -0.5, 0.25, -1.0, 1.0]);
+        let bytes = head.to_packet(&payload);
+        assert_eq!(bytes.len(), VBAN_PACKET_FULL_HEADER_BYTES + 12);
+
+        let packet = VbanPacket::parse(&bytes).unwrap();
+        assert_eq!(packet.header(), head);
+        assert_eq!(packet.payload(), payload.as_slice());
+        assert_eq!(packet.protocol(), VBanProtocol::VbanProtocolAudio);
+        assert_eq!(packet.stream_name_str(), "Stream1");
+        assert_eq!(packet.nu_frame(), 0xDEADBEEF);
+        assert_eq!(packet.audio_format().unwrap(), format);
+        assert_eq!(packet.header().to_packet(packet.payload()), bytes);
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let samples = [0.0, 0.5, -0.5, 0.125, -0.99, 0.75];
+        for bits in 0..8 {
+            let resolution = VBanBitResolution::try_from(bits).unwrap();
+            let data = resolution.encode(&samples);
+            assert_eq!(data.len(), resolution.payload_size(samples.len()));
+            let decoded = resolution.decode(&data);
+            /* Quantization plus the asymmetric scaling of the integer formats */
+            let step = 4.0 / (1u64 << resolution.bits().min(24)) as f32;
+            for (smp, out) in samples.iter().zip(decoded.iter()) {
+                assert!((smp - out).abs() <= step, "{resolution:?}: {smp} became {out}");
+            }
+        }
+    }
+
+    #[test]
+    fn packed_formats_are_padded() {
+        let data = VBanBitResolution::VbanBitfmt12Int.encode(&[0.5, 0.5, 0.5]);
+        assert_eq!(data.len(), 6);
+        assert_eq!(VBanBitResolution::VbanBitfmt12Int.decode(&data)[3], 0.0);
+
+        let data = VBanBitResolution::VbanBitfmt10Int.encode(&[0.5; 4]);
+        assert_eq!(data.len(), 8);
+        assert_eq!(VBanBitResolution::VbanBitfmt10Int.decode(&data)[4..], [0.0, 0.0]);
+    }
+
+    #[test]
+    fn sample_rates() {
+        for (index, hz) in VBAN_SRLIST.iter().enumerate() {
+            let sr = VBanSampleRates::from_hz(*hz).unwrap();
+            assert_eq!(sr as usize, index);
+            assert_eq!(sr.hz(), *hz);
+        }
+        assert!(matches!(VBanSampleRates::from_hz(44000), Err(VbanError::UnsupportedSampleRate(44000))));
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let head = VBanHeader::audio([0; 16], &audio_format(), 0);
+        let bytes = head.to_packet(&[0; 12]);
+
+        assert!(matches!(VbanPacket::parse(b"VBA"), Err(VbanError::NotVban)));
+        assert!(matches!(VbanPacket::parse(b"ABCD0000000000000000000000000000"), Err(VbanError::NotVban)));
+        assert!(matches!(VbanPacket::parse(&bytes[..20]), Err(VbanError::PacketTooShort(20))));
+        let largest = head.to_packet(&[0; VBAN_DATA_MAX_SIZE]);
+        assert_eq!(largest.len(), VBAN_PROTOCOL_MAX_SIZE);
+        assert!(VbanPacket::parse(&largest).is_ok());
+        assert!(matches!(VbanPacket::parse(&head.to_packet(&[0; VBAN_DATA_MAX_SIZE + 1])), Err(VbanError::PacketTooLong(_))));
+
+        let mut text = bytes.clone();
+        text[4] = VBanProtocol::VbanProtocolTxt as u8;
+        let packet = VbanPacket::parse(&text).unwrap();
+        assert!(matches!(packet.audio_format(), Err(VbanError::UnsupportedProtocol(0x40))));
+
+        let mut reserved = bytes.clone();
+        reserved[7] |= VBAN_RESERVED_MASK;
+        assert!(matches!(VbanPacket::parse(&reserved).unwrap().audio_format(), Err(VbanError::ReservedBitSet)));
+
+        let mut codec = bytes;
+        codec[7] |= VBanCodec::VbanCodecVbca as u8;
+        assert!(matches!(VbanPacket::parse(&codec).unwrap().audio_format(), Err(VbanError::UnsupportedCodec(0x10))));
+    }
+}
@@ -0,0 +1,194 @@
+use std::{cell::RefCell, fs::{File, OpenOptions}, io::Write, path::PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Deserialize;
+use crate::{error::VbanError, sink::VbanSink};
+
+/// Sample format of the raw PCM written by a `PipeSink`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PipeFormat {
+    #[default]
+    S16le,
+    S24le,
+    F32le,
+}
+
+impl std::str::FromStr for PipeFormat {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "s16le" => Ok(PipeFormat::S16le),
+            "s24le" => Ok(PipeFormat::S24le),
+            "f32le" => Ok(PipeFormat::F32le),
+            _ => Err(format!("unknown format {s} (expected s16le, s24le or f32le)")),
+        }
+    }
+}
+
+impl std::fmt::Display for PipeFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipeFormat::S16le => write!(f, "s16le"),
+            PipeFormat::S24le => write!(f, "s24le"),
+            PipeFormat::F32le => write!(f, "f32le"),
+        }
+    }
+}
+
+impl PipeFormat {
+
+    fn width(&self) -> usize {
+        match self {
+            PipeFormat::S16le => 2,
+            PipeFormat::S24le => 3,
+            PipeFormat::F32le => 4,
+        }
+    }
+
+    fn encode(&self, buf : &[f32]) -> Vec<u8> {
+        let width = self.width();
+        let mut bytes = vec![0u8; buf.len() * width];
+        for (smp, out) in buf.iter().zip(bytes.chunks_exact_mut(width)) {
+            let smp = smp.clamp(-1.0, 1.0);
+            match self {
+                PipeFormat::S16le => LittleEndian::write_i16(out, (smp * 32767.0).round() as i16),
+                PipeFormat::S24le => LittleEndian::write_i24(out, (smp as f64 * 8388607.0).round() as i32),
+                PipeFormat::F32le => LittleEndian::write_f32(out, smp),
+            }
+        }
+        bytes
+    }
+}
+
+/// Where a `PipeSink` writes to and in which format
+#[derive(Clone, Debug, Default)]
+pub struct PipeOptions {
+    /// A named pipe or file, stdout if None
+    pub path : Option<PathBuf>,
+
+    pub format : PipeFormat,
+}
+
+enum PipeTarget {
+    Stdout,
+    File(File),
+}
+
+/// Writes interleaved raw PCM to stdout or a named pipe, e.g. to feed ffmpeg, sox or Snapcast.
+/// Writes block while the reader is busy. If the reader goes away, the rest of the session is
+/// discarded.
+pub struct PipeSink {
+    format : PipeFormat,
+
+    sample_rate : u32,
+
+    target : RefCell<Option<PipeTarget>>,
+}
+
+impl PipeSink {
+
+    /// Opens the pipe. Opening a named pipe blocks until a reader opened it as well.
+    pub fn create(options : &PipeOptions, sample_rate : u32, num_channels : u16) -> Result<Self, VbanError> {
+        let target = match &options.path {
+            None => PipeTarget::Stdout,
+            Some(path) => PipeTarget::File(OpenOptions::new().write(true).create(true).truncate(true).open(path)?),
+        };
+        eprintln!("Writing {num_channels} channels {} at {sample_rate} Hz to {}.", options.format,
+            options.path.as_ref().map(|path| path.display().to_string()).unwrap_or(String::from("stdout")));
+        Ok(Self {
+            format : options.format,
+            sample_rate,
+            target : RefCell::new(Some(target)),
+        })
+    }
+}
+
+impl VbanSink for PipeSink {
+
+    fn write(&self, buf : &[f32]) {
+        let mut target = self.target.borrow_mut();
+        let bytes = self.format.encode(buf);
+        let result = match target.as_mut() {
+            None => return,
+            Some(PipeTarget::Stdout) => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&bytes).and_then(|()| stdout.flush())
+            },
+            Some(PipeTarget::File(file)) => file.write_all(&bytes),
+        };
+        if let Err(err) = result {
+            eprintln!("Writing to the pipe failed ({err}), discarding audio until the stream restarts.");
+            *target = None;
+        }
+    }
+
+    fn delay(&self) -> Option<usize> {
+        None
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `buf` through a `PipeSink` into a temporary file and returns the bytes that arrived
+    fn pipe(format : PipeFormat, buf : &[f32]) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("vban_sink_pipe_{}_{format}", std::process::id()));
+        let options = PipeOptions { path : Some(path.clone()), format };
+        let sink = PipeSink::create(&options, 44100, 2).unwrap();
+        assert_eq!(sink.sample_rate(), 44100);
+        sink.write(&buf[..2]);
+        sink.write(&buf[2..]);
+        drop(sink);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    const SAMPLES : [f32; 6] = [0.0, 0.5, -0.5, 1.0, -1.0, 2.0];
+
+    #[test]
+    fn writes_s16le() {
+        assert_eq!(pipe(PipeFormat::S16le, &SAMPLES), [
+            0x00, 0x00,
+            0x00, 0x40,
+            0x00, 0xC0,
+            0xFF, 0x7F,
+            0x01, 0x80,
+            0xFF, 0x7F,
+        ]);
+    }
+
+    #[test]
+    fn writes_s24le() {
+        assert_eq!(pipe(PipeFormat::S24le, &SAMPLES), [
+            0x00, 0x00, 0x00,
+            0x00, 0x00, 0x40,
+            0x00, 0x00, 0xC0,
+            0xFF, 0xFF, 0x7F,
+            0x01, 0x00, 0x80,
+            0xFF, 0xFF, 0x7F,
+        ]);
+    }
+
+    #[test]
+    fn writes_f32le() {
+        let bytes = pipe(PipeFormat::F32le, &SAMPLES);
+        assert_eq!(&bytes[4..8], &[0x00, 0x00, 0x00, 0x3F]);
+        let samples : Vec<f32> = bytes.chunks_exact(4).map(LittleEndian::read_f32).collect();
+        assert_eq!(samples, [0.0, 0.5, -0.5, 1.0, -1.0, 1.0]);
+    }
+
+    #[test]
+    fn parses_format_names() {
+        for format in [PipeFormat::S16le, PipeFormat::S24le, PipeFormat::F32le] {
+            assert_eq!(format.to_string().parse::<PipeFormat>(), Ok(format));
+        }
+        assert!("s32le".parse::<PipeFormat>().is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::{cell::RefCell, sync::mpsc, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use ::pipewire as pw;
+use pw::{properties::properties, spa};
+use rtrb::{Consumer, Producer, RingBuffer};
+use crate::{error::VbanError, sink::VbanSink};
+
+/// Longest time `drain` waits for PipeWire to play out the queue
+const PIPEWIRE_DRAIN_TIMEOUT : Duration = Duration::from_secs(2);
+
+/// The queue between the sink and the PipeWire process callback holds this much audio
+const PIPEWIRE_QUEUE_SECONDS : usize = 2;
+
+/// Size of the PipeWire processing period we ask for
+const PIPEWIRE_QUANTUM_MS : u32 = 10;
+
+/// Plays a stream as a node of its own in the PipeWire graph.
+///
+/// PipeWire pulls audio from a real time thread, so the sink runs a main loop on a thread of its
+/// own and hands the samples over through a lock free ring buffer. The node is called
+/// "VBAN: <stream name>" and has proper channel positions, so patchbays show it like any other
+/// application.
+pub struct PipeWireSink {
+    sample_rate : u32,
+
+    num_channels : usize,
+
+    producer : RefCell<Producer<f32>>,
+
+    quit : pw::channel::Sender<()>,
+
+    thread : Option<JoinHandle<()>>,
+}
+
+impl PipeWireSink {
+
+    /// Connects to the PipeWire daemon and creates the playback node. `target` is the name of a
+    /// node to connect to, PipeWire picks one if it is None.
+    pub fn create(stream_name : &str, target : Option<&str>, sample_rate : u32, num_channels : u16) -> Result<Self, VbanError> {
+        let (producer, consumer) = RingBuffer::new(sample_rate as usize * PIPEWIRE_QUEUE_SECONDS * num_channels as usize);
+        let (quit, quit_receiver) = pw::channel::channel();
+        let (ready, ready_receiver) = mpsc::channel();
+
+        let node_name = format!("VBAN: {stream_name}");
+        let target = target.map(String::from);
+        let thread = thread::spawn(move || {
+            if let Err(err) = run_loop(&node_name, target, sample_rate, num_channels, consumer, quit_receiver, &ready) {
+                let _ = ready.send(Err(err));
+            }
+        });
+
+        match ready_receiver.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                return Err(VbanError::PipeWire(err));
+            },
+            Err(_) => return Err(VbanError::PipeWire(pw::Error::CreationFailed)),
+        }
+        eprintln!("PipeWire node VBAN: {stream_name} created with {num_channels} channels at {sample_rate} Hz.");
+
+        Ok(Self {
+            sample_rate,
+            num_channels : num_channels as usize,
+            producer : RefCell::new(producer),
+            quit,
+            thread : Some(thread),
+        })
+    }
+}
+
+/// Runs the PipeWire main loop of a sink until `quit` receives a message
+fn run_loop(node_name : &str, target : Option<String>, sample_rate : u32, num_channels : u16, consumer : Consumer<f32>, quit : pw::channel::Receiver<()>, ready : &mpsc::Sender<Result<(), pw::Error>>) -> Result<(), pw::Error> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+
+    let mut props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::APP_NAME => "vban_sink",
+        *pw::keys::NODE_NAME => node_name,
+        *pw::keys::NODE_DESCRIPTION => node_name,
+        *pw::keys::MEDIA_NAME => node_name,
+        *pw::keys::NODE_LATENCY => format!("{}/{sample_rate}", sample_rate * PIPEWIRE_QUANTUM_MS / 1000),
+    };
+    if let Some(target) = &target {
+        props.insert(*pw::keys::TARGET_OBJECT, target.as_str());
+    }
+    let stream = pw::stream::Stream::new(&core, node_name, props)?;
+
+    let nch = num_channels as usize;
+    let _listener = stream
+        .add_local_listener_with_user_data(consumer)
+        .process(move |stream, consumer| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return;
+            };
+            let requested = buffer.requested() as usize;
+            let data = &mut buffer.datas_mut()[0];
+            let stride = nch * std::mem::size_of::<f32>();
+            let frames = match data.data() {
+                None => 0,
+                Some(slice) => {
+                    /* The mapped buffer may be larger than the cycle, 0 means no request */
+                    let frames = match requested {
+                        0 => slice.len() / stride,
+                        requested => requested.min(slice.len() / stride),
+                    };
+                    /* Only whole frames are taken, whatever is missing is played as silence */
+                    let available = (consumer.slots() / nch).min(frames);
+                    if let Ok(chunk) = consumer.read_chunk(available * nch) {
+                        for (out, smp) in slice.chunks_exact_mut(4).zip(chunk) {
+                            out.copy_from_slice(&smp.to_le_bytes());
+                        }
+                    }
+                    slice[available * stride..frames * stride].fill(0);
+                    frames
+                },
+            };
+            let chunk = data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = stride as _;
+            *chunk.size_mut() = (stride * frames) as _;
+        })
+        .register()?;
+
+    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
+    audio_info.set_rate(sample_rate);
+    audio_info.set_channels(num_channels as u32);
+    audio_info.set_position(channel_positions(num_channels));
+
+    let values : Vec<u8> = spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(spa::pod::Object {
+            type_ : spa::sys::SPA_TYPE_OBJECT_Format,
+            id : spa::sys::SPA_PARAM_EnumFormat,
+            properties : audio_info.into(),
+        }),
+    )
+    .map_err(|_| pw::Error::CreationFailed)?
+    .0
+    .into_inner();
+    let mut params = [spa::pod::Pod::from_bytes(&values).ok_or(pw::Error::CreationFailed)?];
+
+    stream.connect(
+        spa::utils::Direction::Output,
+        None,
+        pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS | pw::stream::StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+
+    let mainloop_quit = mainloop.clone();
+    let _quit = quit.attach(mainloop.loop_(), move |()| mainloop_quit.quit());
+
+    let _ = ready.send(Ok(()));
+    mainloop.run();
+    let _ = stream.disconnect();
+    Ok(())
+}
+
+/// Speaker positions of the usual layouts, AUX channels for anything else
+fn channel_positions(num_channels : u16) -> [u32; 64] {
+    use spa::sys::*;
+    let layout : &[u32] = match num_channels {
+        1 => &[SPA_AUDIO_CHANNEL_MONO],
+        2 => &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR],
+        3 => &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR, SPA_AUDIO_CHANNEL_LFE],
+        4 => &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR, SPA_AUDIO_CHANNEL_RL, SPA_AUDIO_CHANNEL_RR],
+        5 => &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR, SPA_AUDIO_CHANNEL_FC, SPA_AUDIO_CHANNEL_RL, SPA_AUDIO_CHANNEL_RR],
+        6 => &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR, SPA_AUDIO_CHANNEL_FC, SPA_AUDIO_CHANNEL_LFE, SPA_AUDIO_CHANNEL_RL, SPA_AUDIO_CHANNEL_RR],
+        8 => &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR, SPA_AUDIO_CHANNEL_FC, SPA_AUDIO_CHANNEL_LFE, SPA_AUDIO_CHANNEL_RL, SPA_AUDIO_CHANNEL_RR, SPA_AUDIO_CHANNEL_SL, SPA_AUDIO_CHANNEL_SR],
+        _ => &[],
+    };
+    let mut position = [0u32; 64];
+    for (ch, pos) in position.iter_mut().enumerate().take(num_channels as usize) {
+        *pos = layout.get(ch).copied().unwrap_or(SPA_AUDIO_CHANNEL_AUX0 + ch as u32);
+    }
+    position
+}
+
+impl VbanSink for PipeWireSink {
+
+    fn write(&self, buf : &[f32]) {
+        let mut producer = self.producer.borrow_mut();
+        /* Frames that do not fit are dropped whole, so the channels stay aligned */
+        let frames = (buf.len() / self.num_channels).min(producer.slots() / self.num_channels);
+        if let Ok(chunk) = producer.write_chunk_uninit(frames * self.num_channels) {
+            chunk.fill_from_iter(buf.iter().copied());
+        }
+    }
+
+    fn delay(&self) -> Option<usize> {
+        let producer = self.producer.borrow();
+        Some((producer.buffer().capacity() - producer.slots()) / self.num_channels)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn drain(&self) {
+        let start = Instant::now();
+        while self.delay().unwrap_or(0) > 0 && start.elapsed() < PIPEWIRE_DRAIN_TIMEOUT {
+            thread::sleep(Duration::from_millis(PIPEWIRE_QUANTUM_MS as u64));
+        }
+    }
+}
+
+impl Drop for PipeWireSink {
+    fn drop(&mut self) {
+        let _ = self.quit.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
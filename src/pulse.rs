@@ -0,0 +1,162 @@
+use std::{cell::Cell, ffi::{c_char, c_int, c_void, CStr, CString}, fmt, ptr};
+use crate::{error::VbanError, sink::VbanSink};
+
+/// Target latency of the PulseAudio buffer if the route does not set one
+pub const PULSE_DEFAULT_LATENCY_MS : u32 = 40;
+
+const PA_STREAM_PLAYBACK : c_int = 1;
+
+/// PA_SAMPLE_FLOAT32NE, samples are handed over in native byte order
+const PA_SAMPLE_FLOAT32NE : c_int = if cfg!(target_endian = "little") { 5 } else { 6 };
+
+/// PA_CHANNELS_MAX, streams with more channels are refused by the server
+const PA_CHANNELS_MAX : u16 = 32;
+
+/// Buffer attributes set to this are chosen by the server
+const PA_DEFAULT : u32 = u32::MAX;
+
+#[repr(C)]
+struct PaSampleSpec {
+    format : c_int,
+    rate : u32,
+    channels : u8,
+}
+
+#[repr(C)]
+struct PaBufferAttr {
+    maxlength : u32,
+    tlength : u32,
+    prebuf : u32,
+    minreq : u32,
+    fragsize : u32,
+}
+
+#[repr(C)]
+struct PaSimple {
+    _private : [u8; 0],
+}
+
+#[link(name = "pulse-simple")]
+#[link(name = "pulse")]
+extern "C" {
+    fn pa_simple_new(server : *const c_char, name : *const c_char, dir : c_int, dev : *const c_char, stream_name : *const c_char,
+        ss : *const PaSampleSpec, map : *const c_void, attr : *const PaBufferAttr, error : *mut c_int) -> *mut PaSimple;
+    fn pa_simple_write(s : *mut PaSimple, data : *const c_void, bytes : usize, error : *mut c_int) -> c_int;
+    fn pa_simple_drain(s : *mut PaSimple, error : *mut c_int) -> c_int;
+    fn pa_simple_get_latency(s : *mut PaSimple, error : *mut c_int) -> u64;
+    fn pa_simple_free(s : *mut PaSimple);
+    fn pa_strerror(error : c_int) -> *const c_char;
+}
+
+/// Error code of libpulse
+#[derive(Debug)]
+pub struct PulseError(i32);
+
+impl fmt::Display for PulseError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = unsafe { pa_strerror(self.0) };
+        match msg.is_null() {
+            true => write!(f, "error {}", self.0),
+            false => write!(f, "{}", unsafe { CStr::from_ptr(msg) }.to_string_lossy()),
+        }
+    }
+}
+
+impl std::error::Error for PulseError {}
+
+/// Plays a stream through the PulseAudio server, with a stream named after the VBAN stream.
+///
+/// The server buffer is kept at the latency of the route instead of what the ALSA plugin of
+/// PulseAudio would pick. Writes block while the buffer is full, like with `AlsaSink`.
+pub struct PulseSink {
+    simple : *mut PaSimple,
+
+    sample_rate : u32,
+
+    /// Set once a write failed, further audio is discarded until the stream restarts
+    failed : Cell<bool>,
+}
+
+impl PulseSink {
+
+    /// Connects to the server and opens a playback stream. `device` is the name of a sink, the
+    /// server default if None.
+    pub fn create(stream_name : &str, device : Option<&str>, sample_rate : u32, num_channels : u16, latency_ms : u32) -> Result<Self, VbanError> {
+        if num_channels > PA_CHANNELS_MAX {
+            return Err(VbanError::UnsupportedChannelCount(num_channels));
+        }
+        let name = CString::new(format!("VBAN {stream_name}")).unwrap_or_default();
+        let media_name = CString::new(format!("VBAN: {stream_name}")).unwrap_or_default();
+        let device = device.map(|dev| CString::new(dev).unwrap_or_default());
+        let spec = PaSampleSpec {
+            format : PA_SAMPLE_FLOAT32NE,
+            rate : sample_rate,
+            channels : num_channels as u8,
+        };
+        let frame_bytes = num_channels as u32 * std::mem::size_of::<f32>() as u32;
+        let attr = PaBufferAttr {
+            maxlength : PA_DEFAULT,
+            tlength : sample_rate * latency_ms / 1000 * frame_bytes,
+            prebuf : PA_DEFAULT,
+            minreq : PA_DEFAULT,
+            fragsize : PA_DEFAULT,
+        };
+
+        let mut error : c_int = 0;
+        let simple = unsafe {
+            pa_simple_new(ptr::null(), name.as_ptr(), PA_STREAM_PLAYBACK, device.as_ref().map_or(ptr::null(), |dev| dev.as_ptr()),
+                media_name.as_ptr(), &spec, ptr::null(), &attr, &mut error)
+        };
+        if simple.is_null() {
+            return Err(VbanError::PulseAudio(PulseError(error)));
+        }
+        eprintln!("PulseAudio stream VBAN: {stream_name} opened with {num_channels} channels at {sample_rate} Hz, {latency_ms} ms latency.");
+
+        Ok(Self {
+            simple,
+            sample_rate,
+            failed : Cell::new(false),
+        })
+    }
+}
+
+impl VbanSink for PulseSink {
+
+    fn write(&self, buf : &[f32]) {
+        if self.failed.get() {
+            return;
+        }
+        let mut error : c_int = 0;
+        let result = unsafe { pa_simple_write(self.simple, buf.as_ptr() as *const c_void, std::mem::size_of_val(buf), &mut error) };
+        if result < 0 {
+            eprintln!("PulseAudio write failed ({}), discarding audio until the stream restarts.", PulseError(error));
+            self.failed.set(true);
+        }
+    }
+
+    fn delay(&self) -> Option<usize> {
+        let mut error : c_int = 0;
+        let usec = unsafe { pa_simple_get_latency(self.simple, &mut error) };
+        match usec == u64::MAX {
+            true => None,
+            false => Some((usec * self.sample_rate as u64 / 1_000_000) as usize),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn drain(&self) {
+        let mut error : c_int = 0;
+        if unsafe { pa_simple_drain(self.simple, &mut error) } < 0 {
+            eprintln!("PulseAudio drain failed: {}", PulseError(error));
+        }
+    }
+}
+
+impl Drop for PulseSink {
+    fn drop(&mut self) {
+        unsafe { pa_simple_free(self.simple) };
+    }
+}
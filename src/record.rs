@@ -0,0 +1,56 @@
+use std::{path::PathBuf, time::Duration};
+use chrono::Local;
+
+/// Where streams are recorded to and when a new file is started
+#[derive(Clone, Debug)]
+pub struct RecordOptions {
+    pub directory : PathBuf,
+
+    /// Start a new file after this much audio
+    pub max_duration : Option<Duration>,
+
+    /// Start a new file before one grows beyond this many bytes
+    pub max_size : Option<u64>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        Self {
+            directory : PathBuf::from("."),
+            max_duration : None,
+            max_size : None,
+        }
+    }
+}
+
+impl RecordOptions {
+
+    /// Path for a new recording of `stream_name`, named after the current local time.
+    /// A counter is appended if a file of that name exists already.
+    pub fn file_path(&self, stream_name : &str, extension : &str) -> PathBuf {
+        let name : String = stream_name.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let name = if name.is_empty() { String::from("vban") } else { name };
+        let stem = format!("{name}_{}", Local::now().format("%Y-%m-%d_%H-%M-%S"));
+
+        let mut path = self.directory.join(format!("{stem}.{extension}"));
+        let mut count = 1;
+        while path.exists() {
+            path = self.directory.join(format!("{stem}_{count}.{extension}"));
+            count += 1;
+        }
+        path
+    }
+
+    /// Frames that still fit into a file holding `frames` frames in `bytes` bytes, None if
+    /// there is no limit
+    pub fn frames_left(&self, frames : u64, sample_rate : u32, bytes : u64, frame_bytes : u64) -> Option<u64> {
+        let by_duration = self.max_duration.map(|max| ((max.as_secs_f64() * sample_rate as f64) as u64).saturating_sub(frames));
+        let by_size = self.max_size.map(|max| max.saturating_sub(bytes) / frame_bytes.max(1));
+        match (by_duration, by_size) {
+            (Some(duration), Some(size)) => Some(duration.min(size)),
+            (duration, size) => duration.or(size),
+        }
+    }
+}
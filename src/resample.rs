@@ -0,0 +1,205 @@
+use std::f64::consts::PI;
+
+/// Number of input frames on each side of the interpolation point
+const RESAMPLER_HALF_TAPS : usize = 16;
+
+/// Number of precomputed filter phases between two input frames
+const RESAMPLER_PHASES : usize = 256;
+
+/// Ratio changes that move the cutoff by less than this reuse the existing filter table
+const RESAMPLER_CUTOFF_TOLERANCE : f64 = 0.01;
+
+/// Variable ratio resampler for interleaved audio.
+///
+/// Interpolates with a Blackman windowed sinc of 2 * 16 taps. The filter is tabulated in 256
+/// phases and linearly interpolated in between, so the ratio can change on every call without
+/// glitches. When downsampling the cutoff follows the ratio to suppress aliasing.
+pub struct Resampler {
+    num_channels : usize,
+
+    /// Output rate divided by input rate
+    ratio : f64,
+
+    cutoff : f64,
+
+    table : Vec<f32>,
+
+    /// Interleaved input frames that are still needed for interpolation
+    history : Vec<f32>,
+
+    /// Read position in `history` in frames
+    position : f64,
+}
+
+impl Resampler {
+
+    pub fn new(num_channels : usize, ratio : f64) -> Self {
+        let num_channels = num_channels.max(1);
+        let cutoff = Self::cutoff_for(ratio);
+        Self {
+            num_channels,
+            ratio,
+            cutoff,
+            table : Self::build_table(cutoff),
+            history : vec![0.0; RESAMPLER_HALF_TAPS * num_channels],
+            position : RESAMPLER_HALF_TAPS as f64,
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    pub fn set_ratio(&mut self, ratio : f64) {
+        self.ratio = ratio;
+        let cutoff = Self::cutoff_for(ratio);
+        if (cutoff - self.cutoff).abs() > RESAMPLER_CUTOFF_TOLERANCE {
+            self.cutoff = cutoff;
+            self.table = Self::build_table(cutoff);
+        }
+    }
+
+    /// Number of input frames buffered inside the resampler that have not been played yet
+    pub fn latency(&self) -> usize {
+        (self.history.len() / self.num_channels).saturating_sub(self.position as usize)
+    }
+
+    /// Resamples interleaved input. Output is produced as far as the input allows, the rest is
+    /// kept for the next call.
+    pub fn process(&mut self, input : &[f32]) -> Vec<f32> {
+        let nch = self.num_channels;
+        let taps = 2 * RESAMPLER_HALF_TAPS;
+        self.history.extend_from_slice(input);
+
+        let frames = self.history.len() / nch;
+        let step = 1.0 / self.ratio;
+        let mut output = Vec::with_capacity(((input.len() / nch) as f64 * self.ratio) as usize * nch + nch);
+        let mut coeffs = [0f32; 2 * RESAMPLER_HALF_TAPS];
+
+        while (self.position as usize) + RESAMPLER_HALF_TAPS < frames {
+            let idx = self.position as usize;
+            let phase = (self.position - idx as f64) * RESAMPLER_PHASES as f64;
+            let p0 = phase as usize;
+            let weight = (phase - p0 as f64) as f32;
+
+            let row0 = &self.table[p0 * taps..(p0 + 1) * taps];
+            let row1 = &self.table[(p0 + 1) * taps..(p0 + 2) * taps];
+            for (k, coeff) in coeffs.iter_mut().enumerate() {
+                *coeff = row0[k] + (row1[k] - row0[k]) * weight;
+            }
+
+            let first = idx + 1 - RESAMPLER_HALF_TAPS;
+            for ch in 0..nch {
+                let mut acc = 0f32;
+                for (k, coeff) in coeffs.iter().enumerate() {
+                    acc += self.history[(first + k) * nch + ch] * coeff;
+                }
+                output.push(acc);
+            }
+            self.position += step;
+        }
+
+        /* Drop the frames that are no longer needed as left context */
+        let consumed = (self.position as usize + 1).saturating_sub(RESAMPLER_HALF_TAPS).min(frames);
+        self.history.drain(..consumed * nch);
+        self.position -= consumed as f64;
+
+        output
+    }
+
+    /// Forgets all buffered input
+    pub fn reset(&mut self) {
+        self.history = vec![0.0; RESAMPLER_HALF_TAPS * self.num_channels];
+        self.position = RESAMPLER_HALF_TAPS as f64;
+    }
+
+    fn cutoff_for(ratio : f64) -> f64 {
+        ratio.min(1.0) * 0.97
+    }
+
+    /// Row p holds the taps for a read position p / RESAMPLER_PHASES past an input frame. One
+    /// extra row allows interpolating between the last phase and the next frame.
+    fn build_table(cutoff : f64) -> Vec<f32> {
+        let taps = 2 * RESAMPLER_HALF_TAPS;
+        let half = RESAMPLER_HALF_TAPS as f64;
+        let mut table = Vec::with_capacity((RESAMPLER_PHASES + 1) * taps);
+
+        for p in 0..=RESAMPLER_PHASES {
+            let frac = p as f64 / RESAMPLER_PHASES as f64;
+            for k in 0..taps {
+                let t = k as f64 - (half - 1.0) - frac;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * cutoff * t).sin() / (PI * cutoff * t) };
+                let window = if t.abs() >= half { 0.0 } else { 0.42 + 0.5 * (PI * t / half).cos() + 0.08 * (2.0 * PI * t / half).cos() };
+                table.push((cutoff * sinc * window) as f32);
+            }
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frames : usize, period : f64) -> Vec<f32> {
+        (0..frames).map(|n| (2.0 * PI * n as f64 / period).sin() as f32 * 0.5).collect()
+    }
+
+    #[test]
+    fn keeps_dc_level() {
+        for ratio in [0.5, 0.9, 1.0, 1.0001, 1.5, 2.0] {
+            let mut resampler = Resampler::new(1, ratio);
+            let output = resampler.process(&vec![0.5; 4096]);
+            /* The start ramps up from the silent history */
+            for smp in &output[output.len() / 2..] {
+                assert!((smp - 0.5).abs() < 1e-3, "ratio {ratio}: {smp}");
+            }
+        }
+    }
+
+    #[test]
+    fn output_follows_ratio() {
+        for ratio in [0.5, 44100.0 / 48000.0, 1.0, 48000.0 / 44100.0, 2.0] {
+            let mut resampler = Resampler::new(2, ratio);
+            let mut frames = 0;
+            for _ in 0..100 {
+                frames += resampler.process(&vec![0.0; 256 * 2]).len() / 2;
+            }
+            let expected = 100.0 * 256.0 * ratio;
+            assert!((frames as f64 - expected).abs() <= (RESAMPLER_HALF_TAPS as f64 + 1.0) * ratio, "ratio {ratio}: {frames} frames, expected {expected}");
+        }
+    }
+
+    #[test]
+    fn continuous_across_calls() {
+        let input = sine(4000, 97.3);
+        let mut whole = Resampler::new(1, 1.1);
+        let expected = whole.process(&input);
+
+        let mut pieces = Resampler::new(1, 1.1);
+        let mut output = Vec::new();
+        let mut rest = input.as_slice();
+        for size in [1, 7, 128, 300, 3, 256].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (head, tail) = rest.split_at((*size).min(rest.len()));
+            output.extend(pieces.process(head));
+            rest = tail;
+        }
+        assert_eq!(output.len(), expected.len());
+        for (a, b) in output.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let mut resampler = Resampler::new(2, 0.75);
+        let input : Vec<f32> = (0..2048).flat_map(|_| [0.25, -0.5]).collect();
+        let output = resampler.process(&input);
+        for frame in output.chunks(2).skip(output.len() / 4) {
+            assert!((frame[0] - 0.25).abs() < 1e-3 && (frame[1] + 0.5).abs() < 1e-3, "{frame:?}");
+        }
+    }
+}
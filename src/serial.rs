@@ -0,0 +1,264 @@
+use std::{ffi::CStr, fs::{self, File, OpenOptions}, io::{ErrorKind, Write}, os::{fd::FromRawFd, unix::fs::OpenOptionsExt}, path::{Path, PathBuf}};
+use alsa::{rawmidi::Rawmidi, seq::{Addr, MidiEvent, PortCap, PortSubscribe, PortType, Seq}, Direction};
+use serde::Deserialize;
+use crate::error::VbanError;
+
+/// Size of the buffer the sequencer encoder assembles system exclusive messages in
+const SEQ_ENCODER_BUFFER : u32 = 1024;
+
+/// Destination of the payload of serial packets
+pub trait SerialSink {
+    fn write(&mut self, data : &[u8]);
+}
+
+/// Where MIDI received over VBAN is played. The textual form is `seq` for a sequencer port
+/// others can subscribe to, `seq:<client>:<port>` to connect that port right away, or the name
+/// of a rawmidi device like `hw:1,0,0`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum MidiOutput {
+    RawMidi(String),
+
+    Seq(Option<Addr>),
+}
+
+impl std::str::FromStr for MidiOutput {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("seq") {
+            Some("") => Ok(MidiOutput::Seq(None)),
+            Some(target) if target.starts_with(':') => target[1..].parse::<Addr>()
+                .map(|addr| MidiOutput::Seq(Some(addr)))
+                .map_err(|_| format!("invalid sequencer port {} (expected client:port)", &target[1..])),
+            _ => Ok(MidiOutput::RawMidi(String::from(s))),
+        }
+    }
+}
+
+impl TryFrom<String> for MidiOutput {
+    type Error = String;
+
+    fn try_from(s : String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl MidiOutput {
+
+    /// Opens the output
+    pub fn open(&self) -> Result<Box<dyn SerialSink>, VbanError> {
+        match self {
+            MidiOutput::RawMidi(device) => Ok(Box::new(RawMidiSink::open(device)?)),
+            MidiOutput::Seq(target) => Ok(Box::new(SeqMidiSink::open(*target)?)),
+        }
+    }
+}
+
+/// Writes MIDI bytes unchanged to a rawmidi device
+pub struct RawMidiSink {
+    rawmidi : Rawmidi,
+}
+
+impl RawMidiSink {
+
+    pub fn open(device : &str) -> Result<Self, VbanError> {
+        let rawmidi = Rawmidi::new(device, Direction::Playback, false)?;
+        eprintln!("Playing MIDI on {device}.");
+        Ok(Self {
+            rawmidi,
+        })
+    }
+}
+
+impl SerialSink for RawMidiSink {
+    fn write(&mut self, data : &[u8]) {
+        if let Err(err) = self.rawmidi.io().write_all(data) {
+            eprintln!("MIDI write failed: {err}");
+        }
+    }
+}
+
+/// Turns MIDI bytes into events of an ALSA sequencer port. Messages may span packets, the
+/// encoder keeps running status and partial messages until the rest arrives.
+pub struct SeqMidiSink {
+    seq : Seq,
+
+    port : i32,
+
+    encoder : MidiEvent,
+}
+
+impl SeqMidiSink {
+
+    /// Creates the port "VBAN MIDI" of client "vban_sink" and connects it to `target`
+    pub fn open(target : Option<Addr>) -> Result<Self, VbanError> {
+        let seq = Seq::open(None, Some(Direction::Playback), false)?;
+        seq.set_client_name(c"vban_sink")?;
+        let port = seq.create_simple_port(c"VBAN MIDI", PortCap::READ | PortCap::SUBS_READ, PortType::MIDI_GENERIC | PortType::APPLICATION)?;
+        let client = seq.client_id()?;
+        if let Some(target) = target {
+            let subscription = PortSubscribe::empty()?;
+            subscription.set_sender(Addr { client, port });
+            subscription.set_dest(target);
+            seq.subscribe_port(&subscription)?;
+        }
+        eprintln!("Playing MIDI on sequencer port {client}:{port}{}.", target.map(|addr| format!(", connected to {}:{}", addr.client, addr.port)).unwrap_or_default());
+        Ok(Self {
+            seq,
+            port,
+            encoder : MidiEvent::new(SEQ_ENCODER_BUFFER)?,
+        })
+    }
+}
+
+impl SerialSink for SeqMidiSink {
+    fn write(&mut self, data : &[u8]) {
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (used, event) = match self.encoder.encode(rest) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("Discarding MIDI data: {err}");
+                    self.encoder.reset_encode();
+                    return;
+                },
+            };
+            if let Some(mut event) = event {
+                event.set_source(self.port);
+                event.set_subs();
+                event.set_direct();
+                if let Err(err) = self.seq.event_output_direct(&mut event) {
+                    eprintln!("MIDI event output failed: {err}");
+                }
+            }
+            if used == 0 {
+                break;
+            }
+            rest = &rest[used..];
+        }
+    }
+}
+
+/// Makes serial data available on a pseudo terminal, so programs that expect a serial port can
+/// read it. The terminal is in raw mode. While nobody reads, data queues up in the terminal
+/// until its buffer is full, after that it is dropped.
+pub struct PtySink {
+    master : File,
+
+    /// Kept open, so the terminal does not hang up between readers
+    _slave : File,
+
+    link : Option<PathBuf>,
+}
+
+impl PtySink {
+
+    /// Creates the terminal and, if `link` is given, a symlink to it at that path
+    pub fn create(link : Option<&Path>) -> Result<Self, VbanError> {
+        let (master, path) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let master = File::from_raw_fd(fd);
+            let mut name = [0 as libc::c_char; 128];
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let mut termios : libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(fd, libc::TCSANOW, &termios);
+            }
+            (master, PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()))
+        };
+        let slave = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(&path)?;
+
+        if let Some(link) = link {
+            /* Only an old link is replaced, never a regular file */
+            if link.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink()) {
+                fs::remove_file(link)?;
+            }
+            std::os::unix::fs::symlink(&path, link)?;
+        }
+        eprintln!("Serial data goes to {}{}.", path.display(), link.map(|link| format!(" (linked as {})", link.display())).unwrap_or_default());
+
+        Ok(Self {
+            master,
+            _slave : slave,
+            link : link.map(PathBuf::from),
+        })
+    }
+}
+
+impl SerialSink for PtySink {
+    fn write(&mut self, data : &[u8]) {
+        match self.master.write_all(data) {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(err) => eprintln!("Serial write failed: {err}"),
+        }
+    }
+}
+
+impl Drop for PtySink {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = fs::remove_file(link);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::packet::{stream_name_bytes, SerialFormat, VBanHeader, VBanProtocol, VBanSerialType, VbanPacket};
+
+    #[test]
+    fn serial_round_trip() {
+        let format = SerialFormat {
+            bps : 31250,
+            stop_bits : 0,
+            start_bit : true,
+            parity : false,
+            multipart : false,
+            channel : 3,
+            kind : VBanSerialType::Midi,
+        };
+        let head = VBanHeader::serial(stream_name_bytes("MIDI1").unwrap(), &format, 12);
+        let bytes = head.to_packet(&[0x90, 0x3C, 0x7F]);
+        let packet = VbanPacket::parse(&bytes).unwrap();
+        assert_eq!(packet.protocol(), VBanProtocol::VbanProtocolSerial);
+        assert_eq!(packet.header().sample_rate, 0x20 | 11);
+        assert_eq!(packet.serial_format().unwrap(), format);
+        assert_eq!(packet.payload(), [0x90, 0x3C, 0x7F]);
+
+        let mut video = bytes.clone();
+        video[7] = 0x20;
+        assert!(matches!(VbanPacket::parse(&video).unwrap().serial_format(), Err(VbanError::UnsupportedSerialType(0x20))));
+    }
+
+    #[test]
+    fn midi_outputs() {
+        assert_eq!("seq".parse::<MidiOutput>().unwrap(), MidiOutput::Seq(None));
+        assert_eq!("seq:128:0".parse::<MidiOutput>().unwrap(), MidiOutput::Seq(Some(Addr { client : 128, port : 0 })));
+        assert_eq!("hw:1,0,0".parse::<MidiOutput>().unwrap(), MidiOutput::RawMidi(String::from("hw:1,0,0")));
+        assert!("seq:128".parse::<MidiOutput>().is_err());
+    }
+
+    #[test]
+    fn pty_passes_data_through() {
+        let link = std::env::temp_dir().join(format!("vban_sink_pty_{}", std::process::id()));
+        let mut sink = PtySink::create(Some(&link)).unwrap();
+        let mut reader = File::open(&link).unwrap();
+        sink.write(&[0x90, 0x3C, 0x7F]);
+        let mut received = [0u8; 3];
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0x90, 0x3C, 0x7F]);
+
+        drop(sink);
+        assert!(link.symlink_metadata().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! VBAN service packets: PING0 requests and the identification replies to them. Like `packet`
+//! this does not depend on ALSA.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crate::{error::VbanError, packet::{stream_name_bytes, VBanHeader, VbanPacket, VBAN_SRLIST}};
+
+/// Service type of identification requests and replies
+pub const VBAN_SERVICE_IDENTIFICATION : u8 = 0;
+
+pub const VBAN_SERVICE_FNCT_PING0 : u8 = 0;
+
+/// Set in the function of a service packet that answers a request
+pub const VBAN_SERVICE_FNCT_REPLY : u8 = 0x80;
+
+/// Size of the payload of a PING0 packet
+pub const VBAN_PING0_SIZE : usize = 676;
+
+/// Device types of `Ping0::device_type`
+pub const VBAN_PINGTYPE_RECEPTOR : u32 = 0x0000_0001;
+pub const VBAN_PINGTYPE_TRANSMITTER : u32 = 0x0000_0002;
+
+/// Features of `Ping0::features`
+pub const VBAN_PINGFEATURE_AUDIO : u32 = 0x0000_0001;
+pub const VBAN_PINGFEATURE_SERIAL : u32 = 0x0000_0100;
+pub const VBAN_PINGFEATURE_MIDI : u32 = 0x0000_0200;
+pub const VBAN_PINGFEATURE_TXT : u32 = 0x0001_0000;
+
+/// Stream name of the service packets Voicemeeter sends
+const VBAN_SERVICE_STREAM_NAME : &str = "VBAN Service";
+
+/// Identification of a device, the payload of a PING0 reply. Text fields longer than their slot
+/// in the packet are cut.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ping0 {
+    /// `VBAN_PINGTYPE_*` bits
+    pub device_type : u32,
+
+    /// `VBAN_PINGFEATURE_*` bits
+    pub features : u32,
+
+    pub features_ex : u32,
+
+    pub preferred_rate : u32,
+
+    pub min_rate : u32,
+
+    pub max_rate : u32,
+
+    /// Color the device is shown with, 0xRRGGBB
+    pub color_rgb : u32,
+
+    pub version : [u8; 4],
+
+    pub gps_position : String,
+
+    pub user_position : String,
+
+    /// Main language of the user, e.g. "EN"
+    pub lang_code : String,
+
+    /// Address of the device this one is connected to
+    pub distant_ip : String,
+
+    pub distant_port : u16,
+
+    /// Physical device, e.g. the sound card
+    pub device_name : String,
+
+    pub manufacturer_name : String,
+
+    pub application_name : String,
+
+    pub host_name : String,
+
+    pub user_name : String,
+
+    pub user_comment : String,
+}
+
+/// Offsets and sizes of the text fields of a PING0 payload
+const PING0_GPS_POSITION : (usize, usize) = (32, 8);
+const PING0_USER_POSITION : (usize, usize) = (40, 8);
+const PING0_LANG_CODE : (usize, usize) = (48, 8);
+const PING0_DISTANT_IP : (usize, usize) = (128, 32);
+const PING0_DISTANT_PORT : usize = 160;
+const PING0_DEVICE_NAME : (usize, usize) = (164, 64);
+const PING0_MANUFACTURER_NAME : (usize, usize) = (228, 64);
+const PING0_APPLICATION_NAME : (usize, usize) = (292, 64);
+const PING0_HOST_NAME : (usize, usize) = (356, 64);
+const PING0_USER_NAME : (usize, usize) = (420, 128);
+const PING0_USER_COMMENT : (usize, usize) = (548, 128);
+
+impl Ping0 {
+
+    /// Identification of this program as a receiver, with the rates VBAN allows and the host name
+    pub fn receptor() -> Self {
+        let version : Vec<u8> = env!("CARGO_PKG_VERSION").split('.').map(|part| part.parse().unwrap_or(0)).collect();
+        Self {
+            device_type : VBAN_PINGTYPE_RECEPTOR,
+            features : VBAN_PINGFEATURE_AUDIO,
+            preferred_rate : 48000,
+            min_rate : VBAN_SRLIST.iter().copied().min().unwrap_or(0),
+            max_rate : VBAN_SRLIST.iter().copied().max().unwrap_or(0),
+            version : std::array::from_fn(|n| version.get(n).copied().unwrap_or(0)),
+            lang_code : String::from("EN"),
+            application_name : String::from(env!("CARGO_PKG_NAME")),
+            host_name : host_name(),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; VBAN_PING0_SIZE];
+        for (n, value) in [self.device_type, self.features, self.features_ex, self.preferred_rate, self.min_rate, self.max_rate, self.color_rgb].iter().enumerate() {
+            LittleEndian::write_u32(&mut data[n * 4..], *value);
+        }
+        data[28..32].copy_from_slice(&self.version);
+        for (field, text) in [
+            (PING0_GPS_POSITION, &self.gps_position),
+            (PING0_USER_POSITION, &self.user_position),
+            (PING0_LANG_CODE, &self.lang_code),
+            (PING0_DISTANT_IP, &self.distant_ip),
+            (PING0_DEVICE_NAME, &self.device_name),
+            (PING0_MANUFACTURER_NAME, &self.manufacturer_name),
+            (PING0_APPLICATION_NAME, &self.application_name),
+            (PING0_HOST_NAME, &self.host_name),
+            (PING0_USER_NAME, &self.user_name),
+            (PING0_USER_COMMENT, &self.user_comment),
+        ] {
+            write_text(&mut data, field, text);
+        }
+        LittleEndian::write_u16(&mut data[PING0_DISTANT_PORT..], self.distant_port);
+        data
+    }
+
+    pub fn parse(data : &[u8]) -> Result<Self, VbanError> {
+        if data.len() < VBAN_PING0_SIZE {
+            return Err(VbanError::PacketTooShort(data.len()));
+        }
+        let word = |n : usize| LittleEndian::read_u32(&data[n * 4..]);
+        Ok(Self {
+            device_type : word(0),
+            features : word(1),
+            features_ex : word(2),
+            preferred_rate : word(3),
+            min_rate : word(4),
+            max_rate : word(5),
+            color_rgb : word(6),
+            version : data[28..32].try_into().unwrap(),
+            gps_position : read_text(data, PING0_GPS_POSITION),
+            user_position : read_text(data, PING0_USER_POSITION),
+            lang_code : read_text(data, PING0_LANG_CODE),
+            distant_ip : read_text(data, PING0_DISTANT_IP),
+            distant_port : LittleEndian::read_u16(&data[PING0_DISTANT_PORT..]),
+            device_name : read_text(data, PING0_DEVICE_NAME),
+            manufacturer_name : read_text(data, PING0_MANUFACTURER_NAME),
+            application_name : read_text(data, PING0_APPLICATION_NAME),
+            host_name : read_text(data, PING0_HOST_NAME),
+            user_name : read_text(data, PING0_USER_NAME),
+            user_comment : read_text(data, PING0_USER_COMMENT),
+        })
+    }
+}
+
+/// Copies text into a zero padded slot, cut at a character boundary so a NUL always follows
+fn write_text(data : &mut [u8], (offset, size) : (usize, usize), text : &str) {
+    let mut len = text.len().min(size - 1);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    data[offset..offset + len].copy_from_slice(&text.as_bytes()[..len]);
+}
+
+fn read_text(data : &[u8], (offset, size) : (usize, usize)) -> String {
+    let slot = &data[offset..offset + size];
+    let end = slot.iter().position(|byte| *byte == 0).unwrap_or(size);
+    String::from_utf8_lossy(&slot[..end]).into_owned()
+}
+
+/// Host name of the machine, empty if it can not be found out
+pub fn host_name() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|name| String::from(name.trim()))
+        .unwrap_or_default()
+}
+
+/// True if `packet` asks for a PING0 reply
+pub fn is_ping_request(packet : &VbanPacket) -> bool {
+    packet.service().is_ok_and(|(service, function)| service == VBAN_SERVICE_IDENTIFICATION && function == VBAN_SERVICE_FNCT_PING0)
+}
+
+/// A PING0 request, as broadcast to find devices
+pub fn ping_request(nu_frame : u32) -> Vec<u8> {
+    let name = stream_name_bytes(VBAN_SERVICE_STREAM_NAME).unwrap();
+    VBanHeader::service(name, VBAN_SERVICE_IDENTIFICATION, VBAN_SERVICE_FNCT_PING0, nu_frame).to_packet(&[0; VBAN_PING0_SIZE])
+}
+
+/// The reply to the PING0 request `request`, with the stream name and frame counter of the request
+pub fn ping_reply(request : &VbanPacket, info : &Ping0) -> Vec<u8> {
+    VBanHeader::service(*request.stream_name(), VBAN_SERVICE_IDENTIFICATION, VBAN_SERVICE_FNCT_PING0 | VBAN_SERVICE_FNCT_REPLY, request.nu_frame()).to_packet(&info.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::VBAN_PACKET_FULL_HEADER_BYTES;
+
+    #[test]
+    fn ping_round_trip() {
+        let request = ping_request(5);
+        let packet = VbanPacket::parse(&request).unwrap();
+        assert!(is_ping_request(&packet));
+
+        let mut info = Ping0::receptor();
+        info.user_name = String::from("Küche");
+        info.device_name = "x".repeat(100);
+        let reply = ping_reply(&packet, &info);
+        assert_eq!(reply.len(), VBAN_PACKET_FULL_HEADER_BYTES + VBAN_PING0_SIZE);
+
+        let packet = VbanPacket::parse(&reply).unwrap();
+        assert!(!is_ping_request(&packet));
+        assert_eq!(packet.service().unwrap(), (VBAN_SERVICE_IDENTIFICATION, VBAN_SERVICE_FNCT_PING0 | VBAN_SERVICE_FNCT_REPLY));
+        assert_eq!(packet.nu_frame(), 5);
+        let parsed = Ping0::parse(packet.payload()).unwrap();
+        assert_eq!(parsed.device_name.len(), 63);
+        info.device_name.truncate(63);
+        assert_eq!(parsed, info);
+    }
+
+    #[test]
+    fn text_is_cut_at_a_character_boundary() {
+        let info = Ping0 {
+            /* "ü" would take the 63rd and 64th byte, the last one belongs to the NUL */
+            device_name : format!("{}ü", "x".repeat(62)),
+            lang_code : String::from("ÄÄÄÄ"),
+            user_name : String::from("Küche"),
+            ..Default::default()
+        };
+        let data = info.to_bytes();
+        assert_eq!(data.len(), VBAN_PING0_SIZE);
+        assert_eq!(data[PING0_DEVICE_NAME.0 + 62], 0);
+        assert_eq!(data[PING0_LANG_CODE.0 + 6..PING0_LANG_CODE.0 + 8], [0, 0]);
+
+        let parsed = Ping0::parse(&data).unwrap();
+        assert_eq!(parsed.device_name, "x".repeat(62));
+        assert_eq!(parsed.lang_code, "ÄÄÄ");
+        assert_eq!(parsed.user_name, "Küche");
+        assert!(matches!(Ping0::parse(&data[..VBAN_PING0_SIZE - 1]), Err(VbanError::PacketTooShort(675))));
+    }
+}
@@ -0,0 +1,82 @@
+use std::cell::Cell;
+
+/// Destination of the decoded audio of a stream. Samples are interleaved and in the range
+/// [-1.0, 1.0].
+pub trait VbanSink {
+    fn write(&self, buf : &[f32]);
+
+    /// Number of frames written but not played yet, None if the sink does not play in real time
+    fn delay(&self) -> Option<usize>;
+
+    /// Rate the sink actually plays at, streams are resampled if it differs from theirs
+    fn sample_rate(&self) -> u32;
+
+    /// Waits until everything written has been played
+    fn drain(&self) {}
+}
+
+/// Discards all audio. Streams are still received, decoded and tracked, which makes the
+/// receiver usable as a monitor on machines without audio hardware.
+pub struct NullSink {
+    sample_rate : u32,
+
+    /// Samples discarded so far
+    samples : Cell<u64>,
+}
+
+impl NullSink {
+
+    pub fn new(sample_rate : u32) -> Self {
+        Self {
+            sample_rate,
+            samples : Cell::new(0),
+        }
+    }
+
+    /// Number of samples written to the sink, across all channels
+    pub fn samples_written(&self) -> u64 {
+        self.samples.get()
+    }
+}
+
+impl VbanSink for NullSink {
+
+    fn write(&self, buf : &[f32]) {
+        self.samples.set(self.samples.get() + buf.len() as u64);
+    }
+
+    fn delay(&self) -> Option<usize> {
+        None
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_sink_consumes_everything() {
+        let sink = NullSink::new(44100);
+        sink.write(&[0.5; 512]);
+        sink.write(&[]);
+        sink.write(&[1.0; 6]);
+        assert_eq!(sink.samples_written(), 518);
+        sink.drain();
+    }
+
+    #[test]
+    fn null_sink_is_not_clocked() {
+        /* No delay keeps the jitter buffer and drift compensation out of the way, the rate
+           is the one of the stream so it is never resampled */
+        let sink = NullSink::new(44100);
+        assert_eq!(sink.delay(), None);
+        sink.write(&[0.0; 4096]);
+        assert_eq!(sink.delay(), None);
+        assert_eq!(sink.sample_rate(), 44100);
+        assert_eq!(NullSink::new(96000).sample_rate(), 96000);
+    }
+}
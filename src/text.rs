@@ -0,0 +1,255 @@
+use std::{net::IpAddr, process::{Child, Command, Stdio}};
+use serde::Deserialize;
+
+/// A decoded VBAN-TEXT packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextMessage {
+    pub source : IpAddr,
+
+    pub stream_name : String,
+
+    pub text : String,
+}
+
+/// Something a text message asks the receiver to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReceiverCommand {
+    /// Sets the gain in dB of the named stream, of all streams if None
+    Gain(Option<String>, f32),
+
+    /// Mutes or unmutes the named stream, all streams if None. A state of None toggles.
+    Mute(Option<String>, Option<bool>),
+
+    /// Plays the named stream on the first route, any stream if None. Other routes are not
+    /// changed.
+    Select(Option<String>),
+}
+
+impl std::str::FromStr for ReceiverCommand {
+    type Err = String;
+
+    /// Parses one command. The words are `gain|volume [stream] <dB>`,
+    /// `mute [stream] [on|off|toggle]`, `unmute [stream]` and `select <stream>|*`; stream
+    /// names may contain spaces.
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let verb = words.next().unwrap_or_default().to_lowercase();
+        let mut args : Vec<&str> = words.collect();
+        let stream = |args : &[&str]| Some(args.join(" ")).filter(|name| !name.is_empty());
+
+        match verb.as_str() {
+            "gain" | "volume" => {
+                let db = args.pop().ok_or(format!("{verb} needs a value in dB"))?;
+                let db = db.trim_end_matches("dB").parse::<f32>().map_err(|_| format!("invalid gain {db}"))?;
+                Ok(ReceiverCommand::Gain(stream(&args), db))
+            },
+            "mute" => {
+                /* Without a state word the last word belongs to the stream name */
+                let state = match args.last().map(|word| word.to_lowercase()).as_deref() {
+                    Some("on" | "1" | "true") => Some(Some(true)),
+                    Some("off" | "0" | "false") => Some(Some(false)),
+                    Some("toggle") => Some(None),
+                    _ => None,
+                };
+                if state.is_some() {
+                    args.pop();
+                }
+                Ok(ReceiverCommand::Mute(stream(&args), state.unwrap_or(Some(true))))
+            },
+            "unmute" => Ok(ReceiverCommand::Mute(stream(&args), Some(false))),
+            "select" => match stream(&args).as_deref() {
+                None => Err(String::from("select needs a stream name or *")),
+                Some("*") => Ok(ReceiverCommand::Select(None)),
+                Some(name) => Ok(ReceiverCommand::Select(Some(String::from(name)))),
+            },
+            _ => Err(format!("unknown command {s:?}")),
+        }
+    }
+}
+
+/// Which of the handlers below is used for text packets
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextMode {
+    Log,
+
+    Command,
+
+    Script,
+}
+
+impl std::str::FromStr for TextMode {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(TextMode::Log),
+            "command" => Ok(TextMode::Command),
+            "script" => Ok(TextMode::Script),
+            _ => Err(format!("unknown text handler {s} (expected log, command or script)")),
+        }
+    }
+}
+
+/// Receives the text packets of a `VbanRecipient`
+pub trait TextHandler {
+    /// Handles one message and returns the commands the receiver should carry out
+    fn handle(&mut self, message : &TextMessage) -> Vec<ReceiverCommand>;
+}
+
+/// Prints every message
+pub struct LogHandler;
+
+impl TextHandler for LogHandler {
+    fn handle(&mut self, message : &TextMessage) -> Vec<ReceiverCommand> {
+        eprintln!("Text from {} ({}): {}", message.stream_name, message.source, message.text);
+        Vec::new()
+    }
+}
+
+/// Interprets messages as receiver commands, separated by semicolons or line breaks.
+/// Lines that are not understood are reported and skipped.
+pub struct CommandInterpreter;
+
+impl TextHandler for CommandInterpreter {
+    fn handle(&mut self, message : &TextMessage) -> Vec<ReceiverCommand> {
+        message.text.split([';', '\n'])
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match line.parse::<ReceiverCommand>() {
+                Ok(command) => Some(command),
+                Err(err) => {
+                    eprintln!("Ignoring text from {} ({}): {err}", message.stream_name, message.source);
+                    None
+                },
+            })
+            .collect()
+    }
+}
+
+/// Runs a script for every message with the stream name, the sender address and the text as
+/// arguments. The receiver does not wait for the script, so a slow one can run several times at
+/// once.
+pub struct ScriptHandler {
+    script : String,
+
+    /// Scripts that were started and not yet reaped
+    running : Vec<Child>,
+}
+
+impl ScriptHandler {
+
+    pub fn new(script : String) -> Self {
+        Self {
+            script,
+            running : Vec::new(),
+        }
+    }
+}
+
+impl TextHandler for ScriptHandler {
+    fn handle(&mut self, message : &TextMessage) -> Vec<ReceiverCommand> {
+        self.running.retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+        /* stdout may be the audio output of a pipe sink */
+        match Command::new(&self.script).arg(&message.stream_name).arg(message.source.to_string()).arg(&message.text).stdin(Stdio::null()).stdout(Stdio::null()).spawn() {
+            Ok(child) => self.running.push(child),
+            Err(err) => eprintln!("Could not run {}: {err}", self.script),
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::VbanError, packet::{stream_name_bytes, VBanHeader, VBanProtocol, VBanTextFormat, VbanPacket}};
+
+    #[test]
+    fn text_round_trip() {
+        for (format, text) in [(VBanTextFormat::Ascii, "Strip[0].Gain = -6;"), (VBanTextFormat::Utf8, "gain Küche -6"), (VBanTextFormat::Wchar, "mute Küche")] {
+            let head = VBanHeader::text(stream_name_bytes("Command1").unwrap(), format, 7);
+            let mut payload = format.encode(text);
+            payload.extend_from_slice(&[0, 0]);
+            let bytes = head.to_packet(&payload);
+            let packet = VbanPacket::parse(&bytes).unwrap();
+            assert_eq!(packet.protocol(), VBanProtocol::VbanProtocolTxt);
+            assert_eq!(packet.text().unwrap(), text);
+            assert!(matches!(packet.audio_format(), Err(VbanError::UnsupportedProtocol(0x40))));
+        }
+        assert_eq!(VBanTextFormat::Ascii.decode(&VBanTextFormat::Ascii.encode("Küche")), "K?che");
+
+        let mut user = VBanHeader::text([0; 16], VBanTextFormat::Utf8, 0);
+        user.sample_format = 0xF0;
+        assert!(matches!(VbanPacket::parse(&user.to_packet(b"x")).unwrap().text(), Err(VbanError::UnsupportedTextFormat(0xF0))));
+    }
+
+    fn parse(command : &str) -> ReceiverCommand {
+        command.parse().unwrap()
+    }
+
+    #[test]
+    fn gain_commands() {
+        assert_eq!(parse("gain -6"), ReceiverCommand::Gain(None, -6.0));
+        assert_eq!(parse("volume Stream1 -3.5dB"), ReceiverCommand::Gain(Some(String::from("Stream1")), -3.5));
+        assert_eq!(parse("GAIN Living room 2dB"), ReceiverCommand::Gain(Some(String::from("Living room")), 2.0));
+        assert!("gain".parse::<ReceiverCommand>().is_err());
+        assert!("gain Stream1 loud".parse::<ReceiverCommand>().is_err());
+    }
+
+    #[test]
+    fn mute_commands() {
+        assert_eq!(parse("mute"), ReceiverCommand::Mute(None, Some(true)));
+        assert_eq!(parse("mute on"), ReceiverCommand::Mute(None, Some(true)));
+        assert_eq!(parse("mute Stream1 OFF"), ReceiverCommand::Mute(Some(String::from("Stream1")), Some(false)));
+        assert_eq!(parse("mute Stream1 0"), ReceiverCommand::Mute(Some(String::from("Stream1")), Some(false)));
+        assert_eq!(parse("mute Stream1 true"), ReceiverCommand::Mute(Some(String::from("Stream1")), Some(true)));
+        assert_eq!(parse("mute Living room toggle"), ReceiverCommand::Mute(Some(String::from("Living room")), None));
+        /* Without a state word the whole rest is the stream name */
+        assert_eq!(parse("mute Living room"), ReceiverCommand::Mute(Some(String::from("Living room")), Some(true)));
+        assert_eq!(parse("unmute Living room"), ReceiverCommand::Mute(Some(String::from("Living room")), Some(false)));
+    }
+
+    #[test]
+    fn select_commands() {
+        assert_eq!(parse("select Living room"), ReceiverCommand::Select(Some(String::from("Living room"))));
+        assert_eq!(parse("select *"), ReceiverCommand::Select(None));
+        assert!("select".parse::<ReceiverCommand>().is_err());
+        assert!("play Stream1".parse::<ReceiverCommand>().is_err());
+    }
+
+    #[test]
+    fn interpreter_splits_commands() {
+        let message = TextMessage {
+            source : IpAddr::from([192, 168, 1, 20]),
+            stream_name : String::from("Command1"),
+            text : String::from("gain -6; mute Stream1\nbogus\n\nselect *"),
+        };
+        assert_eq!(CommandInterpreter.handle(&message), [
+            ReceiverCommand::Gain(None, -6.0),
+            ReceiverCommand::Mute(Some(String::from("Stream1")), Some(true)),
+            ReceiverCommand::Select(None),
+        ]);
+    }
+
+    #[test]
+    fn script_runs_in_the_background() {
+        use std::{fs, os::unix::fs::PermissionsExt, time::{Duration, Instant}};
+
+        let script = std::env::temp_dir().join(format!("vban_sink_text_{}.sh", std::process::id()));
+        fs::write(&script, "#!/bin/sh\nsleep 2\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut handler = ScriptHandler::new(script.to_string_lossy().into_owned());
+        let message = TextMessage {
+            source : IpAddr::from([127, 0, 0, 1]),
+            stream_name : String::from("Command1"),
+            text : String::from("hello"),
+        };
+
+        let start = Instant::now();
+        assert!(handler.handle(&message).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(handler.running.len(), 1);
+        assert!(handler.running[0].wait().unwrap().success());
+        fs::remove_file(&script).unwrap();
+    }
+}
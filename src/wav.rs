@@ -0,0 +1,426 @@
+use std::{cell::RefCell, fs::File, io::{BufWriter, Seek, SeekFrom, Write}, path::PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use crate::{error::VbanError, packet::VBanBitResolution, record::RecordOptions, sink::VbanSink};
+
+/// RIFF sizes are 32 bit, files are rotated before they reach this size
+const WAV_MAX_SIZE : u64 = u32::MAX as u64;
+
+const WAV_FORMAT_PCM : u16 = 0x0001;
+const WAV_FORMAT_FLOAT : u16 = 0x0003;
+const WAV_FORMAT_EXTENSIBLE : u16 = 0xFFFE;
+
+/// Tail of the sub format GUID of WAVE_FORMAT_EXTENSIBLE, preceded by the format tag
+const WAV_SUBFORMAT_GUID : [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+/// Sample format of the file, the closest one to the bit resolution of the stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WavFormat {
+    U8,
+    S16,
+    S24,
+    S32,
+    F32,
+    F64,
+}
+
+impl From<VBanBitResolution> for WavFormat {
+    fn from(resolution : VBanBitResolution) -> Self {
+        match resolution {
+            VBanBitResolution::VbanBitfmt8Int => WavFormat::U8,
+            VBanBitResolution::VbanBitfmt24Int => WavFormat::S24,
+            VBanBitResolution::VbanBitfmt32Int => WavFormat::S32,
+            VBanBitResolution::VbanBitfmt32Float => WavFormat::F32,
+            VBanBitResolution::VbanBitfmt64Float => WavFormat::F64,
+            _ => WavFormat::S16,
+        }
+    }
+}
+
+impl WavFormat {
+
+    fn width(&self) -> usize {
+        match self {
+            WavFormat::U8 => 1,
+            WavFormat::S16 => 2,
+            WavFormat::S24 => 3,
+            WavFormat::S32 | WavFormat::F32 => 4,
+            WavFormat::F64 => 8,
+        }
+    }
+
+    fn tag(&self) -> u16 {
+        match self {
+            WavFormat::F32 | WavFormat::F64 => WAV_FORMAT_FLOAT,
+            _ => WAV_FORMAT_PCM,
+        }
+    }
+
+    fn encode(&self, buf : &[f32]) -> Vec<u8> {
+        let width = self.width();
+        let mut bytes = vec![0u8; buf.len() * width];
+        for (smp, out) in buf.iter().zip(bytes.chunks_exact_mut(width)) {
+            let smp = smp.clamp(-1.0, 1.0) as f64;
+            match self {
+                /* 8 bit WAV is unsigned */
+                WavFormat::U8 => out[0] = ((smp * 127.0).round() as i32 + 128) as u8,
+                WavFormat::S16 => LittleEndian::write_i16(out, (smp * 32767.0).round() as i16),
+                WavFormat::S24 => LittleEndian::write_i24(out, (smp * 8388607.0).round() as i32),
+                WavFormat::S32 => LittleEndian::write_i32(out, (smp * 2147483647.0).round() as i32),
+                WavFormat::F32 => LittleEndian::write_f32(out, smp as f32),
+                WavFormat::F64 => LittleEndian::write_f64(out, smp),
+            }
+        }
+        bytes
+    }
+}
+
+/// Builds the RIFF header for `data_size` bytes of audio. WAVE_FORMAT_EXTENSIBLE is used for
+/// more than two channels or more than 16 bit, as the format specification asks for. The RIFF
+/// size counts the pad byte that follows a data chunk of odd size.
+fn wav_header(format : WavFormat, sample_rate : u32, num_channels : u16, data_size : u32) -> Vec<u8> {
+    let width = format.width() as u16;
+    let extensible = num_channels > 2 || width > 2;
+    let fmt_size : u32 = if extensible { 40 } else { 16 };
+
+    let mut head = Vec::with_capacity(28 + fmt_size as usize);
+    head.extend_from_slice(b"RIFF");
+    head.extend_from_slice(&(4 + 8 + fmt_size + 8).saturating_add(data_size).saturating_add(data_size & 1).to_le_bytes());
+    head.extend_from_slice(b"WAVE");
+
+    head.extend_from_slice(b"fmt ");
+    head.extend_from_slice(&fmt_size.to_le_bytes());
+    head.extend_from_slice(&(if extensible { WAV_FORMAT_EXTENSIBLE } else { format.tag() }).to_le_bytes());
+    head.extend_from_slice(&num_channels.to_le_bytes());
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&(sample_rate * (num_channels * width) as u32).to_le_bytes());
+    head.extend_from_slice(&(num_channels * width).to_le_bytes());
+    head.extend_from_slice(&(width * 8).to_le_bytes());
+    if extensible {
+        head.extend_from_slice(&22u16.to_le_bytes());
+        head.extend_from_slice(&(width * 8).to_le_bytes());
+        /* No speaker positions, the channels are just numbered */
+        head.extend_from_slice(&0u32.to_le_bytes());
+        head.extend_from_slice(&format.tag().to_le_bytes());
+        head.extend_from_slice(&WAV_SUBFORMAT_GUID);
+    }
+
+    head.extend_from_slice(b"data");
+    head.extend_from_slice(&data_size.to_le_bytes());
+    head
+}
+
+struct WavFile {
+    writer : BufWriter<File>,
+
+    path : PathBuf,
+
+    frames : u64,
+
+    data_size : u64,
+
+    header_size : u64,
+}
+
+struct WavState {
+    current : Option<WavFile>,
+
+    /// Frames written since the header was last brought up to date
+    unsynced : u64,
+}
+
+/// Records a stream to WAV files. The header is kept up to date about once a second, so a
+/// file stays readable if the process dies. Files are rotated by duration or size as set in
+/// the `RecordOptions`.
+pub struct WavFileSink {
+    options : RecordOptions,
+
+    stream_name : String,
+
+    sample_rate : u32,
+
+    num_channels : u16,
+
+    format : WavFormat,
+
+    state : RefCell<WavState>,
+}
+
+impl WavFileSink {
+
+    /// Creates the directory if necessary and opens the first file
+    pub fn create(options : &RecordOptions, stream_name : &str, sample_rate : u32, num_channels : u16, sample_format : VBanBitResolution) -> Result<Self, VbanError> {
+        std::fs::create_dir_all(&options.directory)?;
+        let sink = Self {
+            options : options.clone(),
+            stream_name : String::from(stream_name),
+            sample_rate,
+            num_channels,
+            format : WavFormat::from(sample_format),
+            state : RefCell::new(WavState {
+                current : None,
+                unsynced : 0,
+            }),
+        };
+        sink.state.borrow_mut().current = Some(sink.open()?);
+        Ok(sink)
+    }
+
+    fn open(&self) -> Result<WavFile, VbanError> {
+        let path = self.options.file_path(&self.stream_name, "wav");
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let header = wav_header(self.format, self.sample_rate, self.num_channels, 0);
+        writer.write_all(&header)?;
+        eprintln!("Recording to {}.", path.display());
+        Ok(WavFile {
+            writer,
+            path,
+            frames : 0,
+            data_size : 0,
+            header_size : header.len() as u64,
+        })
+    }
+
+    /// Writes the sizes into the header of the file. A data chunk of odd size gets its pad
+    /// byte, which the audio written next overwrites.
+    fn sync(&self, file : &mut WavFile) -> std::io::Result<()> {
+        let header = wav_header(self.format, self.sample_rate, self.num_channels, file.data_size as u32);
+        let end = file.header_size + file.data_size;
+        file.writer.seek(SeekFrom::Start(0))?;
+        file.writer.write_all(&header)?;
+        file.writer.seek(SeekFrom::Start(end))?;
+        if file.data_size % 2 == 1 {
+            file.writer.write_all(&[0])?;
+            file.writer.seek(SeekFrom::Start(end))?;
+        }
+        file.writer.flush()
+    }
+
+    fn close(&self, mut file : WavFile) {
+        match self.sync(&mut file) {
+            Ok(()) => eprintln!("Closed {} ({:.1} s).", file.path.display(), file.frames as f64 / self.sample_rate as f64),
+            Err(err) => eprintln!("Could not finish {}: {err}", file.path.display()),
+        }
+    }
+
+    /// Closes a file after a failed write. Audio still buffered is dropped, as writing it
+    /// would likely fail again, and the header is patched to the whole frames that reached
+    /// the file.
+    fn close_after_error(&self, mut file : WavFile) {
+        let frame_bytes = (self.format.width() * self.num_channels as usize) as u64;
+        let (inner, _buffered) = file.writer.into_parts();
+        let written = inner.metadata().and_then(|meta| {
+            let data_size = meta.len().saturating_sub(file.header_size) / frame_bytes * frame_bytes;
+            inner.set_len(file.header_size + data_size)?;
+            Ok(data_size)
+        });
+        file.writer = BufWriter::new(inner);
+        match written {
+            Ok(data_size) => {
+                file.data_size = data_size;
+                file.frames = data_size / frame_bytes;
+                self.close(file);
+            },
+            Err(err) => eprintln!("Could not finish {}: {err}", file.path.display()),
+        }
+    }
+
+    fn write_samples(&self, state : &mut WavState, mut buf : &[f32]) -> Result<(), VbanError> {
+        let frame_bytes = (self.format.width() * self.num_channels as usize) as u64;
+        while buf.len() >= self.num_channels as usize {
+            let mut file = match state.current.take() {
+                None => self.open()?,
+                Some(file) => file,
+            };
+
+            let frames = (buf.len() / self.num_channels as usize) as u64;
+            /* One byte is kept free for the pad byte */
+            let size_left = (WAV_MAX_SIZE - 1 - file.header_size - file.data_size) / frame_bytes;
+            let left = self.options.frames_left(file.frames, self.sample_rate, file.header_size + file.data_size, frame_bytes)
+                .unwrap_or(u64::MAX)
+                .min(size_left);
+            if left == 0 && file.frames > 0 {
+                self.close(file);
+                continue;
+            }
+
+            /* A file holds at least one frame even if the size limit is smaller */
+            let count = frames.min(left.max(1));
+            let (now, rest) = buf.split_at(count as usize * self.num_channels as usize);
+            let mut result = file.writer.write_all(&self.format.encode(now));
+            if result.is_ok() {
+                file.frames += count;
+                file.data_size += count * frame_bytes;
+                state.unsynced += count;
+                if state.unsynced >= self.sample_rate as u64 {
+                    state.unsynced = 0;
+                    result = self.sync(&mut file);
+                }
+            }
+            /* The file is kept so the error path can still finish it */
+            state.current = Some(file);
+            result?;
+            buf = rest;
+        }
+        Ok(())
+    }
+}
+
+impl VbanSink for WavFileSink {
+
+    fn write(&self, buf : &[f32]) {
+        let mut state = self.state.borrow_mut();
+        if let Err(err) = self.write_samples(&mut state, buf) {
+            /* Start over with a new file on the next write */
+            eprintln!("Recording failed: {err}");
+            if let Some(file) = state.current.take() {
+                self.close_after_error(file);
+            }
+        }
+    }
+
+    fn delay(&self) -> Option<usize> {
+        None
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn drain(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(mut file) = state.current.take() {
+            if let Err(err) = self.sync(&mut file) {
+                eprintln!("Could not finish {}: {err}", file.path.display());
+            }
+            state.current = Some(file);
+        }
+    }
+}
+
+impl Drop for WavFileSink {
+    fn drop(&mut self) {
+        if let Some(file) = self.state.get_mut().current.take() {
+            self.close(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name : &str, num_channels : u16, sample_format : VBanBitResolution, frames : usize) -> Vec<u8> {
+        let options = RecordOptions {
+            directory : std::env::temp_dir().join(format!("vban_sink_wav_{}_{name}", std::process::id())),
+            ..Default::default()
+        };
+        let path = {
+            let sink = WavFileSink::create(&options, name, 48000, num_channels, sample_format).unwrap();
+            sink.write(&vec![0.5; frames * num_channels as usize]);
+            let state = sink.state.borrow();
+            state.current.as_ref().unwrap().path.clone()
+        };
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&options.directory).unwrap();
+        bytes
+    }
+
+    fn u16_at(bytes : &[u8], at : usize) -> u16 {
+        LittleEndian::read_u16(&bytes[at..])
+    }
+
+    fn u32_at(bytes : &[u8], at : usize) -> u32 {
+        LittleEndian::read_u32(&bytes[at..])
+    }
+
+    #[test]
+    fn plain_pcm_header() {
+        let bytes = record("pcm", 2, VBanBitResolution::VbanBitfmt16Int, 100);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), WAV_FORMAT_PCM);
+        assert_eq!(u16_at(&bytes, 22), 2);
+        assert_eq!(u32_at(&bytes, 24), 48000);
+        assert_eq!(u32_at(&bytes, 28), 48000 * 4);
+        assert_eq!(u16_at(&bytes, 32), 4);
+        assert_eq!(u16_at(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 400);
+        assert_eq!(bytes.len(), 44 + 400);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(LittleEndian::read_i16(&bytes[44..]), 16384);
+    }
+
+    #[test]
+    fn extensible_header() {
+        let bytes = record("ext", 4, VBanBitResolution::VbanBitfmt32Float, 10);
+        assert_eq!(u32_at(&bytes, 16), 40);
+        assert_eq!(u16_at(&bytes, 20), WAV_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(&bytes, 22), 4);
+        assert_eq!(u16_at(&bytes, 32), 16);
+        assert_eq!(u16_at(&bytes, 34), 32);
+        assert_eq!(u16_at(&bytes, 36), 22);
+        assert_eq!(u16_at(&bytes, 38), 32);
+        assert_eq!(u16_at(&bytes, 44), WAV_FORMAT_FLOAT);
+        assert_eq!(&bytes[46..60], &WAV_SUBFORMAT_GUID);
+        assert_eq!(&bytes[60..64], b"data");
+        assert_eq!(u32_at(&bytes, 64), 160);
+        assert_eq!(bytes.len(), 68 + 160);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(LittleEndian::read_f32(&bytes[68..]), 0.5);
+    }
+
+    #[test]
+    fn odd_data_chunk_is_padded() {
+        /* 24 bit mono is extensible, 3 frames make 9 bytes of audio */
+        let bytes = record("odd", 1, VBanBitResolution::VbanBitfmt24Int, 3);
+        assert_eq!(u32_at(&bytes, 64), 9);
+        assert_eq!(bytes.len(), 68 + 9 + 1);
+        assert_eq!(bytes[77], 0);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    }
+
+    #[test]
+    fn pad_byte_is_overwritten_by_more_audio() {
+        let options = RecordOptions {
+            directory : std::env::temp_dir().join(format!("vban_sink_wav_{}_grow", std::process::id())),
+            ..Default::default()
+        };
+        let sink = WavFileSink::create(&options, "grow", 48000, 1, VBanBitResolution::VbanBitfmt8Int).unwrap();
+        sink.write(&[0.5; 3]);
+        sink.drain();
+        sink.write(&[-0.5; 2]);
+        let path = sink.state.borrow().current.as_ref().unwrap().path.clone();
+        drop(sink);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&options.directory).unwrap();
+        assert_eq!(u32_at(&bytes, 40), 5);
+        assert_eq!(&bytes[44..], [192, 192, 192, 64, 64, 0]);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    }
+
+    #[test]
+    fn failed_write_still_finishes_the_header() {
+        let options = RecordOptions {
+            directory : std::env::temp_dir().join(format!("vban_sink_wav_{}_failed", std::process::id())),
+            ..Default::default()
+        };
+        let sink = WavFileSink::create(&options, "failed", 48000, 2, VBanBitResolution::VbanBitfmt16Int).unwrap();
+        sink.write(&[0.5; 200]);
+        let mut file = sink.state.borrow_mut().current.take().unwrap();
+        let path = file.path.clone();
+        /* 100 frames reached the disk, then a frame was cut short and more audio is stuck in the buffer */
+        file.writer.flush().unwrap();
+        file.writer.get_mut().write_all(&[1, 2, 3]).unwrap();
+        file.writer.write_all(&[0; 40]).unwrap();
+        sink.close_after_error(file);
+        drop(sink);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&options.directory).unwrap();
+        assert_eq!(u32_at(&bytes, 40), 400);
+        assert_eq!(bytes.len(), 44 + 400);
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    }
+}